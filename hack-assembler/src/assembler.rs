@@ -1,7 +1,120 @@
 use regex::Regex;
 use std::collections::HashMap;
+use std::fmt;
 use std::str::Lines;
 
+/// largest address that fits in the 15 bits of an A-instruction
+const MAX_ADDRESS: usize = 32767;
+
+/// an error that stops the assembly
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembleError {
+    /// 1-based line number of the offending instruction in the input
+    pub line: usize,
+    /// human readable description
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AssembleError {}
+
+/// the predefined symbols and their addresses
+pub const PREDEFINED_SYMBOLS: [(&str, usize); 23] = [
+    ("SP", 0),
+    ("LCL", 1),
+    ("ARG", 2),
+    ("THIS", 3),
+    ("THAT", 4),
+    ("R0", 0),
+    ("R1", 1),
+    ("R2", 2),
+    ("R3", 3),
+    ("R4", 4),
+    ("R5", 5),
+    ("R6", 6),
+    ("R7", 7),
+    ("R8", 8),
+    ("R9", 9),
+    ("R10", 10),
+    ("R11", 11),
+    ("R12", 12),
+    ("R13", 13),
+    ("R14", 14),
+    ("R15", 15),
+    ("SCREEN", 16384),
+    ("KBD", 24576),
+];
+
+/// the computations that the ALU supports and their a-bit and c-bits,
+/// the forms of the spec first, followed by the commutative forms of the binary computations
+pub const COMPUTE_TABLE: [(&str, &str); 34] = [
+    ("0", "0101010"),
+    ("1", "0111111"),
+    ("-1", "0111010"),
+    ("D", "0001100"),
+    ("A", "0110000"),
+    ("M", "1110000"),
+    ("!D", "0001101"),
+    ("!A", "0110001"),
+    ("!M", "1110001"),
+    ("-D", "0001111"),
+    ("-A", "0110011"),
+    ("-M", "1110011"),
+    ("D+1", "0011111"),
+    ("A+1", "0110111"),
+    ("M+1", "1110111"),
+    ("D-1", "0001110"),
+    ("A-1", "0110010"),
+    ("M-1", "1110010"),
+    ("D+A", "0000010"),
+    ("D+M", "1000010"),
+    ("D-A", "0010011"),
+    ("D-M", "1010011"),
+    ("A-D", "0000111"),
+    ("M-D", "1000111"),
+    ("D&A", "0000000"),
+    ("D&M", "1000000"),
+    ("D|A", "0010101"),
+    ("D|M", "1010101"),
+    ("A+D", "0000010"),
+    ("M+D", "1000010"),
+    ("A&D", "0000000"),
+    ("M&D", "1000000"),
+    ("A|D", "0010101"),
+    ("M|D", "1010101"),
+];
+
+/// the destinations of a C-instruction and their d-bits
+pub const DEST_TABLE: [(&str, &str); 9] = [
+    ("null", "000"),
+    ("", "000"),
+    ("M", "001"),
+    ("D", "010"),
+    ("MD", "011"),
+    ("A", "100"),
+    ("AM", "101"),
+    ("AD", "110"),
+    ("AMD", "111"),
+];
+
+/// the jumps of a C-instruction and their j-bits
+pub const JUMP_TABLE: [(&str, &str); 9] = [
+    ("null", "000"),
+    ("", "000"),
+    ("JGT", "001"),
+    ("JEQ", "010"),
+    ("JGE", "011"),
+    ("JLT", "100"),
+    ("JNE", "101"),
+    ("JLE", "110"),
+    ("JMP", "111"),
+];
+
 #[derive(Debug)]
 pub struct CInstruction {
     pub dest: String,
@@ -30,6 +143,12 @@ pub struct Assembler<'a> {
     next_symbol_address: usize,
     /// symbol table mapping symbols to addresses
     symbol_table: HashMap<String, usize>,
+    /// the (LABEL) declarations and their ROM addresses, in order of address
+    labels: Vec<(String, usize)>,
+    /// the variables and the RAM addresses assigned to them, in order of address
+    variables: Vec<(String, usize)>,
+    /// the 1-based input line of the instruction at every ROM address
+    rom_lines: Vec<usize>,
     /// compute instruction mapping to binary representation
     compute_hash_map: HashMap<String, String>,
     /// destination instruction mapping to binary representation
@@ -48,112 +167,51 @@ impl<'a> Assembler<'a> {
     /// `Assembler.new()`: constructor
     pub fn new(input: &'a str) -> Self {
         // maps all the built-in symbols
-        let mut symbol_table = HashMap::new();
-        symbol_table.insert("SP".to_string(), 0);
-        symbol_table.insert("LCL".to_string(), 1);
-        symbol_table.insert("ARG".to_string(), 2);
-        symbol_table.insert("THIS".to_string(), 3);
-        symbol_table.insert("THAT".to_string(), 4);
-        symbol_table.insert("R0".to_string(), 0);
-        symbol_table.insert("R1".to_string(), 1);
-        symbol_table.insert("R2".to_string(), 2);
-        symbol_table.insert("R3".to_string(), 3);
-        symbol_table.insert("R4".to_string(), 4);
-        symbol_table.insert("R5".to_string(), 5);
-        symbol_table.insert("R6".to_string(), 6);
-        symbol_table.insert("R7".to_string(), 7);
-        symbol_table.insert("R8".to_string(), 8);
-        symbol_table.insert("R9".to_string(), 9);
-        symbol_table.insert("R10".to_string(), 10);
-        symbol_table.insert("R11".to_string(), 11);
-        symbol_table.insert("R12".to_string(), 12);
-        symbol_table.insert("R13".to_string(), 13);
-        symbol_table.insert("R14".to_string(), 14);
-        symbol_table.insert("R15".to_string(), 15);
-        symbol_table.insert("SCREEN".to_string(), 16384);
-        symbol_table.insert("KBD".to_string(), 24576);
-
-        // maps all the built-in compute instructions that the ALU supports
-        let mut compute_hash_map = HashMap::new();
-        compute_hash_map.insert("0".to_string(), "0101010".to_string());
-        compute_hash_map.insert("1".to_string(), "0111111".to_string());
-        compute_hash_map.insert("-1".to_string(), "0111010".to_string());
-        compute_hash_map.insert("D".to_string(), "0001100".to_string());
-        compute_hash_map.insert("A".to_string(), "0110000".to_string());
-        compute_hash_map.insert("M".to_string(), "1110000".to_string());
-        compute_hash_map.insert("!D".to_string(), "0001101".to_string());
-        compute_hash_map.insert("!A".to_string(), "0110001".to_string());
-        compute_hash_map.insert("!M".to_string(), "1110001".to_string());
-        compute_hash_map.insert("-D".to_string(), "0001111".to_string());
-        compute_hash_map.insert("-A".to_string(), "0110011".to_string());
-        compute_hash_map.insert("-M".to_string(), "1110011".to_string());
-        compute_hash_map.insert("D+1".to_string(), "0011111".to_string());
-        compute_hash_map.insert("A+1".to_string(), "0110111".to_string());
-        compute_hash_map.insert("M+1".to_string(), "1110111".to_string());
-        compute_hash_map.insert("D-1".to_string(), "0001110".to_string());
-        compute_hash_map.insert("A-1".to_string(), "0110010".to_string());
-        compute_hash_map.insert("M-1".to_string(), "1110010".to_string());
-        compute_hash_map.insert("D+A".to_string(), "0000010".to_string());
-        compute_hash_map.insert("D+M".to_string(), "1000010".to_string());
-        compute_hash_map.insert("D-A".to_string(), "0010011".to_string());
-        compute_hash_map.insert("D-M".to_string(), "1010011".to_string());
-        compute_hash_map.insert("A-D".to_string(), "0000111".to_string());
-        compute_hash_map.insert("M-D".to_string(), "1000111".to_string());
-        compute_hash_map.insert("D&A".to_string(), "0000000".to_string());
-        compute_hash_map.insert("D&M".to_string(), "1000000".to_string());
-        compute_hash_map.insert("D|A".to_string(), "0010101".to_string());
-        compute_hash_map.insert("D|M".to_string(), "1010101".to_string());
-
-        // maps all the built-in dest instructions that the ALU supports
-        let mut dest_hash_map = HashMap::new();
-        dest_hash_map.insert("null".to_string(), "000".to_string());
-        dest_hash_map.insert("".to_string(), "000".to_string());
-        dest_hash_map.insert("M".to_string(), "001".to_string());
-        dest_hash_map.insert("D".to_string(), "010".to_string());
-        dest_hash_map.insert("MD".to_string(), "011".to_string());
-        dest_hash_map.insert("A".to_string(), "100".to_string());
-        dest_hash_map.insert("AM".to_string(), "101".to_string());
-        dest_hash_map.insert("AD".to_string(), "110".to_string());
-        dest_hash_map.insert("AMD".to_string(), "111".to_string());
-
-        // maps all the built-in jump instructions that the ALU supports
-        let mut jump_hash_map = HashMap::new();
-        jump_hash_map.insert("null".to_string(), "000".to_string());
-        jump_hash_map.insert("".to_string(), "000".to_string());
-        jump_hash_map.insert("JGT".to_string(), "001".to_string());
-        jump_hash_map.insert("JEQ".to_string(), "010".to_string());
-        jump_hash_map.insert("JGE".to_string(), "011".to_string());
-        jump_hash_map.insert("JLT".to_string(), "100".to_string());
-        jump_hash_map.insert("JNE".to_string(), "101".to_string());
-        jump_hash_map.insert("JLE".to_string(), "110".to_string());
-        jump_hash_map.insert("JMP".to_string(), "111".to_string());
+        let symbol_table = PREDEFINED_SYMBOLS
+            .iter()
+            .map(|&(symbol, address)| (symbol.to_string(), address))
+            .collect();
+
+        // maps all the built-in compute, dest and jump instructions that the ALU supports
+        let compute_hash_map = to_hash_map(&COMPUTE_TABLE);
+        let dest_hash_map = to_hash_map(&DEST_TABLE);
+        let jump_hash_map = to_hash_map(&JUMP_TABLE);
 
         // regexes for the three types of instructions
 
         // l_instruction matches (LABEL)
-        let l_instruction: Regex = Regex::new(r"^\(([_0-9a-zA-Z\.\$:]+)\)").unwrap();
+        let l_instruction: Regex = Regex::new(r"^\(([_0-9a-zA-Z\.\$:]+)\)$").unwrap();
         // a_instruction matches @ADDRESS
-        let a_instruction: Regex = Regex::new(r"^@([_0-9a-zA-Z\.\$:]+)").unwrap();
+        let a_instruction: Regex = Regex::new(r"^@([_0-9a-zA-Z\.\$:]+)$").unwrap();
         // c_instruction matches DEST=COMP;JMP
         let c_instruction: Regex =
-            Regex::new(r"^([ADM]*)(=?)([-\+01DAM!&\|]+)(;?)([JGTEQNLMP]*)").unwrap();
+            Regex::new(r"^([ADM]*)(=?)([-\+01DAM!&\|]+)(;?)([JGTEQNLMP]*)$").unwrap();
 
         Assembler {
-            input: input,
+            input,
             iterator: input.lines(),
             current_instruction: None,
             current_line: 0,
             next_symbol_address: 16,
-            symbol_table: symbol_table,
-            compute_hash_map: compute_hash_map,
-            dest_hash_map: dest_hash_map,
-            jump_hash_map: jump_hash_map,
-            l_instruction: l_instruction,
-            a_instruction: a_instruction,
-            c_instruction: c_instruction,
+            symbol_table,
+            labels: Vec::new(),
+            variables: Vec::new(),
+            rom_lines: Vec::new(),
+            compute_hash_map,
+            dest_hash_map,
+            jump_hash_map,
+            l_instruction,
+            a_instruction,
+            c_instruction,
         }
     }
 
+    /// `Assembler.define_symbol()`: adds a predefined symbol, e.g. the base address of a device
+    /// of the emulator, before `assemble` is called; labels of the input may not reuse it
+    pub fn define_symbol(&mut self, symbol: &str, address: usize) {
+        self.symbol_table.insert(symbol.to_string(), address);
+    }
+
     /// `Assembler.reset_input_iterator()`: resets the input iterator when the reset button is pressed
     /// on the Hack computer
     fn reset_input_iterator(&mut self) {
@@ -162,34 +220,69 @@ impl<'a> Assembler<'a> {
     }
 
     /// public `Assembler.assemble()`: assembles the input asm instruction and returns the binary representation
-    pub fn assemble(&mut self) -> String {
-        self.build_symbol_table();
+    /// or the first error, with its line number in the input
+    pub fn assemble(&mut self) -> Result<String, AssembleError> {
+        self.build_symbol_table()?;
         self.reset_input_iterator();
 
         let mut output = String::new();
         self.advance();
-        while self.current_instruction != None {
-            if let Some(instruction) = self.get_instruction() {
-                output += &format!("{}\n", self.get_machine_language_instruction(instruction));
+        while self.current_instruction.is_some() {
+            if let Some(instruction) = self.get_instruction()? {
+                output += &format!("{}\n", self.get_machine_language_instruction(instruction)?);
+                self.rom_lines.push(self.current_line);
             }
             self.advance();
         }
-        output
+        Ok(output)
+    }
+
+    /// `Assembler.labels()`: the labels declared by the input and their ROM addresses,
+    /// in order of address, once `assemble` succeeded
+    pub fn labels(&self) -> &[(String, usize)] {
+        &self.labels
+    }
+
+    /// `Assembler.variables()`: the variables of the input and the RAM addresses assigned to them,
+    /// in order of address, once `assemble` succeeded
+    pub fn variables(&self) -> &[(String, usize)] {
+        &self.variables
+    }
+
+    /// `Assembler.rom_lines()`: the 1-based input line of the instruction at every ROM address,
+    /// once `assemble` succeeded
+    pub fn rom_lines(&self) -> &[usize] {
+        &self.rom_lines
+    }
+
+    /// `Assembler.error()`: returns an error at the current line
+    fn error(&self, message: String) -> AssembleError {
+        AssembleError {
+            line: self.current_line,
+            message,
+        }
     }
 
     /// `Assembler.build_symbol_table()`: builds the symbol table from @variable declarations
-    fn build_symbol_table(&mut self) {
+    fn build_symbol_table(&mut self) -> Result<(), AssembleError> {
         let mut line_counter = 0;
 
         self.advance();
-        while self.current_instruction != None {
+        while self.current_instruction.is_some() {
             if let Some(symbol) = self.get_l_symbol() {
-                self.symbol_table.insert(symbol, line_counter);
+                if self.symbol_table.contains_key(&symbol) {
+                    return Err(
+                        self.error(format!("symbol `{}` is defined more than once", symbol))
+                    );
+                }
+                self.symbol_table.insert(symbol.clone(), line_counter);
+                self.labels.push((symbol, line_counter));
             } else {
                 line_counter += 1;
             }
             self.advance();
         }
+        Ok(())
     }
 
     /// `Assembler.get_l_symbol()`: returns the symbol name from a l_instruction e.g. (LABEL)
@@ -205,19 +298,34 @@ impl<'a> Assembler<'a> {
     }
 
     /// `Assembler.get_machine_language_instruction`: Converts DEST=COMP;JMP to a 16 bit binary representation of CInstruction the ALU understands
-    fn get_machine_language_instruction(&self, instruction: Instruction) -> String {
+    fn get_machine_language_instruction(
+        &self,
+        instruction: Instruction,
+    ) -> Result<String, AssembleError> {
         match instruction {
             Instruction::A { address } => {
+                if address > MAX_ADDRESS {
+                    return Err(self.error(format!(
+                        "address {} is out of range 0-{}",
+                        address, MAX_ADDRESS
+                    )));
+                }
                 let s = format!("{:b}", address);
-                format!("{:0>16}", s)
+                Ok(format!("{:0>16}", s))
             }
             Instruction::C { instruction } => {
-                format!(
+                let lookup = |table: &HashMap<String, String>, field: &str, kind: &str| {
+                    table
+                        .get(field)
+                        .cloned()
+                        .ok_or_else(|| self.error(format!("unknown {} `{}`", kind, field)))
+                };
+                Ok(format!(
                     "111{comp}{dest}{jump}",
-                    comp = self.compute_hash_map[&instruction.comp],
-                    dest = self.dest_hash_map[&instruction.dest],
-                    jump = self.jump_hash_map[&instruction.jmp]
-                )
+                    comp = lookup(&self.compute_hash_map, &instruction.comp, "computation")?,
+                    dest = lookup(&self.dest_hash_map, &instruction.dest, "destination")?,
+                    jump = lookup(&self.jump_hash_map, &instruction.jmp, "jump")?
+                ))
             }
         }
     }
@@ -244,7 +352,7 @@ impl<'a> Assembler<'a> {
     }
 
     /// converts the current instruction into an Instruction enum.
-    fn get_instruction(&mut self) -> Option<Instruction> {
+    fn get_instruction(&mut self) -> Result<Option<Instruction>, AssembleError> {
         // take the current instruction
         let c = self.current_instruction.as_ref().unwrap();
 
@@ -254,13 +362,13 @@ impl<'a> Assembler<'a> {
             let dest = caps.get(1).map_or("", |m| m.as_str());
             let comp = caps.get(3).map_or("", |m| m.as_str());
             let jmp = caps.get(5).map_or("", |m| m.as_str());
-            Some(Instruction::C {
+            Ok(Some(Instruction::C {
                 instruction: CInstruction {
                     dest: dest.to_string(),
                     comp: comp.to_string(),
                     jmp: jmp.to_string(),
                 },
-            })
+            }))
         // if the current instruction is an a_instruction, parse it and return an AInstruction
         } else if self.a_instruction.is_match(c) {
             let caps = self.a_instruction.captures(c).unwrap();
@@ -273,6 +381,8 @@ impl<'a> Assembler<'a> {
                     if !self.symbol_table.contains_key(&address_or_symbol) {
                         self.symbol_table
                             .insert(address_or_symbol.clone(), self.next_symbol_address);
+                        self.variables
+                            .push((address_or_symbol.clone(), self.next_symbol_address));
                         self.next_symbol_address += 1;
                     }
 
@@ -280,18 +390,103 @@ impl<'a> Assembler<'a> {
                 }
             };
             // return the AInstruction
-            Some(Instruction::A {
+            Ok(Some(Instruction::A {
                 address: address_number,
-            })
+            }))
         // if the current instruction is a l_instruction, return None
         } else if self.l_instruction.is_match(c) {
-            None
+            Ok(None)
         } else {
-            println!(
-                "Assembler failed. Syntax error at line {} of the input file.",
-                self.current_line
-            );
-            ::std::process::exit(1);
+            Err(self.error(format!("syntax error in `{}`", c)))
         }
     }
 }
+
+/// converts a table of mnemonics and their bits into a map from mnemonic to bits
+fn to_hash_map(table: &[(&str, &str)]) -> HashMap<String, String> {
+    table
+        .iter()
+        .map(|&(mnemonic, bits)| (mnemonic.to_string(), bits.to_string()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commutative_forms_assemble_like_the_spec_forms() {
+        let spec = Assembler::new("D=D+M\nD=D&M\nD=D|A\n").assemble();
+        let commuted = Assembler::new("D=M+D\nD=M&D\nD=A|D\n").assemble();
+        assert_eq!(spec, commuted);
+    }
+
+    #[test]
+    fn labels_and_variables_resolve() {
+        let hack = Assembler::new("@i\n(LOOP)\n@LOOP\n0;JMP\n")
+            .assemble()
+            .unwrap();
+        assert_eq!(
+            hack,
+            "0000000000010000\n0000000000000001\n1110101010000111\n"
+        );
+    }
+
+    #[test]
+    fn exports_the_symbol_table() {
+        let mut assembler = Assembler::new(
+            "(START)\n@i\n(LOOP)\n(AGAIN)\n// comment\n@LOOP\n0;JMP\n(END)\n@sum\n@i\n",
+        );
+        assembler.assemble().unwrap();
+        assert_eq!(assembler.rom_lines(), [2, 6, 7, 9, 10]);
+        assert_eq!(
+            assembler.variables(),
+            [("i".to_string(), 16), ("sum".to_string(), 17)]
+        );
+        let labels: Vec<(&str, usize)> = assembler
+            .labels()
+            .iter()
+            .map(|(label, address)| (label.as_str(), *address))
+            .collect();
+        assert_eq!(
+            labels,
+            [("START", 0), ("LOOP", 1), ("AGAIN", 1), ("END", 3)]
+        );
+    }
+
+    #[test]
+    fn defines_extra_symbols() {
+        let mut assembler = Assembler::new("@TIMER\nD=M\n@x\n");
+        assembler.define_symbol("TIMER", 24577);
+        let hack = assembler.assemble().unwrap();
+        assert!(hack.starts_with("0110000000000001\n"), "{}", hack);
+        assert_eq!(assembler.variables(), [("x".to_string(), 16)]);
+
+        let mut assembler = Assembler::new("(TIMER)\n");
+        assembler.define_symbol("TIMER", 24577);
+        assert!(assembler.assemble().is_err());
+    }
+
+    #[test]
+    fn errors_report_the_input_line() {
+        let err = Assembler::new("@1\n\n// comment\nD=D*A\n")
+            .assemble()
+            .unwrap_err();
+        assert_eq!(err.line, 4);
+        assert_eq!(err.to_string(), "line 4: syntax error in `D=D*A`");
+
+        let err = Assembler::new("(LOOP)\n@LOOP\n(LOOP)\n")
+            .assemble()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 3: symbol `LOOP` is defined more than once"
+        );
+
+        let err = Assembler::new("@32768\n").assemble().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "line 1: address 32768 is out of range 0-32767"
+        );
+    }
+}
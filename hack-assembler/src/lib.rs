@@ -0,0 +1,5 @@
+pub use assembler::{
+    AssembleError, Assembler, COMPUTE_TABLE, DEST_TABLE, JUMP_TABLE, PREDEFINED_SYMBOLS,
+};
+
+mod assembler;
@@ -3,9 +3,7 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
-pub use assembler::Assembler;
-
-mod assembler;
+use hack_assembler::Assembler;
 
 fn main() {
     let cmd_matches = Command::new("HackAssembler")
@@ -36,13 +34,19 @@ fn main() {
 
     let output_hack = str::replace(&input_asm, ".asm", ".hack");
     let mut assembler = Assembler::new(&contents);
-    let hack = assembler.assemble();
+    let hack = match assembler.assemble() {
+        Ok(hack) => hack,
+        Err(err) => {
+            eprintln!("Assembler failed. {}: {}", input_asm, err);
+            ::std::process::exit(1);
+        }
+    };
 
     let path = Path::new(&output_hack);
     let display = path.display();
 
-    let mut file = File::create(&path).expect(&format!("Couldn't create {}", display));
+    let mut file = File::create(path).unwrap_or_else(|_| panic!("Couldn't create {}", display));
 
     file.write_all(hack.as_bytes())
-        .expect(&format!("Couldn't write to {}", display));
+        .unwrap_or_else(|_| panic!("Couldn't write to {}", display));
 }
@@ -0,0 +1,100 @@
+use hack_emulator::decode::{self, Instruction};
+use hack_emulator::{rom, Machine};
+use std::path::Path;
+use std::time::Instant;
+
+/// instructions executed per measurement
+const CYCLES: u64 = 200_000_000;
+
+/// the documented throughput target of `Machine::run`, in millions of instructions per second
+const TARGET_MIPS: f64 = 200.0;
+/// the documented speedup of `Machine::run` over the naive loop
+const TARGET_SPEEDUP: f64 = 2.0;
+
+/// a straightforward interpreter that decodes the instruction word on every cycle,
+/// the baseline the pre-decoded engine is compared with
+fn run_naive(rom: &[u16], cycles: u64) -> u16 {
+    let mut ram = vec![0u16; 32768];
+    let (mut a, mut d, mut pc) = (0u16, 0u16, 0u16);
+    for _ in 0..cycles {
+        let word = rom.get(pc as usize).copied().unwrap_or(0);
+        match decode::decode(word) {
+            Instruction::A(value) => {
+                a = value;
+                pc = (pc + 1) & 0x7fff;
+            }
+            Instruction::C(c) => {
+                let address = (a & 0x7fff) as usize;
+                let y = if c.reads_m { ram[address] } else { a };
+                let out = c.comp.eval(d, y);
+                let target = a;
+                if c.dest & decode::DEST_M != 0 {
+                    ram[address] = out;
+                }
+                if c.dest & decode::DEST_A != 0 {
+                    a = out;
+                }
+                if c.dest & decode::DEST_D != 0 {
+                    d = out;
+                }
+                pc = if c.jumps(out) {
+                    target & 0x7fff
+                } else {
+                    (pc + 1) & 0x7fff
+                };
+            }
+        }
+    }
+    pc
+}
+
+/// runs `f` and returns the millions of instructions per second it achieved
+fn measure(f: impl FnOnce()) -> f64 {
+    let start = Instant::now();
+    f();
+    CYCLES as f64 / start.elapsed().as_secs_f64() / 1e6
+}
+
+fn main() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../projects/06/pong/Pong.hack");
+    let program = rom::load(&path).expect("Pong.hack is in the repository");
+
+    let naive = measure(|| {
+        std::hint::black_box(run_naive(&program.words, CYCLES));
+    });
+    println!(
+        "naive decode-every-cycle loop: {:8.1} M instructions/s",
+        naive
+    );
+
+    let mut machine = Machine::new(&program.words);
+    let stepped = measure(|| {
+        for _ in 0..CYCLES {
+            machine.step();
+        }
+    });
+    println!(
+        "pre-decoded, single steps:     {:8.1} M instructions/s",
+        stepped
+    );
+
+    let mut machine = Machine::new(&program.words);
+    let fused = measure(|| machine.run(CYCLES));
+    println!(
+        "pre-decoded, superinstructions: {:7.1} M instructions/s",
+        fused
+    );
+
+    let verdict = |met| if met { "met" } else { "MISSED" };
+    println!(
+        "target {:.0} M instructions/s: {}",
+        TARGET_MIPS,
+        verdict(fused >= TARGET_MIPS)
+    );
+    println!(
+        "target {:.1}x the naive loop: {} ({:.2}x)",
+        TARGET_SPEEDUP,
+        verdict(fused >= TARGET_SPEEDUP * naive),
+        fused / naive
+    );
+}
@@ -0,0 +1,343 @@
+use crate::decode::{self, Instruction, DEST_M};
+use crate::keys::KeyScript;
+use crate::machine::{Machine, KBD, RAM_SIZE, SCREEN};
+use crate::rom::Program;
+use crate::trace::TraceFilter;
+use std::collections::VecDeque;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// number of instructions kept for the report of a violation
+pub const HISTORY: usize = 16;
+/// the values of `SP` (RAM[0]) inside the stack
+pub const STACK: RangeInclusive<u16> = 256..=2047;
+/// `LCL`, `ARG`, `THIS` and `THAT`: the VM bootstrap saves them with `call Sys.init` before any
+/// code sets them, so they count as written from the start
+pub const SEGMENT_POINTERS: RangeInclusive<u16> = 1..=4;
+
+/// an operation that checked mode traps on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// reading a RAM address that was never written
+    UninitializedRead(u16),
+    /// writing the screen from code not marked as graphics
+    ScreenWrite(u16),
+    /// writing the keyboard register
+    KeyboardWrite,
+    /// setting `SP` outside of `STACK`
+    StackPointer(u16),
+    /// jumping, or running, to a ROM address past the end of the program
+    PastProgram(u16),
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::UninitializedRead(address) => {
+                write!(f, "read RAM[{}], which was never written", address)
+            }
+            Violation::ScreenWrite(address) => write!(
+                f,
+                "wrote the screen at RAM[{}] from code not marked as graphics",
+                address
+            ),
+            Violation::KeyboardWrite => write!(f, "wrote the keyboard register RAM[{}]", KBD),
+            Violation::StackPointer(value) => write!(
+                f,
+                "set SP (RAM[0]) to {}, outside of the stack {}-{}",
+                *value as i16,
+                STACK.start(),
+                STACK.end()
+            ),
+            Violation::PastProgram(address) => {
+                write!(f, "went to ROM[{}], past the end of the program", address)
+            }
+        }
+    }
+}
+
+/// an instruction executed in checked mode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Executed {
+    /// the cycle count before the instruction
+    pub cycle: u64,
+    /// the ROM address of the instruction
+    pub pc: u16,
+    /// the instruction word
+    pub instruction: u16,
+}
+
+/// a violation and where it happened; the machine is stopped before the offending instruction,
+/// except for `PastProgram`, which is found after the jump
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    /// what the program did
+    pub violation: Violation,
+    /// the offending instruction, the last of `history`
+    pub at: Executed,
+    /// the last instructions executed, oldest first, up to `HISTORY`
+    pub history: Vec<Executed>,
+}
+
+impl Trap {
+    /// `Trap.report()`: the violation, the offending instruction with its .asm line and the
+    /// instructions before it
+    pub fn report(&self, program: &Program) -> String {
+        let line = |executed: &Executed| {
+            let source = match program.asm_line(executed.pc) {
+                Some((line, text)) => format!("line {}: {}", line, text),
+                None => decode::disassemble(executed.instruction),
+            };
+            format!(
+                "cycle {} PC {} ({}) {}",
+                executed.cycle,
+                executed.pc,
+                program.describe(executed.pc),
+                source
+            )
+        };
+        let mut report = format!(
+            "checked mode: {}\n  at {}\nrecent instructions:",
+            self.violation,
+            line(&self.at)
+        );
+        for executed in &self.history {
+            report += &format!("\n  {}", line(executed));
+        }
+        report
+    }
+}
+
+/// # Checker
+/// runs a machine one instruction at a time and traps on the operations that usually mean that a
+/// program corrupts itself: reading RAM that was never written, writing the screen outside of the
+/// graphics code, writing the keyboard, moving `SP` out of the stack and running past the program
+///
+/// only the RAM below `SCREEN` is checked for reads, the devices hold their own values;
+/// `SP` is checked when it is written, so programs that never set it are not affected
+pub struct Checker {
+    /// whether every RAM address was written, by the program or with `mark_written`
+    written: Vec<bool>,
+    /// the ROM addresses of the code allowed to write the screen
+    graphics: TraceFilter,
+    /// the last instructions executed, oldest first
+    history: VecDeque<Executed>,
+}
+
+impl Checker {
+    /// `Checker::new()`: constructor, no RAM address but `SEGMENT_POINTERS` has been written yet
+    /// graphics: the code allowed to write the screen, none when empty
+    pub fn new(graphics: TraceFilter) -> Self {
+        let mut checker = Checker {
+            written: vec![false; RAM_SIZE],
+            graphics,
+            history: VecDeque::with_capacity(HISTORY),
+        };
+        for address in SEGMENT_POINTERS {
+            checker.mark_written(address);
+        }
+        checker
+    }
+
+    /// `Checker.mark_written()`: counts `address` as written, e.g. after setting it up with
+    /// `Machine.poke()`
+    pub fn mark_written(&mut self, address: u16) {
+        self.written[address as usize % RAM_SIZE] = true;
+    }
+
+    /// `Checker.mark_all_written()`: counts every address as written, e.g. for a restored run
+    pub fn mark_all_written(&mut self) {
+        self.written.fill(true);
+    }
+
+    /// `Checker.check()`: the violation of the next instruction of `machine`, before executing it
+    pub fn check(&self, machine: &Machine) -> Option<Violation> {
+        let Instruction::C(c) = machine.instruction(machine.pc()) else {
+            return None;
+        };
+        let address = machine.a() % RAM_SIZE as u16;
+        if c.reads_m && address < SCREEN && !self.written[address as usize] {
+            return Some(Violation::UninitializedRead(address));
+        }
+        if c.dest & DEST_M == 0 {
+            return None;
+        }
+        match address {
+            0 => {
+                let y = if c.reads_m {
+                    machine.peek(address)
+                } else {
+                    machine.a()
+                };
+                let sp = c.comp.eval(machine.d(), y);
+                (!STACK.contains(&sp)).then_some(Violation::StackPointer(sp))
+            }
+            KBD => Some(Violation::KeyboardWrite),
+            SCREEN..KBD if self.graphics.is_empty() || !self.graphics.matches(machine.pc()) => {
+                Some(Violation::ScreenWrite(address))
+            }
+            _ => None,
+        }
+    }
+
+    /// `Checker.step()`: executes the next instruction of `machine` unless it is a violation
+    pub fn step(&mut self, machine: &mut Machine) -> Result<(), Trap> {
+        let pc = machine.pc();
+        let at = Executed {
+            cycle: machine.cycles(),
+            pc,
+            instruction: machine.rom()[pc as usize],
+        };
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(at);
+        if let Some(violation) = self.check(machine) {
+            return Err(self.trap(violation, at));
+        }
+        if let Some(address) = machine.write_address() {
+            self.written[address as usize] = true;
+        }
+        machine.step();
+        if machine.pc() as usize >= machine.program_len() {
+            return Err(self.trap(Violation::PastProgram(machine.pc()), at));
+        }
+        Ok(())
+    }
+
+    /// `Checker.run()`: runs `machine` for `cycles` instructions, pressing the keys of `keys`,
+    /// until the first violation
+    pub fn run(
+        &mut self,
+        machine: &mut Machine,
+        keys: &mut KeyScript,
+        cycles: u64,
+    ) -> Result<(), Trap> {
+        for _ in 0..cycles {
+            keys.apply(machine);
+            self.step(machine)?;
+        }
+        keys.apply(machine);
+        Ok(())
+    }
+
+    fn trap(&self, violation: Violation, at: Executed) -> Trap {
+        Trap {
+            violation,
+            at,
+            history: self.history.iter().copied().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom;
+    use std::path::Path;
+
+    /// runs `asm` in checked mode, with `R0`-`R2` set up, until the first violation
+    fn run(asm: &str, graphics: &[&str]) -> (Program, Option<Trap>) {
+        let program = rom::assemble(asm, "t.asm").unwrap();
+        let mut filter = TraceFilter::default();
+        for spec in graphics {
+            filter.add("--graphics", spec, &program).unwrap();
+        }
+        let mut checker = Checker::new(filter);
+        let mut machine = Machine::new(&program.words);
+        for address in 0..3 {
+            machine.poke(address, 256 + address);
+            checker.mark_written(address);
+        }
+        let trap = checker
+            .run(&mut machine, &mut KeyScript::default(), 100)
+            .err();
+        (program, trap)
+    }
+
+    const END: &str = "(END)\n@END\n0;JMP\n";
+
+    #[test]
+    fn traps_on_each_violation() {
+        let violation = |asm: &str| run(&format!("{}{}", asm, END), &[]).1.map(|t| t.violation);
+        assert_eq!(violation("@2\nD=M\n@20\nM=D\nD=M\n"), None);
+        assert_eq!(
+            violation("@2\nD=M\n@20\nD=D+M\n"),
+            Some(Violation::UninitializedRead(20))
+        );
+        assert_eq!(
+            violation("@SCREEN\nM=-1\n"),
+            Some(Violation::ScreenWrite(SCREEN))
+        );
+        assert_eq!(violation("@KBD\nM=0\n"), Some(Violation::KeyboardWrite));
+        assert_eq!(violation("@KBD\nD=M\n"), None);
+        assert_eq!(
+            violation("@2048\nD=A\n@SP\nM=D\n"),
+            Some(Violation::StackPointer(2048))
+        );
+        assert_eq!(
+            violation("@SP\nM=M-1\n@SP\nM=M-1\n"),
+            Some(Violation::StackPointer(255))
+        );
+        assert_eq!(violation("@SP\nAM=M+1\n"), None);
+        assert_eq!(
+            violation("@100\n0;JMP\n"),
+            Some(Violation::PastProgram(100))
+        );
+        // running off the end without the final loop
+        assert_eq!(
+            run("@1\nD=A\n", &[]).1.map(|t| t.violation),
+            Some(Violation::PastProgram(2))
+        );
+    }
+
+    #[test]
+    fn allows_the_graphics_code_to_write_the_screen() {
+        let asm = format!(
+            "@DRAW\n0;JMP\n(DRAW)\n@SCREEN\nM=-1\n(BLIT)\n@SCREEN\nM=0\n{}",
+            END
+        );
+        assert_eq!(run(&asm, &["DRAW", "BLIT"]).1, None);
+        assert_eq!(run(&asm, &["D*"]).1.unwrap().at.pc, 5);
+    }
+
+    #[test]
+    fn reports_the_source_line_and_history() {
+        let asm = format!("@R2\nD=M\n@x\nM=D\n(LOOP)\n@y\nD=M\n{}", END);
+        let (program, trap) = run(&asm, &[]);
+        let trap = trap.unwrap();
+        assert_eq!(trap.at.pc, 5);
+        assert_eq!(trap.history.len(), 6);
+        assert_eq!(
+            trap.report(&program),
+            "\
+checked mode: read RAM[17], which was never written
+  at cycle 5 PC 5 (LOOP+1) line 7: D=M
+recent instructions:
+  cycle 0 PC 0 (0) line 1: @R2
+  cycle 1 PC 1 (1) line 2: D=M
+  cycle 2 PC 2 (2) line 3: @x
+  cycle 3 PC 3 (3) line 4: M=D
+  cycle 4 PC 4 (LOOP) line 6: @y
+  cycle 5 PC 5 (LOOP+1) line 7: D=M"
+        );
+    }
+
+    #[test]
+    fn runs_pong_without_violations() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../projects/06/pong/Pong.asm");
+        let program = rom::load(&path).unwrap();
+        let mut graphics = TraceFilter::default();
+        // the OS draws with `Screen` and writes text with `Output`
+        for spec in ["screen.*", "output.*"] {
+            graphics.add("--graphics", spec, &program).unwrap();
+        }
+        let mut checker = Checker::new(graphics);
+        let mut machine = Machine::new(&program.words);
+        let mut keys = KeyScript::parse("5200000 right\n5500000 release\n", "t.keys").unwrap();
+        let result = checker.run(&mut machine, &mut keys, 6_000_000);
+        if let Err(trap) = result {
+            panic!("{}", trap.report(&program));
+        }
+    }
+}
@@ -0,0 +1,595 @@
+use crate::decode;
+use crate::machine::{Machine, Registers};
+use crate::rom::Program;
+use crate::screen;
+use crate::source_map::SourceMap;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+/// number of cycles `continue` and `next` run for at most, so that a program that never stops
+/// gives back the prompt
+const RUN_LIMIT: u64 = 100_000_000;
+
+/// the commands of the debugger
+const HELP: &str = "\
+break ADDR|LABEL       set a breakpoint, b
+delete [ADDR|LABEL]    delete a breakpoint, or all of them
+watch ADDR|VAR         stop when RAM[ADDR] changes, w
+unwatch [ADDR|VAR]     delete a watchpoint, or all of them
+step [N]               execute N instructions, s
+next                   step over a call: run until the instruction after a jump, n
+continue [N]           run until a breakpoint, a watchpoint or the end, c
+back [N]               undo N instructions, reverse step
+print ADDR [N] [/d|/x|/b]  print N words of RAM in decimal, hex or binary, p
+regs                   print the registers, r
+list                   show the current instruction, l
+info                   list the breakpoints and watchpoints
+screen FILE            save the screen to a .png or .pbm file
+quit                   leave the debugger, q
+an empty line repeats the last command, addresses can be numbers or symbols";
+
+/// what executing a command asks of the read-eval-print loop
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// print the text and read the next command
+    Output(String),
+    /// leave the debugger
+    Quit,
+}
+
+/// what an instruction undoes to when stepping back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Undo {
+    /// the registers before the instruction
+    registers: Registers,
+    /// the RAM address the instruction wrote and its value before, if it wrote RAM
+    write: Option<(u16, u16)>,
+}
+
+/// why running stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stop {
+    /// the number of instructions asked for ran
+    Done,
+    /// the PC reached a breakpoint
+    Breakpoint,
+    /// a watched address changed
+    Watchpoint { address: u16, old: u16, new: u16 },
+    /// the program reached its final infinite loop
+    Halted,
+    /// the limit of cycles ran out
+    Limit,
+}
+
+/// # Debugger
+/// a debugger for Hack machine code, driven by text commands
+pub struct Debugger {
+    /// the machine being debugged
+    machine: Machine,
+    /// the program, with the assembler's symbol table if it was assembled from .asm
+    program: Program,
+    /// where the instructions were translated from, if known
+    source_map: Option<SourceMap>,
+    /// the ROM addresses to stop at
+    breakpoints: BTreeSet<u16>,
+    /// the RAM addresses to stop at when they change, and the names they were given by
+    watchpoints: BTreeMap<u16, String>,
+    /// how to undo the last instructions, the oldest first
+    history: VecDeque<Undo>,
+    /// the number of instructions `back` can undo
+    history_limit: usize,
+    /// the command an empty line repeats
+    last_command: String,
+}
+
+impl Debugger {
+    /// `Debugger::new()`: constructor
+    /// history_limit: the number of instructions `back` can undo
+    pub fn new(
+        machine: Machine,
+        program: Program,
+        source_map: Option<SourceMap>,
+        history_limit: usize,
+    ) -> Self {
+        Debugger {
+            machine,
+            program,
+            source_map,
+            breakpoints: BTreeSet::new(),
+            watchpoints: BTreeMap::new(),
+            history: VecDeque::new(),
+            history_limit,
+            last_command: String::new(),
+        }
+    }
+
+    /// `Debugger.machine()`: the machine being debugged
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    /// `Debugger.location()`: describes the instruction at the PC, with its label,
+    /// its .asm line and the VM command it was translated from when they are known
+    pub fn location(&self) -> String {
+        let pc = self.machine.pc();
+        let label = match self.program.label_at(pc) {
+            Some(_) => format!(" {}", self.program.describe(pc)),
+            None => String::new(),
+        };
+        let mut text = format!(
+            "{:>5}{}: {}",
+            pc,
+            label,
+            decode::disassemble(self.machine.rom()[pc as usize])
+        );
+        let source = self.source_map.as_ref().and_then(|map| map.get(pc));
+        match (self.program.asm_line(pc), source) {
+            (Some((line, asm)), _) => text += &format!("\n      asm line {}: {}", line, asm),
+            (None, Some(source)) => text += &format!("\n      asm line {}", source.asm_line),
+            (None, None) => {}
+        }
+        if let Some(vm) = source.and_then(|source| source.vm.as_ref()) {
+            text += &format!("\n      vm {}", vm);
+        }
+        text
+    }
+
+    /// `Debugger.execute()`: executes a command, see `help`
+    pub fn execute(&mut self, line: &str) -> Reply {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&command, args)) = words.split_first() else {
+            return Reply::Output(String::new());
+        };
+        let output = match command {
+            "quit" | "q" => return Reply::Quit,
+            "help" | "h" => Ok(HELP.to_string()),
+            "break" | "b" => self.set_breakpoint(args),
+            "delete" | "d" => self.delete_breakpoint(args),
+            "watch" | "w" => self.set_watchpoint(args),
+            "unwatch" => self.delete_watchpoint(args),
+            "step" | "s" => count(args, 1).map(|n| self.run_and_report(n)),
+            "next" | "n" => Ok(self.next()),
+            "continue" | "c" => count(args, RUN_LIMIT).map(|n| self.run_and_report(n)),
+            "back" => count(args, 1).map(|n| self.back(n)),
+            "print" | "p" => self.print(args),
+            "regs" | "r" => Ok(self.registers()),
+            "list" | "l" => Ok(self.location()),
+            "info" | "i" => Ok(self.info()),
+            "screen" => self.screen(args),
+            _ => Err(format!("unknown command `{}`, try `help`", command)),
+        };
+        Reply::Output(output.unwrap_or_else(|err| format!("error: {}", err)))
+    }
+
+    /// `Debugger.repl()`: reads commands from `input` and writes their output to `output`
+    /// until `quit` or the end of the input
+    pub fn repl<R: BufRead, W: Write>(&mut self, input: R, mut output: W) -> io::Result<()> {
+        writeln!(output, "{}", self.location())?;
+        write!(output, "(hdb) ")?;
+        output.flush()?;
+        for line in input.lines() {
+            match self.execute(&line?) {
+                Reply::Output(text) if text.is_empty() => {}
+                Reply::Output(text) => writeln!(output, "{}", text)?,
+                Reply::Quit => return Ok(()),
+            }
+            write!(output, "(hdb) ")?;
+            output.flush()?;
+        }
+        writeln!(output)
+    }
+
+    /// resolves a ROM address given as a number or a label
+    fn rom_address(&self, arg: &str) -> Result<u16, String> {
+        parse_number(arg)
+            .or_else(|| self.program.label(arg))
+            .ok_or_else(|| format!("`{}` is neither a ROM address nor a label", arg))
+    }
+
+    /// resolves a RAM address given as a number or a variable
+    fn ram_address(&self, arg: &str) -> Result<u16, String> {
+        parse_number(arg)
+            .or_else(|| self.program.variable(arg))
+            .ok_or_else(|| format!("`{}` is neither a RAM address nor a variable", arg))
+    }
+
+    /// the `break` command
+    fn set_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let [arg] = args else {
+            return Err("usage: break ADDR|LABEL".to_string());
+        };
+        let address = self.rom_address(arg)?;
+        self.breakpoints.insert(address);
+        Ok(format!(
+            "breakpoint at {} ({})",
+            address,
+            self.program.describe(address)
+        ))
+    }
+
+    /// the `delete` command
+    fn delete_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args {
+            [] => {
+                self.breakpoints.clear();
+                Ok("deleted all breakpoints".to_string())
+            }
+            [arg] => {
+                let address = self.rom_address(arg)?;
+                if self.breakpoints.remove(&address) {
+                    Ok(format!("deleted the breakpoint at {}", address))
+                } else {
+                    Err(format!("no breakpoint at {}", address))
+                }
+            }
+            _ => Err("usage: delete [ADDR|LABEL]".to_string()),
+        }
+    }
+
+    /// the `watch` command
+    fn set_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let [arg] = args else {
+            return Err("usage: watch ADDR|VAR".to_string());
+        };
+        let address = self.ram_address(arg)?;
+        self.watchpoints.insert(address, arg.to_string());
+        Ok(format!("watchpoint on RAM[{}] ({})", address, arg))
+    }
+
+    /// the `unwatch` command
+    fn delete_watchpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args {
+            [] => {
+                self.watchpoints.clear();
+                Ok("deleted all watchpoints".to_string())
+            }
+            [arg] => {
+                let address = self.ram_address(arg)?;
+                match self.watchpoints.remove(&address) {
+                    Some(_) => Ok(format!("deleted the watchpoint on RAM[{}]", address)),
+                    None => Err(format!("no watchpoint on RAM[{}]", address)),
+                }
+            }
+            _ => Err("usage: unwatch [ADDR|VAR]".to_string()),
+        }
+    }
+
+    /// executes one instruction, remembering how to undo it,
+    /// and returns the RAM address it wrote with the old and the new value
+    fn step(&mut self) -> Option<(u16, u16, u16)> {
+        let registers = self.machine.registers();
+        let write = self
+            .machine
+            .write_address()
+            .map(|address| (address, self.machine.peek(address)));
+        self.machine.step();
+        if self.history_limit > 0 {
+            if self.history.len() == self.history_limit {
+                self.history.pop_front();
+            }
+            self.history.push_back(Undo { registers, write });
+        }
+        write.map(|(address, old)| (address, old, self.machine.peek(address)))
+    }
+
+    /// runs up to `cycles` instructions, stopping at breakpoints, watchpoints and the end
+    /// until: stops when the PC reaches this address with SP at most this value
+    fn run(&mut self, cycles: u64, until: Option<(u16, u16)>) -> Stop {
+        for _ in 0..cycles {
+            if let Some((address, old, new)) = self.step() {
+                if old != new && self.watchpoints.contains_key(&address) {
+                    return Stop::Watchpoint { address, old, new };
+                }
+            }
+            let pc = self.machine.pc();
+            if self.breakpoints.contains(&pc) {
+                return Stop::Breakpoint;
+            }
+            if let Some((address, sp)) = until {
+                if pc == address && self.machine.peek(0) <= sp {
+                    return Stop::Done;
+                }
+            }
+            if self.machine.halted() {
+                return Stop::Halted;
+            }
+        }
+        if until.is_some() {
+            Stop::Limit
+        } else {
+            Stop::Done
+        }
+    }
+
+    /// the `next` command: steps over a jump by running until the instruction after it
+    /// is reached with SP no higher than before, so a call runs until it returns
+    fn next(&mut self) -> String {
+        let pc = self.machine.pc();
+        let stop = match self.machine.instruction(pc) {
+            decode::Instruction::C(c) if c.jump != 0 => {
+                let sp = self.machine.peek(0);
+                self.run(RUN_LIMIT, Some(((pc + 1) & 0x7fff, sp)))
+            }
+            _ => self.run(1, None),
+        };
+        self.report(stop)
+    }
+
+    /// the `step` and `continue` commands: runs up to `cycles` instructions
+    fn run_and_report(&mut self, cycles: u64) -> String {
+        let stop = self.run(cycles, None);
+        self.report(stop)
+    }
+
+    /// describes why running stopped and where
+    fn report(&self, stop: Stop) -> String {
+        let reason = match stop {
+            Stop::Done => String::new(),
+            Stop::Breakpoint => "breakpoint\n".to_string(),
+            Stop::Watchpoint { address, old, new } => format!(
+                "watchpoint {}: RAM[{}] {} -> {}\n",
+                self.watchpoints[&address], address, old as i16, new as i16
+            ),
+            Stop::Halted => "the program halted\n".to_string(),
+            Stop::Limit => format!("stopped after {} cycles\n", RUN_LIMIT),
+        };
+        reason + &self.location()
+    }
+
+    /// the `back` command: undoes the last `n` instructions
+    fn back(&mut self, n: u64) -> String {
+        for _ in 0..n {
+            let Some(undo) = self.history.pop_back() else {
+                return format!("no more history\n{}", self.location());
+            };
+            if let Some((address, old)) = undo.write {
+                self.machine.poke(address, old);
+            }
+            self.machine.set_registers(undo.registers);
+        }
+        self.location()
+    }
+
+    /// the `print` command
+    fn print(&self, args: &[&str]) -> Result<String, String> {
+        let usage = || "usage: print ADDR [N] [/d|/x|/b]".to_string();
+        let (format, args) = match args.split_last() {
+            Some((last, rest)) if last.starts_with('/') => (*last, rest),
+            _ => ("/d", args),
+        };
+        let (address, n) = match args {
+            [address] => (self.ram_address(address)?, 1),
+            [address, n] => (
+                self.ram_address(address)?,
+                parse_number(n).ok_or_else(usage)?,
+            ),
+            _ => return Err(usage()),
+        };
+        let mut lines = Vec::new();
+        for address in (address..=u16::MAX).take(n as usize) {
+            let value = self.machine.peek(address);
+            let value = match format {
+                "/d" => (value as i16).to_string(),
+                "/x" => format!("0x{:04x}", value),
+                "/b" => format!("{:016b}", value),
+                _ => return Err(usage()),
+            };
+            lines.push(format!("RAM[{}] = {}", address, value));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    /// the `regs` command
+    fn registers(&self) -> String {
+        format!(
+            "A={} D={} PC={} cycles={}",
+            self.machine.a(),
+            self.machine.d() as i16,
+            self.machine.pc(),
+            self.machine.cycles()
+        )
+    }
+
+    /// the `info` command
+    fn info(&self) -> String {
+        let mut lines = Vec::new();
+        for &address in &self.breakpoints {
+            lines.push(format!(
+                "breakpoint at {} ({})",
+                address,
+                self.program.describe(address)
+            ));
+        }
+        for (address, name) in &self.watchpoints {
+            lines.push(format!("watchpoint on RAM[{}] ({})", address, name));
+        }
+        if lines.is_empty() {
+            lines.push("no breakpoints or watchpoints".to_string());
+        }
+        lines.join("\n")
+    }
+
+    /// the `screen` command
+    fn screen(&self, args: &[&str]) -> Result<String, String> {
+        let [path] = args else {
+            return Err("usage: screen FILE".to_string());
+        };
+        screen::save(self.machine.screen(), Path::new(path)).map_err(|err| err.to_string())?;
+        Ok(format!("saved {}", path))
+    }
+}
+
+/// parses a decimal or `0x` hexadecimal number
+fn parse_number(arg: &str) -> Option<u16> {
+    match arg.strip_prefix("0x") {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => arg.parse().ok(),
+    }
+}
+
+/// parses the optional count of a command
+fn count(args: &[&str], default: u64) -> Result<u64, String> {
+    match args {
+        [] => Ok(default),
+        [n] => n.parse().map_err(|_| format!("`{}` is not a count", n)),
+        _ => Err("expected at most one count".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom;
+
+    /// sums 1..=n into `sum` through a subroutine called from `(MAIN)`
+    const PROGRAM: &str = "\
+(MAIN)
+@3
+D=A
+@n
+M=D
+@RET
+D=A
+@R15
+M=D
+@ADD
+0;JMP
+(RET)
+@END
+0;JMP
+(ADD)
+@n
+D=M
+@sum
+M=M+D
+@n
+M=M-1
+D=M
+@ADD
+D;JGT
+@R15
+A=M
+0;JMP
+(END)
+@END
+0;JMP
+";
+
+    fn debugger() -> Debugger {
+        let program = rom::assemble(PROGRAM, "Sum.asm").unwrap();
+        let machine = Machine::new(&program.words);
+        Debugger::new(machine, program, None, 100)
+    }
+
+    fn output(debugger: &mut Debugger, command: &str) -> String {
+        match debugger.execute(command) {
+            Reply::Output(text) => text,
+            Reply::Quit => panic!("unexpected quit"),
+        }
+    }
+
+    #[test]
+    fn stops_at_breakpoints_by_label() {
+        let mut debugger = debugger();
+        assert_eq!(output(&mut debugger, "break ADD"), "breakpoint at 12 (ADD)");
+        let text = output(&mut debugger, "continue");
+        assert_eq!(text, "breakpoint\n   12 ADD: @16\n      asm line 16: @n");
+        output(&mut debugger, "delete ADD");
+        assert!(output(&mut debugger, "c").starts_with("the program halted\n"));
+        assert_eq!(debugger.machine().peek(17), 6);
+        assert_eq!(
+            output(&mut debugger, "break NOWHERE"),
+            "error: `NOWHERE` is neither a ROM address nor a label"
+        );
+    }
+
+    #[test]
+    fn stops_when_a_watched_variable_changes() {
+        let mut debugger = debugger();
+        output(&mut debugger, "watch sum");
+        let text = output(&mut debugger, "c");
+        assert!(
+            text.starts_with("watchpoint sum: RAM[17] 0 -> 3\n"),
+            "{}",
+            text
+        );
+        let text = output(&mut debugger, "");
+        assert!(
+            text.starts_with("watchpoint sum: RAM[17] 3 -> 5\n"),
+            "{}",
+            text
+        );
+        assert_eq!(
+            output(&mut debugger, "print n 2"),
+            "RAM[16] = 2\nRAM[17] = 5"
+        );
+    }
+
+    #[test]
+    fn steps_over_calls() {
+        let mut debugger = debugger();
+        output(&mut debugger, "step 9");
+        assert!(output(&mut debugger, "list").starts_with("    9 MAIN+9: 0;JMP"));
+        let text = output(&mut debugger, "next");
+        assert!(text.starts_with("   10 RET: @24"), "{}", text);
+        assert_eq!(debugger.machine().peek(17), 6);
+    }
+
+    #[test]
+    fn steps_back_through_the_history() {
+        let mut debugger = debugger();
+        output(&mut debugger, "watch sum");
+        output(&mut debugger, "c");
+        output(&mut debugger, "c");
+        let before = output(&mut debugger, "regs");
+        output(&mut debugger, "step 7");
+        output(&mut debugger, "back 7");
+        assert_eq!(output(&mut debugger, "regs"), before);
+        assert_eq!(debugger.machine().peek(17), 5);
+        output(&mut debugger, "back 9");
+        assert_eq!(debugger.machine().peek(17), 3);
+        assert_eq!(
+            output(&mut debugger, "back 1000").lines().next(),
+            Some("no more history")
+        );
+        assert_eq!(debugger.machine().cycles(), 0);
+        assert_eq!(debugger.machine().peek(17), 0);
+    }
+
+    #[test]
+    fn prints_ram_in_several_formats() {
+        let mut debugger = debugger();
+        output(&mut debugger, "c");
+        assert_eq!(output(&mut debugger, "p sum /x"), "RAM[17] = 0x0006");
+        assert_eq!(
+            output(&mut debugger, "p 15 /b"),
+            "RAM[15] = 0000000000001010"
+        );
+        assert_eq!(output(&mut debugger, "p R15 1 /d"), "RAM[15] = 10");
+        assert_eq!(
+            output(&mut debugger, "p sum /o"),
+            "error: usage: print ADDR [N] [/d|/x|/b]"
+        );
+    }
+
+    #[test]
+    fn shows_the_translated_source() {
+        let program = rom::parse_hack("0000000000000111\n1110110000010000\n", "t.hack").unwrap();
+        let map = SourceMap::parse(
+            "1\t-\tMain.vm:2\tpush constant 7\n2\t0\tMain.vm:2\tpush constant 7\n3\t1\tMain.vm:2\tpush constant 7\n",
+            "t.map",
+        )
+        .unwrap();
+        let mut debugger = Debugger::new(Machine::new(&program.words), program, Some(map), 10);
+        assert_eq!(
+            output(&mut debugger, "s"),
+            "    1: D=A\n      asm line 3\n      vm Main.vm:2 push constant 7"
+        );
+    }
+}
@@ -0,0 +1,341 @@
+use hack_assembler::{COMPUTE_TABLE, DEST_TABLE, JUMP_TABLE};
+use std::sync::OnceLock;
+
+/// bit of the destination that writes the A register
+pub const DEST_A: u8 = 0b100;
+/// bit of the destination that writes the D register
+pub const DEST_D: u8 = 0b010;
+/// bit of the destination that writes RAM[A]
+pub const DEST_M: u8 = 0b001;
+
+/// the computation of a C-instruction, where `Y` stands for A or M depending on the a-bit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comp {
+    Zero,
+    One,
+    MinusOne,
+    D,
+    Y,
+    NotD,
+    NotY,
+    NegD,
+    NegY,
+    DPlusOne,
+    YPlusOne,
+    DMinusOne,
+    YMinusOne,
+    DPlusY,
+    DMinusY,
+    YMinusD,
+    DAndY,
+    DOrY,
+    /// c-bits that the assembler has no mnemonic for, computed bit by bit like the ALU does
+    Alu(u8),
+}
+
+impl Comp {
+    /// `Comp::from_mnemonic()`: the computation named by a mnemonic of `COMPUTE_TABLE`
+    fn from_mnemonic(mnemonic: &str) -> Option<Self> {
+        let comp = match mnemonic.replace('M', "A").as_str() {
+            "0" => Comp::Zero,
+            "1" => Comp::One,
+            "-1" => Comp::MinusOne,
+            "D" => Comp::D,
+            "A" => Comp::Y,
+            "!D" => Comp::NotD,
+            "!A" => Comp::NotY,
+            "-D" => Comp::NegD,
+            "-A" => Comp::NegY,
+            "D+1" => Comp::DPlusOne,
+            "A+1" => Comp::YPlusOne,
+            "D-1" => Comp::DMinusOne,
+            "A-1" => Comp::YMinusOne,
+            "D+A" | "A+D" => Comp::DPlusY,
+            "D-A" => Comp::DMinusY,
+            "A-D" => Comp::YMinusD,
+            "D&A" | "A&D" => Comp::DAndY,
+            "D|A" | "A|D" => Comp::DOrY,
+            _ => return None,
+        };
+        Some(comp)
+    }
+
+    /// `Comp.eval()`: computes the output of the ALU
+    /// d: the D register
+    /// y: the A register or RAM[A]
+    #[inline(always)]
+    pub fn eval(self, d: u16, y: u16) -> u16 {
+        match self {
+            Comp::Zero => 0,
+            Comp::One => 1,
+            Comp::MinusOne => 0xffff,
+            Comp::D => d,
+            Comp::Y => y,
+            Comp::NotD => !d,
+            Comp::NotY => !y,
+            Comp::NegD => d.wrapping_neg(),
+            Comp::NegY => y.wrapping_neg(),
+            Comp::DPlusOne => d.wrapping_add(1),
+            Comp::YPlusOne => y.wrapping_add(1),
+            Comp::DMinusOne => d.wrapping_sub(1),
+            Comp::YMinusOne => y.wrapping_sub(1),
+            Comp::DPlusY => d.wrapping_add(y),
+            Comp::DMinusY => d.wrapping_sub(y),
+            Comp::YMinusD => y.wrapping_sub(d),
+            Comp::DAndY => d & y,
+            Comp::DOrY => d | y,
+            Comp::Alu(bits) => alu(bits, d, y),
+        }
+    }
+}
+
+/// computes the ALU output for the c-bits `zx nx zy ny f no`
+fn alu(bits: u8, d: u16, y: u16) -> u16 {
+    let mut x = if bits & 0b100000 != 0 { 0 } else { d };
+    if bits & 0b010000 != 0 {
+        x = !x;
+    }
+    let mut y = if bits & 0b001000 != 0 { 0 } else { y };
+    if bits & 0b000100 != 0 {
+        y = !y;
+    }
+    let out = if bits & 0b000010 != 0 {
+        x.wrapping_add(y)
+    } else {
+        x & y
+    };
+    if bits & 0b000001 != 0 {
+        !out
+    } else {
+        out
+    }
+}
+
+/// a decoded C-instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CInstruction {
+    /// the computation
+    pub comp: Comp,
+    /// whether the computation reads RAM[A] rather than A
+    pub reads_m: bool,
+    /// the `DEST_A`, `DEST_D` and `DEST_M` bits
+    pub dest: u8,
+    /// the j-bits: jump if the output is negative, zero, positive
+    pub jump: u8,
+}
+
+impl CInstruction {
+    /// `CInstruction.jumps()`: whether the instruction jumps for the ALU output `out`
+    #[inline(always)]
+    pub fn jumps(&self, out: u16) -> bool {
+        jumps(self.jump, out)
+    }
+}
+
+/// whether the j-bits `jump` take the jump for the ALU output `out`
+#[inline(always)]
+pub fn jumps(jump: u8, out: u16) -> bool {
+    let out = out as i16;
+    (jump & 0b100 != 0 && out < 0)
+        || (jump & 0b010 != 0 && out == 0)
+        || (jump & 0b001 != 0 && out > 0)
+}
+
+/// a decoded instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    /// `@value`
+    A(u16),
+    /// `dest=comp;jump`
+    C(CInstruction),
+}
+
+/// an instruction of the pre-decoded program, possibly fused with the instruction after it
+/// into a superinstruction that takes two cycles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Op {
+    /// `@value`
+    A(u16),
+    /// `dest=comp;jump`
+    C(CInstruction),
+    /// `@address` `D=M`
+    LoadM(u16),
+    /// `@value` `D=A`
+    LoadA(u16),
+    /// `@address` `0;JMP`
+    Goto(u16),
+    /// `@address` `D;jump`
+    BranchD(u16, u8),
+    /// `@value` followed by any other C-instruction
+    AThenC(u16, CInstruction),
+}
+
+/// maps the a-bit and c-bits of a C-instruction to its computation, built once from `COMPUTE_TABLE`
+fn comp_table() -> &'static [Comp; 128] {
+    static TABLE: OnceLock<[Comp; 128]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [Comp::Zero; 128];
+        for (bits, comp) in table.iter_mut().enumerate() {
+            *comp = Comp::Alu(bits as u8 & 0b111111);
+        }
+        for (mnemonic, bits) in COMPUTE_TABLE {
+            let bits = usize::from_str_radix(bits, 2).expect("the table holds binary numbers");
+            table[bits] = Comp::from_mnemonic(mnemonic).expect("the table holds known mnemonics");
+        }
+        table
+    })
+}
+
+/// decodes a single instruction word
+pub fn decode(word: u16) -> Instruction {
+    if word & 0x8000 == 0 {
+        return Instruction::A(word);
+    }
+    Instruction::C(CInstruction {
+        comp: comp_table()[(word >> 6) as usize & 0b1111111],
+        reads_m: word & 0x1000 != 0,
+        dest: (word >> 3) as u8 & 0b111,
+        jump: word as u8 & 0b111,
+    })
+}
+
+/// pre-decodes a program into one op per ROM address
+/// an A-instruction followed by a C-instruction is fused into a superinstruction,
+/// the op of the C-instruction is kept at its own address for jumps that land on it
+pub fn predecode(rom: &[u16]) -> Vec<Op> {
+    let instructions: Vec<Instruction> = rom.iter().map(|&word| decode(word)).collect();
+    let mut ops = Vec::with_capacity(instructions.len());
+    for (idx, instruction) in instructions.iter().enumerate() {
+        let op = match (instruction, instructions.get(idx + 1)) {
+            (Instruction::A(value), Some(Instruction::C(c))) => fuse(*value, *c),
+            (Instruction::A(value), _) => Op::A(*value),
+            (Instruction::C(c), _) => Op::C(*c),
+        };
+        ops.push(op);
+    }
+    ops
+}
+
+/// fuses `@value` with the C-instruction after it
+fn fuse(value: u16, c: CInstruction) -> Op {
+    match c {
+        CInstruction {
+            comp: Comp::Y,
+            reads_m,
+            dest: DEST_D,
+            jump: 0,
+        } => {
+            if reads_m {
+                Op::LoadM(value)
+            } else {
+                Op::LoadA(value)
+            }
+        }
+        CInstruction {
+            comp: Comp::Zero,
+            dest: 0,
+            jump: 0b111,
+            ..
+        } => Op::Goto(value),
+        CInstruction {
+            comp: Comp::D,
+            dest: 0,
+            jump,
+            ..
+        } if jump != 0 => Op::BranchD(value, jump),
+        _ => Op::AThenC(value, c),
+    }
+}
+
+/// returns the mnemonic of `bits` in `table`, the first one for bits that have several
+fn mnemonic(table: &'static [(&'static str, &'static str)], bits: &str) -> Option<&'static str> {
+    table
+        .iter()
+        .find(|(_, table_bits)| *table_bits == bits)
+        .map(|(mnemonic, _)| *mnemonic)
+}
+
+/// disassembles an instruction word, e.g. `@17` or `AM=M+1`
+/// computations the assembler has no mnemonic for are shown as their c-bits
+pub fn disassemble(word: u16) -> String {
+    if word & 0x8000 == 0 {
+        return format!("@{}", word);
+    }
+    let comp_bits = format!("{:07b}", (word >> 6) & 0b1111111);
+    let comp = match mnemonic(&COMPUTE_TABLE, &comp_bits) {
+        Some(comp) => comp.to_string(),
+        None => format!("<{}>", comp_bits),
+    };
+    let dest = (word >> 3) & 0b111;
+    let jump = word & 0b111;
+    let mut text = String::new();
+    if dest != 0 {
+        text.push_str(mnemonic(&DEST_TABLE, &format!("{:03b}", dest)).unwrap_or_default());
+        text.push('=');
+    }
+    text.push_str(&comp);
+    if jump != 0 {
+        text.push(';');
+        text.push_str(mnemonic(&JUMP_TABLE, &format!("{:03b}", jump)).unwrap_or_default());
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hack_assembler::Assembler;
+
+    #[test]
+    fn table_computations_match_the_alu() {
+        for (mnemonic, bits) in COMPUTE_TABLE {
+            let bits = u8::from_str_radix(bits, 2).unwrap() & 0b111111;
+            let comp = Comp::from_mnemonic(mnemonic).unwrap();
+            for (d, y) in [
+                (0, 0),
+                (1, 0xffff),
+                (0x7fff, 1),
+                (0x8000, 0x8000),
+                (1234, 4321),
+            ] {
+                assert_eq!(
+                    comp.eval(d, y),
+                    alu(bits, d, y),
+                    "{} on {} {}",
+                    mnemonic,
+                    d,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn disassembles_what_the_assembler_assembles() {
+        let asm = "@17\nAM=M+1\nD;JGT\n0;JMP\nMD=D|M\nM=-1;JLE\n";
+        let hack = Assembler::new(asm).assemble().unwrap();
+        let words = hack
+            .lines()
+            .map(|line| u16::from_str_radix(line, 2).unwrap());
+        let text: Vec<String> = words.map(disassemble).collect();
+        assert_eq!(text.join("\n") + "\n", asm);
+    }
+
+    #[test]
+    fn fuses_an_a_instruction_with_the_next_c_instruction() {
+        let hack = Assembler::new("@5\nD=M\n@7\n0;JMP\n@1\nD;JNE\n@2\nM=D\n@3\n")
+            .assemble()
+            .unwrap();
+        let rom: Vec<u16> = hack
+            .lines()
+            .map(|line| u16::from_str_radix(line, 2).unwrap())
+            .collect();
+        let ops = predecode(&rom);
+        assert_eq!(ops[0], Op::LoadM(5));
+        assert_eq!(ops[2], Op::Goto(7));
+        assert_eq!(ops[4], Op::BranchD(1, 0b101));
+        assert!(matches!(ops[6], Op::AThenC(2, _)));
+        assert!(matches!(ops[7], Op::C(_)));
+        assert_eq!(ops[8], Op::A(3));
+    }
+}
@@ -0,0 +1,401 @@
+use crate::machine::{KBD, RAM_SIZE, SCREEN};
+use crate::screen::SCREEN_WORDS;
+use std::any::Any;
+use std::ops::Range;
+
+/// # Device
+/// a memory-mapped peripheral: it claims a range of the I/O addresses, from `SCREEN` up, and the
+/// machine reads and writes it instead of the RAM there
+///
+/// devices that need time, e.g. timers, return true from `needs_tick`; they are ticked lazily with
+/// the cycle count before each access and at the end of `Machine.run()` and `Machine.step()`,
+/// so they must not rely on being ticked every cycle
+pub trait Device {
+    /// `Device.name()`: the name of the device, used in error messages
+    fn name(&self) -> &str;
+
+    /// `Device.range()`: the addresses the device claims
+    fn range(&self) -> Range<u16>;
+
+    /// `Device.peek()`: the word at `address` without side effects, e.g. for debuggers and snapshots
+    fn peek(&self, address: u16) -> u16;
+
+    /// `Device.read()`: the word at `address` read by the program
+    fn read(&mut self, address: u16) -> u16 {
+        self.peek(address)
+    }
+
+    /// `Device.write()`: a word written at `address`, by the program or with `Machine.poke()`
+    fn write(&mut self, address: u16, value: u16);
+
+    /// `Device.needs_tick()`: whether `tick` must be called, false so that the machine can skip
+    /// the devices that do not depend on time
+    fn needs_tick(&self) -> bool {
+        false
+    }
+
+    /// `Device.tick()`: the machine has executed `cycle` instructions since the reset
+    fn tick(&mut self, _cycle: u64) {}
+
+    /// `Device.symbols()`: the names of the addresses of the device, for the assembler
+    fn symbols(&self) -> Vec<(String, u16)> {
+        Vec::new()
+    }
+
+    /// `Device.as_any()`: the device as `Any`, to get it back with `Machine.device()`
+    fn as_any(&self) -> &dyn Any;
+
+    /// `Device.as_any_mut()`: the device as `Any`, to get it back with `Machine.device_mut()`
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+
+/// the default devices of the Hack computer, the screen and the keyboard
+pub fn hack_devices() -> Vec<Box<dyn Device>> {
+    vec![Box::new(Screen::new()), Box::new(Keyboard::new())]
+}
+
+/// the screen: 512x256 black and white pixels, 16 per word, at `SCREEN`
+pub struct Screen {
+    /// the words of the screen memory map
+    words: Vec<u16>,
+}
+
+impl Screen {
+    /// `Screen::new()`: constructor, all the pixels are white
+    pub fn new() -> Self {
+        Screen {
+            words: vec![0; SCREEN_WORDS],
+        }
+    }
+
+    /// `Screen.words()`: the words of the screen memory map
+    pub fn words(&self) -> &[u16] {
+        &self.words
+    }
+}
+
+impl Default for Screen {
+    fn default() -> Self {
+        Screen::new()
+    }
+}
+
+impl Device for Screen {
+    fn name(&self) -> &str {
+        "screen"
+    }
+
+    fn range(&self) -> Range<u16> {
+        SCREEN..KBD
+    }
+
+    fn peek(&self, address: u16) -> u16 {
+        self.words[(address - SCREEN) as usize]
+    }
+
+    fn write(&mut self, address: u16, value: u16) {
+        self.words[(address - SCREEN) as usize] = value;
+    }
+
+    fn symbols(&self) -> Vec<(String, u16)> {
+        vec![("SCREEN".to_string(), SCREEN)]
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// the keyboard: the code of the key being pressed, 0 for none, at `KBD`
+#[derive(Default)]
+pub struct Keyboard {
+    /// the key code
+    code: u16,
+}
+
+impl Keyboard {
+    /// `Keyboard::new()`: constructor, no key is pressed
+    pub fn new() -> Self {
+        Keyboard { code: 0 }
+    }
+
+    /// `Keyboard.set_key()`: sets the code of the key being pressed, 0 for none
+    pub fn set_key(&mut self, code: u16) {
+        self.code = code;
+    }
+}
+
+impl Device for Keyboard {
+    fn name(&self) -> &str {
+        "keyboard"
+    }
+
+    fn range(&self) -> Range<u16> {
+        KBD..KBD + 1
+    }
+
+    fn peek(&self, _address: u16) -> u16 {
+        self.code
+    }
+
+    fn write(&mut self, _address: u16, value: u16) {
+        self.code = value;
+    }
+
+    fn symbols(&self) -> Vec<(String, u16)> {
+        vec![("KBD".to_string(), KBD)]
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+}
+
+/// marks the I/O addresses that no device claims in `Bus.owners`
+const NO_DEVICE: u8 = u8::MAX;
+/// number of I/O addresses, from `SCREEN` to the end of the address space
+const IO_SIZE: usize = RAM_SIZE - SCREEN as usize;
+
+/// the devices of a machine and the device of every I/O address
+pub(crate) struct Bus {
+    /// the devices, in the order they were added
+    devices: Vec<Box<dyn Device>>,
+    /// the index in `devices` of the device at every I/O address, `NO_DEVICE` for plain RAM
+    owners: Box<[u8; IO_SIZE]>,
+    /// the indices of the devices that need ticks
+    tickers: Vec<usize>,
+}
+
+impl Bus {
+    /// `Bus::new()`: a bus without devices
+    pub(crate) fn new() -> Self {
+        Bus {
+            devices: Vec::new(),
+            owners: vec![NO_DEVICE; IO_SIZE]
+                .try_into()
+                .expect("IO_SIZE addresses"),
+            tickers: Vec::new(),
+        }
+    }
+
+    /// `Bus.add()`: adds a device, or explains why its range cannot be claimed
+    pub(crate) fn add(&mut self, device: Box<dyn Device>) -> Result<(), String> {
+        let range = device.range();
+        if range.start < SCREEN || range.end as usize > RAM_SIZE || range.is_empty() {
+            return Err(format!(
+                "addresses {}-{} are not I/O addresses, {}-{}",
+                range.start,
+                range.end.wrapping_sub(1),
+                SCREEN,
+                RAM_SIZE - 1
+            ));
+        }
+        if self.devices.len() == NO_DEVICE as usize {
+            return Err("too many devices".to_string());
+        }
+        let owned = (range.start - SCREEN) as usize..(range.end - SCREEN) as usize;
+        if let Some(&owner) = self.owners[owned.clone()]
+            .iter()
+            .find(|&&owner| owner != NO_DEVICE)
+        {
+            return Err(format!(
+                "its addresses overlap those of `{}`",
+                self.devices[owner as usize].name()
+            ));
+        }
+        let index = self.devices.len() as u8;
+        self.owners[owned].fill(index);
+        if device.needs_tick() {
+            self.tickers.push(index as usize);
+        }
+        self.devices.push(device);
+        Ok(())
+    }
+
+    /// `Bus.devices()`: the devices
+    pub(crate) fn devices(&self) -> &[Box<dyn Device>] {
+        &self.devices
+    }
+
+    /// `Bus.devices_mut()`: the devices
+    pub(crate) fn devices_mut(&mut self) -> &mut [Box<dyn Device>] {
+        &mut self.devices
+    }
+
+    /// `Bus.owner()`: the index of the device at I/O `address`
+    #[inline(always)]
+    fn owner(&self, address: u16) -> Option<usize> {
+        let owner = self.owners[(address - SCREEN) as usize];
+        (owner != NO_DEVICE).then_some(owner as usize)
+    }
+
+    /// `Bus.peek()`: the word of the device at I/O `address`, none for plain RAM
+    pub(crate) fn peek(&self, address: u16) -> Option<u16> {
+        self.owner(address)
+            .map(|owner| self.devices[owner].peek(address))
+    }
+
+    /// `Bus.read()`: reads the device at I/O `address` at `cycle`, none for plain RAM
+    pub(crate) fn read(&mut self, address: u16, cycle: u64) -> Option<u16> {
+        let owner = self.owner(address)?;
+        let device = &mut self.devices[owner];
+        if device.needs_tick() {
+            device.tick(cycle);
+        }
+        Some(device.read(address))
+    }
+
+    /// `Bus.write()`: writes the device at I/O `address` at `cycle`,
+    /// returns false for plain RAM
+    pub(crate) fn write(&mut self, address: u16, value: u16, cycle: u64) -> bool {
+        let Some(owner) = self.owner(address) else {
+            return false;
+        };
+        let device = &mut self.devices[owner];
+        if device.needs_tick() {
+            device.tick(cycle);
+        }
+        device.write(address, value);
+        true
+    }
+
+    /// `Bus.tick()`: ticks every device that needs it
+    pub(crate) fn tick(&mut self, cycle: u64) {
+        for &index in &self.tickers {
+            self.devices[index].tick(cycle);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::machine::Machine;
+    use crate::rom;
+
+    /// counts the cycles since it was last read, at the address after the keyboard
+    struct Timer {
+        /// the cycle count at the last tick
+        now: u64,
+        /// the cycle count at the last read
+        start: u64,
+    }
+
+    impl Device for Timer {
+        fn name(&self) -> &str {
+            "timer"
+        }
+
+        fn range(&self) -> Range<u16> {
+            KBD + 1..KBD + 2
+        }
+
+        fn peek(&self, _address: u16) -> u16 {
+            (self.now - self.start) as u16
+        }
+
+        fn read(&mut self, address: u16) -> u16 {
+            let elapsed = self.peek(address);
+            self.start = self.now;
+            elapsed
+        }
+
+        fn write(&mut self, _address: u16, _value: u16) {}
+
+        fn needs_tick(&self) -> bool {
+            true
+        }
+
+        fn tick(&mut self, cycle: u64) {
+            self.now = cycle;
+        }
+
+        fn symbols(&self) -> Vec<(String, u16)> {
+            vec![("TIMER".to_string(), KBD + 1)]
+        }
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+
+        fn as_any_mut(&mut self) -> &mut dyn Any {
+            self
+        }
+    }
+
+    fn machine_with_timer(asm: &str) -> Machine {
+        let mut machine = Machine::new(&[]);
+        machine
+            .add_device(Box::new(Timer { now: 0, start: 0 }))
+            .unwrap();
+        let program = rom::assemble_with_symbols(asm, "t.asm", &machine.symbols()).unwrap();
+        assert_eq!(program.variable("TIMER"), Some(KBD + 1));
+        let mut loaded = Machine::new(&program.words);
+        loaded
+            .add_device(Box::new(Timer { now: 0, start: 0 }))
+            .unwrap();
+        loaded
+    }
+
+    #[test]
+    fn ticks_devices_before_they_are_read() {
+        // reads the timer at cycles 3 and 5
+        let asm = "@1\n@2\n@TIMER\nD=M\n@TIMER\nD=M\n@sum\nM=D\n";
+        let mut machine = machine_with_timer(asm);
+        machine.run(4);
+        assert_eq!(machine.d(), 3);
+        machine.run(4);
+        assert_eq!(machine.peek(16), 2);
+        // the devices are ticked at the end of a run
+        assert_eq!(machine.peek(KBD + 1), 3);
+        machine.run(2);
+        assert_eq!(machine.peek(KBD + 1), 5);
+
+        let mut stepped = machine_with_timer(asm);
+        for _ in 0..8 {
+            stepped.step();
+        }
+        assert_eq!((stepped.peek(16), stepped.peek(KBD + 1)), (2, 3));
+    }
+
+    #[test]
+    fn the_screen_and_keyboard_are_devices() {
+        let mut machine = Machine::new(&[]);
+        machine.poke(SCREEN + 1, 0xf0f0);
+        machine.set_key(131);
+        assert_eq!(machine.device::<Screen>().unwrap().words()[1], 0xf0f0);
+        assert_eq!(machine.screen()[1], 0xf0f0);
+        assert_eq!(machine.peek(KBD), 131);
+        assert_eq!(
+            machine.symbols(),
+            [("SCREEN".to_string(), SCREEN), ("KBD".to_string(), KBD)]
+        );
+
+        let err = machine.add_device(Box::new(Keyboard::new())).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "device `keyboard`: its addresses overlap those of `keyboard`"
+        );
+        let err = machine
+            .add_device(Box::new(Timer { now: 0, start: 0 }))
+            .and_then(|()| machine.add_device(Box::new(Timer { now: 0, start: 0 })))
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "device `timer`: its addresses overlap those of `timer`"
+        );
+
+        // without devices, the I/O addresses are plain RAM
+        let mut bare = Machine::with_devices(&[], Vec::new()).unwrap();
+        bare.poke(KBD + 1, 7);
+        assert_eq!(bare.peek(KBD + 1), 7);
+    }
+}
@@ -0,0 +1,112 @@
+use std::fmt;
+use std::io;
+
+/// an error that stops the emulator
+#[derive(Debug)]
+pub enum EmulatorError {
+    /// a program or input file is invalid at the given line
+    Load {
+        /// the file the error was found in
+        path: String,
+        /// 1-based line number of the offending line
+        line: usize,
+        /// human readable description
+        message: String,
+    },
+    /// a binary file, such as a snapshot, is invalid
+    Format {
+        /// the file the error was found in
+        path: String,
+        /// human readable description
+        message: String,
+    },
+    /// a device cannot be added to the machine
+    Device {
+        /// the name of the device
+        name: String,
+        /// human readable description
+        message: String,
+    },
+    /// a program stopped on a violation of checked mode
+    Trap {
+        /// the report of the violation
+        report: String,
+    },
+    /// a file could not be read or written
+    Io {
+        /// the path that was being accessed
+        path: String,
+        /// the underlying error
+        source: io::Error,
+    },
+}
+
+impl EmulatorError {
+    /// `EmulatorError::load()`: constructor for an error in an input file
+    pub fn load(path: &str, line: usize, message: String) -> Self {
+        EmulatorError::Load {
+            path: path.to_string(),
+            line,
+            message,
+        }
+    }
+
+    /// `EmulatorError::format()`: constructor for an error in a binary file
+    pub fn format(path: &str, message: String) -> Self {
+        EmulatorError::Format {
+            path: path.to_string(),
+            message,
+        }
+    }
+
+    /// `EmulatorError::device()`: constructor for an error while adding a device
+    pub fn device(name: &str, message: String) -> Self {
+        EmulatorError::Device {
+            name: name.to_string(),
+            message,
+        }
+    }
+
+    /// `EmulatorError::trap()`: constructor for a violation of checked mode
+    pub fn trap(report: String) -> Self {
+        EmulatorError::Trap { report }
+    }
+
+    /// `EmulatorError::io()`: constructor for an error while accessing `path`
+    pub fn io(path: &str, source: io::Error) -> Self {
+        EmulatorError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EmulatorError::Load {
+                path,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", path, line, message),
+            EmulatorError::Format { path, message } => write!(f, "{}: {}", path, message),
+            EmulatorError::Device { name, message } => {
+                write!(f, "device `{}`: {}", name, message)
+            }
+            EmulatorError::Trap { report } => write!(f, "{}", report),
+            EmulatorError::Io { path, source } => write!(f, "{}: {}", path, source),
+        }
+    }
+}
+
+impl std::error::Error for EmulatorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EmulatorError::Load { .. }
+            | EmulatorError::Format { .. }
+            | EmulatorError::Device { .. }
+            | EmulatorError::Trap { .. } => None,
+            EmulatorError::Io { source, .. } => Some(source),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+use crate::error::EmulatorError;
+use crate::machine::Machine;
+use std::fs;
+use std::path::Path;
+
+/// the names of the keys of the Hack keyboard that are not printable characters, by code
+const KEY_NAMES: [(&str, u16); 27] = [
+    ("release", 0),
+    ("space", 32),
+    ("enter", 128),
+    ("backspace", 129),
+    ("left", 130),
+    ("up", 131),
+    ("right", 132),
+    ("down", 133),
+    ("home", 134),
+    ("end", 135),
+    ("pageup", 136),
+    ("pagedown", 137),
+    ("insert", 138),
+    ("delete", 139),
+    ("esc", 140),
+    ("f1", 141),
+    ("f2", 142),
+    ("f3", 143),
+    ("f4", 144),
+    ("f5", 145),
+    ("f6", 146),
+    ("f7", 147),
+    ("f8", 148),
+    ("f9", 149),
+    ("f10", 150),
+    ("f11", 151),
+    ("f12", 152),
+];
+
+/// the key code of a name, a printable character or a number, e.g. `left`, `q` or `130`
+pub fn parse_key(key: &str) -> Option<u16> {
+    if let Some(&(_, code)) = KEY_NAMES.iter().find(|(name, _)| *name == key) {
+        return Some(code);
+    }
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch @ '!'..='~'), None) if !ch.is_ascii_digit() => Some(ch as u16),
+        _ => key.parse::<u16>().ok(),
+    }
+}
+
+/// the name of a key code, as `parse_key` reads it
+pub fn key_name(code: u16) -> String {
+    if let Some((name, _)) = KEY_NAMES.iter().find(|(_, key)| *key == code) {
+        return name.to_string();
+    }
+    match code {
+        // digits and `#` would be read as a number and a comment
+        33..=126 if !(code as u8).is_ascii_digit() && code != b'#' as u16 => {
+            (code as u8 as char).to_string()
+        }
+        _ => code.to_string(),
+    }
+}
+
+/// a change of the keyboard register at a cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// the number of instructions executed when the key changes
+    pub cycle: u64,
+    /// the code of the key pressed, 0 for a release
+    pub code: u16,
+}
+
+/// # KeyScript
+/// the keys pressed and released during a run, at exact cycles, so a session can be reproduced
+///
+/// the text format has one `CYCLE KEY` event per line, where the key is a name (`left`, `enter`,
+/// `f1`, `release`, ...), a printable character or a key code; `#` starts a comment
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyScript {
+    /// the events, by cycle
+    events: Vec<KeyEvent>,
+    /// the index of the first event not applied yet
+    next: usize,
+}
+
+impl KeyScript {
+    /// `KeyScript::new()`: a script of `events`, which are sorted by cycle
+    pub fn new(mut events: Vec<KeyEvent>) -> Self {
+        events.sort_by_key(|event| event.cycle);
+        KeyScript { events, next: 0 }
+    }
+
+    /// `KeyScript::parse()`: parses the text of a script
+    /// path: the file the text came from, used in error messages
+    pub fn parse(text: &str, path: &str) -> Result<Self, EmulatorError> {
+        let mut events: Vec<KeyEvent> = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |message: String| EmulatorError::load(path, idx + 1, message);
+            let (cycle, key) = line
+                .split_once(char::is_whitespace)
+                .ok_or_else(|| error(format!("`{}` is not CYCLE KEY", line)))?;
+            let cycle = cycle
+                .parse::<u64>()
+                .map_err(|_| error(format!("invalid cycle `{}`", cycle)))?;
+            let key = key.trim();
+            let code = parse_key(key).ok_or_else(|| error(format!("unknown key `{}`", key)))?;
+            if events.last().is_some_and(|last| last.cycle > cycle) {
+                return Err(error(format!(
+                    "cycle {} is before the previous event",
+                    cycle
+                )));
+            }
+            events.push(KeyEvent { cycle, code });
+        }
+        Ok(KeyScript::new(events))
+    }
+
+    /// `KeyScript::load()`: reads a script file
+    pub fn load(path: &Path) -> Result<Self, EmulatorError> {
+        let display = path.display().to_string();
+        let text = fs::read_to_string(path).map_err(|err| EmulatorError::io(&display, err))?;
+        KeyScript::parse(&text, &display)
+    }
+
+    /// `KeyScript.save()`: writes the script to a file, in the format `parse` reads
+    pub fn save(&self, path: &Path) -> Result<(), EmulatorError> {
+        fs::write(path, self.to_text())
+            .map_err(|err| EmulatorError::io(&path.display().to_string(), err))
+    }
+
+    /// `KeyScript.to_text()`: the script in the text format
+    pub fn to_text(&self) -> String {
+        let mut text = String::from("# cycle key\n");
+        for event in &self.events {
+            text += &format!("{} {}\n", event.cycle, key_name(event.code));
+        }
+        text
+    }
+
+    /// `KeyScript.events()`: all the events of the script
+    pub fn events(&self) -> &[KeyEvent] {
+        &self.events
+    }
+
+    /// `KeyScript.applied()`: the events applied so far
+    pub fn applied(&self) -> &[KeyEvent] {
+        &self.events[..self.next]
+    }
+
+    /// `KeyScript.pending()`: the events not applied yet
+    pub fn pending(&self) -> &[KeyEvent] {
+        &self.events[self.next..]
+    }
+
+    /// `KeyScript.push()`: adds an event after the others, as a session is recorded
+    pub fn push(&mut self, event: KeyEvent) {
+        debug_assert!(self
+            .events
+            .last()
+            .is_none_or(|last| last.cycle <= event.cycle));
+        self.events.push(event);
+    }
+
+    /// `KeyScript.apply()`: sets the keyboard register for the events due at the current cycle,
+    /// for callers that step the machine themselves
+    pub fn apply(&mut self, machine: &mut Machine) {
+        while let Some(event) = self
+            .events
+            .get(self.next)
+            .filter(|event| event.cycle <= machine.cycles())
+        {
+            machine.set_key(event.code);
+            self.next += 1;
+        }
+    }
+
+    /// `KeyScript.run()`: runs `machine` for `cycles` instructions, setting the keyboard register
+    /// when the cycle count reaches each event; events of earlier cycles are applied first
+    pub fn run(&mut self, machine: &mut Machine, cycles: u64) {
+        let end = machine.cycles().saturating_add(cycles);
+        while let Some(event) = self.events.get(self.next).filter(|event| event.cycle < end) {
+            machine.run(event.cycle.saturating_sub(machine.cycles()));
+            machine.set_key(event.code);
+            self.next += 1;
+        }
+        machine.run(end - machine.cycles());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom;
+    use crate::screen;
+    use crate::{Registers, KBD};
+
+    fn path(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join(name)
+    }
+
+    #[test]
+    fn parses_and_writes_scripts() {
+        let text = "# pong\n10 right\n20 release  # stop\n\n30 q\n40 131\n50 f12\n60 7\n";
+        let script = KeyScript::parse(text, "t.keys").unwrap();
+        let codes: Vec<(u64, u16)> = script.events().iter().map(|e| (e.cycle, e.code)).collect();
+        assert_eq!(
+            codes,
+            [(10, 132), (20, 0), (30, 113), (40, 131), (50, 152), (60, 7)]
+        );
+        assert_eq!(
+            script.to_text(),
+            "# cycle key\n10 right\n20 release\n30 q\n40 up\n50 f12\n60 7\n"
+        );
+        assert_eq!(
+            KeyScript::parse(&script.to_text(), "t.keys").unwrap(),
+            script
+        );
+
+        let err = KeyScript::parse("10 right\n5 left\n", "t.keys").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "t.keys:2: cycle 5 is before the previous event"
+        );
+        let err = KeyScript::parse("10 shift\n", "t.keys").unwrap_err();
+        assert_eq!(err.to_string(), "t.keys:1: unknown key `shift`");
+        let err = KeyScript::parse("right\n", "t.keys").unwrap_err();
+        assert_eq!(err.to_string(), "t.keys:1: `right` is not CYCLE KEY");
+    }
+
+    #[test]
+    fn sets_the_keyboard_at_exact_cycles() {
+        let mut machine = Machine::new(&rom::assemble("@KBD\nD=M\n", "t.asm").unwrap().words);
+        let mut script = KeyScript::parse("3 a\n5 release\n", "t.keys").unwrap();
+        script.run(&mut machine, 4);
+        assert_eq!((machine.peek(KBD), script.pending().len()), (97, 1));
+        script.run(&mut machine, 2);
+        assert_eq!(machine.peek(KBD), 0);
+        assert_eq!(script.applied().len(), 2);
+    }
+
+    #[test]
+    fn runs_up_to_the_largest_cycle_count() {
+        let mut machine = Machine::new(&rom::assemble("@KBD\nD=M\n", "t.asm").unwrap().words);
+        machine.set_registers(Registers {
+            cycle: u64::MAX - 5,
+            ..machine.registers()
+        });
+        let mut script = KeyScript::parse("3 a\n", "t.keys").unwrap();
+        script.run(&mut machine, u64::MAX);
+        assert_eq!(machine.cycles(), u64::MAX);
+        assert_eq!(machine.peek(KBD), 97);
+    }
+
+    #[test]
+    fn replays_pong_exactly() {
+        let program = rom::load(&path("../projects/06/pong/Pong.asm")).unwrap();
+        let replay = || {
+            let mut machine = Machine::new(&program.words);
+            let mut script = KeyScript::load(&path("golden/Pong.keys")).unwrap();
+            script.run(&mut machine, 6_000_000);
+            screen::to_pbm(machine.screen())
+        };
+        let screen = replay();
+        assert_eq!(screen, replay());
+        assert_eq!(screen, fs::read(path("golden/PongKeys.pbm")).unwrap());
+        assert_ne!(screen, fs::read(path("golden/Pong.pbm")).unwrap());
+    }
+}
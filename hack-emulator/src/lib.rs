@@ -0,0 +1,18 @@
+pub use error::EmulatorError;
+pub use machine::{Machine, Registers, KBD, RAM_SIZE, ROM_SIZE, SCREEN};
+pub use rom::Program;
+
+pub mod checked;
+pub mod debugger;
+pub mod decode;
+pub mod device;
+mod error;
+pub mod keys;
+mod machine;
+pub mod profile;
+pub mod rom;
+pub mod screen;
+pub mod snapshot;
+pub mod source_map;
+pub mod trace;
+pub mod tui;
@@ -0,0 +1,460 @@
+use crate::decode::{self, CInstruction, Instruction, Op, DEST_A, DEST_D, DEST_M};
+use crate::device::{self, Bus, Device, Keyboard, Screen};
+use crate::error::EmulatorError;
+
+/// number of words of the instruction memory
+pub const ROM_SIZE: usize = 32768;
+/// number of words addressable in the data memory, including the memory-mapped I/O
+pub const RAM_SIZE: usize = 32768;
+/// base address of the screen memory map
+pub const SCREEN: u16 = 16384;
+/// address of the keyboard register
+pub const KBD: u16 = 24576;
+
+/// addresses are 15 bits wide, both for the instruction and the data memory
+const ADDRESS_MASK: u16 = 0x7fff;
+
+/// the registers of the CPU and the cycle count
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Registers {
+    /// the A register
+    pub a: u16,
+    /// the D register
+    pub d: u16,
+    /// the program counter
+    pub pc: u16,
+    /// number of instructions executed since the reset
+    pub cycle: u64,
+}
+
+/// # Machine
+/// the Hack computer: ROM, RAM, the A, D and PC registers, and the devices mapped into the RAM
+pub struct Machine {
+    /// the instruction words, padded with zeros to the full ROM
+    rom: Vec<u16>,
+    /// number of words of the loaded program
+    program_len: usize,
+    /// the pre-decoded instructions, one per ROM address
+    instructions: Box<[Instruction; ROM_SIZE]>,
+    /// the pre-decoded ops, with superinstructions, one per ROM address
+    ops: Box<[Op; ROM_SIZE]>,
+    /// the data memory, a fixed size so that indexing with a masked address needs no bounds check;
+    /// the addresses claimed by devices are unused
+    ram: Box<[u16; RAM_SIZE]>,
+    /// the devices and the I/O addresses they claim
+    bus: Bus,
+    /// the A register
+    a: u16,
+    /// the D register
+    d: u16,
+    /// the program counter
+    pc: u16,
+    /// number of instructions executed since the reset
+    cycle: u64,
+}
+
+impl Machine {
+    /// `Machine.new()`: constructor, loads `program` into ROM, clears everything else and
+    /// maps the screen and the keyboard
+    pub fn new(program: &[u16]) -> Self {
+        Machine::with_devices(program, device::hack_devices())
+            .expect("the screen and the keyboard do not overlap")
+    }
+
+    /// `Machine.with_devices()`: constructor, loads `program` into ROM, clears everything else and
+    /// maps `devices`, which need not include the screen and the keyboard
+    pub fn with_devices(
+        program: &[u16],
+        devices: Vec<Box<dyn Device>>,
+    ) -> Result<Self, EmulatorError> {
+        let mut machine = Machine::bare(program);
+        for device in devices {
+            machine.add_device(device)?;
+        }
+        Ok(machine)
+    }
+
+    /// a machine without devices
+    fn bare(program: &[u16]) -> Self {
+        let mut rom = program.to_vec();
+        rom.resize(ROM_SIZE, 0);
+        let instructions: Vec<Instruction> = rom.iter().map(|&word| decode::decode(word)).collect();
+        let ops = decode::predecode(&rom);
+        Machine {
+            rom,
+            program_len: program.len(),
+            instructions: instructions.try_into().expect("one per ROM address"),
+            ops: ops.try_into().expect("one per ROM address"),
+            ram: vec![0; RAM_SIZE].try_into().expect("RAM_SIZE words"),
+            bus: Bus::new(),
+            a: 0,
+            d: 0,
+            pc: 0,
+            cycle: 0,
+        }
+    }
+
+    /// `Machine.add_device()`: maps a device at the addresses it claims, which must be I/O addresses
+    /// no other device claims
+    pub fn add_device(&mut self, device: Box<dyn Device>) -> Result<(), EmulatorError> {
+        let name = device.name().to_string();
+        self.bus
+            .add(device)
+            .map_err(|message| EmulatorError::device(&name, message))
+    }
+
+    /// `Machine.device()`: the first device of type `T`, e.g. `machine.device::<Screen>()`
+    pub fn device<T: Device + 'static>(&self) -> Option<&T> {
+        self.bus
+            .devices()
+            .iter()
+            .find_map(|device| device.as_any().downcast_ref())
+    }
+
+    /// `Machine.device_mut()`: the first device of type `T`
+    pub fn device_mut<T: Device + 'static>(&mut self) -> Option<&mut T> {
+        self.bus
+            .devices_mut()
+            .iter_mut()
+            .find_map(|device| device.as_any_mut().downcast_mut())
+    }
+
+    /// `Machine.symbols()`: the names the devices give to their addresses, for the assembler
+    pub fn symbols(&self) -> Vec<(String, u16)> {
+        self.bus
+            .devices()
+            .iter()
+            .flat_map(|device| device.symbols())
+            .collect()
+    }
+
+    /// `Machine.reset()`: restarts the program, RAM keeps its contents like on the real hardware
+    pub fn reset(&mut self) {
+        self.a = 0;
+        self.d = 0;
+        self.pc = 0;
+        self.cycle = 0;
+    }
+
+    /// `Machine.a()`: the A register
+    pub fn a(&self) -> u16 {
+        self.a
+    }
+
+    /// `Machine.d()`: the D register
+    pub fn d(&self) -> u16 {
+        self.d
+    }
+
+    /// `Machine.pc()`: the program counter
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    /// `Machine.cycles()`: number of instructions executed since the reset
+    pub fn cycles(&self) -> u64 {
+        self.cycle
+    }
+
+    /// `Machine.registers()`: the registers and the cycle count
+    pub fn registers(&self) -> Registers {
+        Registers {
+            a: self.a,
+            d: self.d,
+            pc: self.pc,
+            cycle: self.cycle,
+        }
+    }
+
+    /// `Machine.set_registers()`: sets the registers and the cycle count, e.g. to go back in time
+    pub fn set_registers(&mut self, registers: Registers) {
+        self.a = registers.a;
+        self.d = registers.d;
+        self.pc = registers.pc & ADDRESS_MASK;
+        self.cycle = registers.cycle;
+    }
+
+    /// `Machine.instruction()`: the decoded instruction at ROM `address`
+    pub fn instruction(&self, address: u16) -> Instruction {
+        self.instructions[(address & ADDRESS_MASK) as usize]
+    }
+
+    /// `Machine.write_address()`: the RAM address the next instruction writes, if it writes RAM
+    pub fn write_address(&self) -> Option<u16> {
+        match self.instruction(self.pc) {
+            Instruction::C(c) if c.dest & DEST_M != 0 => Some(self.a & ADDRESS_MASK),
+            _ => None,
+        }
+    }
+
+    /// `Machine.halted()`: whether the program is in the infinite loop that ends Hack programs,
+    /// `@X` `0;JMP` at address X
+    pub fn halted(&self) -> bool {
+        let Some(at) = self.pc.checked_sub(1) else {
+            return false;
+        };
+        matches!(self.instruction(self.pc), Instruction::C(c) if c.jump == 0b111 && c.dest == 0)
+            && self.instruction(at) == Instruction::A(at)
+            && self.a == at
+    }
+
+    /// `Machine.rom()`: the instruction memory
+    pub fn rom(&self) -> &[u16] {
+        &self.rom
+    }
+
+    /// `Machine.program_len()`: number of words of the loaded program
+    pub fn program_len(&self) -> usize {
+        self.program_len
+    }
+
+    /// `Machine.peek()`: reads the data memory or a device without side effects
+    pub fn peek(&self, address: u16) -> u16 {
+        let address = address & ADDRESS_MASK;
+        let device = (address >= SCREEN)
+            .then(|| self.bus.peek(address))
+            .flatten();
+        device.unwrap_or(self.ram[address as usize])
+    }
+
+    /// `Machine.poke()`: writes the data memory or a device, e.g. to set up the inputs of a test
+    pub fn poke(&mut self, address: u16, value: u16) {
+        store(
+            &mut self.ram,
+            &mut self.bus,
+            address & ADDRESS_MASK,
+            value,
+            self.cycle,
+        );
+    }
+
+    /// `Machine.set_key()`: sets the keyboard to the code of the key being pressed, 0 for none
+    pub fn set_key(&mut self, code: u16) {
+        match self.device_mut::<Keyboard>() {
+            Some(keyboard) => keyboard.set_key(code),
+            None => self.ram[KBD as usize] = code,
+        }
+    }
+
+    /// `Machine.screen()`: the words of the screen memory map, from `SCREEN`
+    pub fn screen(&self) -> &[u16] {
+        match self.device::<Screen>() {
+            Some(screen) => screen.words(),
+            None => &self.ram[SCREEN as usize..KBD as usize],
+        }
+    }
+
+    /// `Machine.step()`: executes a single instruction, without superinstructions
+    pub fn step(&mut self) {
+        match self.instructions[(self.pc & ADDRESS_MASK) as usize] {
+            Instruction::A(value) => {
+                self.a = value;
+                self.pc = (self.pc + 1) & ADDRESS_MASK;
+            }
+            Instruction::C(c) => execute(
+                c,
+                &mut self.a,
+                &mut self.d,
+                &mut self.pc,
+                &mut self.ram,
+                &mut self.bus,
+                self.cycle,
+            ),
+        }
+        self.cycle += 1;
+        self.bus.tick(self.cycle);
+    }
+
+    /// `Machine.run()`: executes `cycles` instructions using the superinstructions,
+    /// the state afterwards is the same as after as many calls to `step`
+    pub fn run(&mut self, cycles: u64) {
+        let end = self.cycle.saturating_add(cycles);
+        // the registers are kept in locals so that the compiler can keep them in CPU registers
+        let (mut a, mut d, mut pc, mut cycle) = (self.a, self.d, self.pc, self.cycle);
+        let ops = &self.ops;
+        let ram = &mut self.ram;
+        let bus = &mut self.bus;
+
+        // a superinstruction takes two cycles, so the last one may have to be single-stepped
+        while end - cycle >= 2 {
+            match ops[(pc & ADDRESS_MASK) as usize] {
+                Op::A(value) => {
+                    a = value;
+                    pc = (pc + 1) & ADDRESS_MASK;
+                    cycle += 1;
+                }
+                Op::C(c) => {
+                    execute(c, &mut a, &mut d, &mut pc, ram, bus, cycle);
+                    cycle += 1;
+                }
+                Op::LoadM(address) => {
+                    a = address;
+                    d = load(ram, bus, address & ADDRESS_MASK, cycle + 1);
+                    pc = (pc + 2) & ADDRESS_MASK;
+                    cycle += 2;
+                }
+                Op::LoadA(value) => {
+                    a = value;
+                    d = value;
+                    pc = (pc + 2) & ADDRESS_MASK;
+                    cycle += 2;
+                }
+                Op::Goto(address) => {
+                    a = address;
+                    pc = address & ADDRESS_MASK;
+                    cycle += 2;
+                }
+                Op::BranchD(address, jump) => {
+                    a = address;
+                    pc = if decode::jumps(jump, d) {
+                        address & ADDRESS_MASK
+                    } else {
+                        (pc + 2) & ADDRESS_MASK
+                    };
+                    cycle += 2;
+                }
+                Op::AThenC(value, c) => {
+                    a = value;
+                    pc = (pc + 1) & ADDRESS_MASK;
+                    execute(c, &mut a, &mut d, &mut pc, ram, bus, cycle + 1);
+                    cycle += 2;
+                }
+            }
+        }
+
+        (self.a, self.d, self.pc, self.cycle) = (a, d, pc, cycle);
+        while self.cycle < end {
+            self.step();
+        }
+        self.bus.tick(self.cycle);
+    }
+}
+
+/// reads the data memory, or the device at an I/O address
+/// cycle: the number of instructions executed before the one reading
+#[inline(always)]
+fn load(ram: &[u16; RAM_SIZE], bus: &mut Bus, address: u16, cycle: u64) -> u16 {
+    if address < SCREEN {
+        ram[address as usize]
+    } else {
+        load_io(ram, bus, address, cycle)
+    }
+}
+
+/// reads an I/O address, out of line as programs seldom access devices
+#[cold]
+#[inline(never)]
+fn load_io(ram: &[u16; RAM_SIZE], bus: &mut Bus, address: u16, cycle: u64) -> u16 {
+    bus.read(address, cycle).unwrap_or(ram[address as usize])
+}
+
+/// writes the data memory, or the device at an I/O address
+/// cycle: the number of instructions executed before the one writing
+#[inline(always)]
+fn store(ram: &mut [u16; RAM_SIZE], bus: &mut Bus, address: u16, value: u16, cycle: u64) {
+    if address < SCREEN {
+        ram[address as usize] = value;
+    } else {
+        store_io(ram, bus, address, value, cycle);
+    }
+}
+
+/// writes an I/O address, out of line as programs seldom access devices
+#[cold]
+#[inline(never)]
+fn store_io(ram: &mut [u16; RAM_SIZE], bus: &mut Bus, address: u16, value: u16, cycle: u64) {
+    if !bus.write(address, value, cycle) {
+        ram[address as usize] = value;
+    }
+}
+
+/// executes a C-instruction at `pc`
+/// memory is read and written at the old value of A, and jumps go to it,
+/// as A is only updated at the end of the cycle
+/// cycle: the number of instructions executed before this one, for the devices
+#[inline(always)]
+fn execute(
+    c: CInstruction,
+    a: &mut u16,
+    d: &mut u16,
+    pc: &mut u16,
+    ram: &mut [u16; RAM_SIZE],
+    bus: &mut Bus,
+    cycle: u64,
+) {
+    let address = *a & ADDRESS_MASK;
+    let y = if c.reads_m {
+        load(ram, bus, address, cycle)
+    } else {
+        *a
+    };
+    let out = c.comp.eval(*d, y);
+    let target = *a;
+    if c.dest & DEST_M != 0 {
+        store(ram, bus, address, out, cycle);
+    }
+    if c.dest & DEST_A != 0 {
+        *a = out;
+    }
+    if c.dest & DEST_D != 0 {
+        *d = out;
+    }
+    *pc = if c.jumps(out) {
+        target & ADDRESS_MASK
+    } else {
+        (*pc + 1) & ADDRESS_MASK
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom;
+    use std::path::Path;
+
+    fn load(path: &str) -> Machine {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../projects")
+            .join(path);
+        Machine::new(&rom::load(&path).unwrap().words)
+    }
+
+    #[test]
+    fn runs_max() {
+        let mut machine = load("06/max/Max.hack");
+        machine.poke(0, 3);
+        machine.poke(1, 0xfffe);
+        machine.run(100);
+        assert_eq!(machine.peek(2), 3);
+    }
+
+    #[test]
+    fn runs_up_to_the_largest_cycle_count() {
+        let mut machine = load("06/max/Max.hack");
+        machine.cycle = u64::MAX - 5;
+        machine.run(u64::MAX);
+        assert_eq!(machine.cycle, u64::MAX);
+    }
+
+    #[test]
+    fn superinstructions_match_single_steps_on_pong() {
+        let mut fused = load("06/pong/Pong.hack");
+        let mut single = load("06/pong/Pong.hack");
+        // odd chunks so that superinstructions get split at the end of a run,
+        // for long enough that the game has drawn its first frame
+        for _ in 0..100 {
+            fused.run(60_001);
+            for _ in 0..60_001 {
+                single.step();
+            }
+            assert_eq!(
+                (fused.a, fused.d, fused.pc, fused.cycle),
+                (single.a, single.d, single.pc, single.cycle)
+            );
+        }
+        assert!(fused.ram == single.ram);
+        assert_eq!(fused.screen(), single.screen());
+        // the game has drawn something by now
+        assert!(fused.screen().iter().any(|&word| word != 0));
+    }
+}
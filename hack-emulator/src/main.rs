@@ -0,0 +1,507 @@
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
+use hack_emulator::checked::Checker;
+use hack_emulator::debugger::Debugger;
+use hack_emulator::keys::KeyScript;
+use hack_emulator::profile::Profile;
+use hack_emulator::snapshot::Snapshot;
+use hack_emulator::source_map::SourceMap;
+use hack_emulator::trace::{self, TraceFilter, TraceFormat, TraceWriter};
+use hack_emulator::tui::{self, Render, TuiOptions};
+use hack_emulator::{rom, screen, EmulatorError, Machine, Program};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+fn main() {
+    let cmd_matches = Command::new("HackEmulator")
+        .author("dcbuild3r, dcbuilder@proton.me")
+        .version("0.1.0")
+        .about("An emulator for the Hack computer used in the Nand2Tetris course.")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("run")
+                .about("Run a program for a number of cycles and print the registers.")
+                .arg(input_arg())
+                .arg(restore_arg())
+                .arg(set_arg())
+                .arg(
+                    Arg::new("cycles")
+                        .short('c')
+                        .long("cycles")
+                        .value_name("N")
+                        .help("The number of instructions to execute.")
+                        .value_parser(value_parser!(u64))
+                        .default_value("1000000"),
+                )
+                .arg(
+                    Arg::new("print")
+                        .short('p')
+                        .long("print")
+                        .value_name("ADDRESS")
+                        .help("Print RAM[ADDRESS] after running.")
+                        .value_parser(value_parser!(u16))
+                        .action(ArgAction::Append),
+                )
+                .arg(
+                    Arg::new("screenshot")
+                        .short('s')
+                        .long("screenshot")
+                        .value_name("FILE")
+                        .help("Save the screen after running to a .png or .pbm file."),
+                )
+                .arg(
+                    Arg::new("save-snapshot")
+                        .long("save-snapshot")
+                        .value_name("FILE")
+                        .help("Save the state after running to FILE, for --restore to resume it."),
+                )
+                .arg(keys_arg())
+                .arg(
+                    Arg::new("checked")
+                        .long("checked")
+                        .help("Stop on reads of RAM never written, writes to the screen outside of --graphics code or to the keyboard, SP leaving 256-2047 and runs past the program.")
+                        .action(ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("graphics")
+                        .long("graphics")
+                        .value_name("RANGE")
+                        .help("Allow the code at START-END, at an address, in the region of a label, or of the labels or functions starting with PREFIX*, to write the screen in checked mode.")
+                        .requires("checked")
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("tui")
+                .about("Run a program in the terminal, showing its screen and passing it the keys pressed.")
+                .arg(input_arg())
+                .arg(restore_arg())
+                .arg(set_arg())
+                .arg(
+                    Arg::new("render")
+                        .long("render")
+                        .value_name("MODE")
+                        .help("How to draw the screen.")
+                        .value_parser(["braille", "half-block"])
+                        .default_value("braille"),
+                )
+                .arg(
+                    Arg::new("scale")
+                        .long("scale")
+                        .value_name("N")
+                        .help("Draw every NxN square of pixels as one dot [default: 2 for braille, 4 for half-block].")
+                        .value_parser(value_parser!(usize)),
+                )
+                .arg(
+                    Arg::new("speed")
+                        .long("speed")
+                        .value_name("N")
+                        .help("The number of instructions to execute per second.")
+                        .value_parser(value_parser!(u64))
+                        .default_value("2000000"),
+                )
+                .arg(
+                    Arg::new("hold")
+                        .long("hold")
+                        .value_name("MS")
+                        .help("How long a key stays pressed, in milliseconds.")
+                        .value_parser(value_parser!(u64))
+                        .default_value("500"),
+                )
+                .arg(keys_arg())
+                .arg(
+                    Arg::new("record")
+                        .long("record")
+                        .value_name("FILE")
+                        .help("Write the keys of the session to FILE on quitting, for `--keys` to replay it."),
+                ),
+        )
+        .subcommand(
+            Command::new("debug")
+                .about("Debug a program interactively, type `help` for the commands.")
+                .arg(input_arg())
+                .arg(restore_arg())
+                .arg(set_arg())
+                .arg(
+                    Arg::new("source-map")
+                        .long("source-map")
+                        .value_name("FILE")
+                        .help("The source map written by `vm-translator --source-map`, to show the .asm line and VM command of every instruction."),
+                )
+                .arg(
+                    Arg::new("history")
+                        .long("history")
+                        .value_name("N")
+                        .help("The number of instructions `back` can undo.")
+                        .value_parser(value_parser!(usize))
+                        .default_value("100000"),
+                ),
+        )
+        .subcommand(
+            Command::new("profile")
+                .about("Run a program and report where its cycles were spent.")
+                .arg(input_arg())
+                .arg(restore_arg())
+                .arg(set_arg())
+                .arg(
+                    Arg::new("cycles")
+                        .short('c')
+                        .long("cycles")
+                        .value_name("N")
+                        .help("The maximum number of instructions to execute, the run also stops when the program halts.")
+                        .value_parser(value_parser!(u64))
+                        .default_value("1000000"),
+                )
+                .arg(
+                    Arg::new("top")
+                        .long("top")
+                        .value_name("N")
+                        .help("The number of regions and loops to report.")
+                        .value_parser(value_parser!(usize))
+                        .default_value("10"),
+                )
+                .arg(
+                    Arg::new("lcov")
+                        .long("lcov")
+                        .value_name("FILE")
+                        .help("Write the line coverage of the .asm program to FILE in lcov format."),
+                ),
+        )
+        .subcommand(
+            Command::new("trace")
+                .about("Run a program and write every instruction executed, with the registers and the RAM written.")
+                .arg(input_arg())
+                .arg(restore_arg())
+                .arg(set_arg())
+                .arg(keys_arg())
+                .arg(
+                    Arg::new("cycles")
+                        .short('c')
+                        .long("cycles")
+                        .value_name("N")
+                        .help("The maximum number of instructions to execute, the run also stops when the program halts.")
+                        .value_parser(value_parser!(u64))
+                        .default_value("1000000"),
+                )
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("The trace file to write.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("format")
+                        .long("format")
+                        .value_name("FORMAT")
+                        .help("The format of the trace [default: jsonl for .jsonl files, binary otherwise].")
+                        .value_parser(["binary", "jsonl"]),
+                )
+                .arg(
+                    Arg::new("pc")
+                        .long("pc")
+                        .value_name("RANGE")
+                        .help("Only trace the instructions at START-END, at an address, or in the region of a label.")
+                        .action(ArgAction::Append),
+                ),
+        )
+        .subcommand(
+            Command::new("who-wrote")
+                .about("Find the instruction of a trace that last wrote a RAM address.")
+                .arg(
+                    Arg::new("trace")
+                        .value_name("TRACE")
+                        .help("The trace file written by `trace`.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("address")
+                        .value_name("ADDRESS")
+                        .help("The RAM address, or a variable of the program.")
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("input")
+                        .short('i')
+                        .long("input")
+                        .value_name("FILE")
+                        .help("The traced .asm program, to name its labels and variables."),
+                ),
+        )
+        .get_matches();
+
+    // report the first error and exit with a non-zero status
+    let result = match cmd_matches.subcommand() {
+        Some(("run", matches)) => run(matches),
+        Some(("tui", matches)) => tui(matches),
+        Some(("debug", matches)) => debug(matches),
+        Some(("profile", matches)) => profile(matches),
+        Some(("trace", matches)) => trace(matches),
+        Some(("who-wrote", matches)) => who_wrote(matches),
+        _ => unreachable!("a subcommand is required"),
+    };
+    if let Err(err) = result {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// the program to run, for every subcommand
+fn input_arg() -> Arg {
+    Arg::new("input")
+        .short('i')
+        .long("input")
+        .value_name("FILE")
+        .help(
+            "The .hack or .asm program to run, labels and .asm lines are known for .asm programs.",
+        )
+        .required_unless_present("restore")
+}
+
+/// the snapshot to resume, for every subcommand
+fn restore_arg() -> Arg {
+    Arg::new("restore")
+        .long("restore")
+        .value_name("FILE")
+        .help("Resume the run saved in the snapshot FILE, --input then only names its labels.")
+}
+
+/// the RAM words to set before running, for every subcommand
+fn set_arg() -> Arg {
+    Arg::new("set")
+        .long("set")
+        .value_name("ADDRESS=VALUE")
+        .help("Set RAM[ADDRESS] to VALUE before running, e.g. --set 0=256.")
+        .action(ArgAction::Append)
+}
+
+/// the key script to replay, for the subcommands that run without a debugger
+fn keys_arg() -> Arg {
+    Arg::new("keys").long("keys").value_name("FILE").help(
+        "Replay the keys of FILE, one `CYCLE KEY` line per press or release, e.g. `5200000 right`.",
+    )
+}
+
+/// loads the program of `--input`, or the state of `--restore`, into a machine and applies `--set`
+/// returns the keys still to be pressed in the snapshot
+fn load(matches: &ArgMatches) -> Result<(Program, Machine, KeyScript), EmulatorError> {
+    let input = matches.get_one::<String>("input");
+    let program = match input {
+        Some(input) => rom::load(Path::new(input))?,
+        None => Program::default(),
+    };
+    let (mut machine, keys) = match matches.get_one::<String>("restore") {
+        Some(path) => {
+            let snapshot = Snapshot::load(Path::new(path))?;
+            if let Some(input) = input.filter(|_| snapshot.rom != program.words) {
+                let message = format!("the snapshot is not a run of {}", input);
+                return Err(EmulatorError::format(path, message));
+            }
+            snapshot.restore()
+        }
+        None => (Machine::new(&program.words), KeyScript::default()),
+    };
+
+    for assignment in matches.get_many::<String>("set").unwrap_or_default() {
+        let (address, value) = parse_assignment(assignment).ok_or_else(|| {
+            EmulatorError::load("--set", 1, format!("`{}` is not ADDRESS=VALUE", assignment))
+        })?;
+        machine.poke(address, value);
+    }
+    Ok((program, machine, keys))
+}
+
+/// the script of `--keys`, or the keys pending in the snapshot without it
+fn key_script(matches: &ArgMatches, pending: KeyScript) -> Result<KeyScript, EmulatorError> {
+    match matches.get_one::<String>("keys") {
+        Some(path) => KeyScript::load(Path::new(path)),
+        None => Ok(pending),
+    }
+}
+
+/// the `run` subcommand
+fn run(matches: &ArgMatches) -> Result<(), EmulatorError> {
+    let (program, mut machine, pending) = load(matches)?;
+
+    let cycles = *matches.get_one::<u64>("cycles").expect("default");
+    let mut script = key_script(matches, pending)?;
+    let start = Instant::now();
+    if matches.get_flag("checked") {
+        let mut checker = checker(matches, &program)?;
+        checker
+            .run(&mut machine, &mut script, cycles)
+            .map_err(|trap| EmulatorError::trap(trap.report(&program)))?;
+    } else {
+        script.run(&mut machine, cycles);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    println!(
+        "A={} D={} PC={} cycles={} ({:.1} M instructions/s)",
+        machine.a(),
+        machine.d(),
+        machine.pc(),
+        machine.cycles(),
+        cycles as f64 / elapsed.max(1e-9) / 1e6
+    );
+    for &address in matches.get_many::<u16>("print").unwrap_or_default() {
+        println!("RAM[{}] = {}", address, machine.peek(address) as i16);
+    }
+    if let Some(path) = matches.get_one::<String>("screenshot") {
+        screen::save(machine.screen(), Path::new(path))?;
+    }
+    if let Some(path) = matches.get_one::<String>("save-snapshot") {
+        Snapshot::capture(&machine, &script).save(Path::new(path))?;
+    }
+    Ok(())
+}
+
+/// the checker of `run --checked`, with the `--graphics` code and the RAM set up by `--set`
+/// or restored counted as written
+fn checker(matches: &ArgMatches, program: &Program) -> Result<Checker, EmulatorError> {
+    let mut graphics = TraceFilter::default();
+    for spec in matches.get_many::<String>("graphics").unwrap_or_default() {
+        graphics.add("--graphics", spec, program)?;
+    }
+    let mut checker = Checker::new(graphics);
+    if matches.contains_id("restore") {
+        checker.mark_all_written();
+    }
+    for assignment in matches.get_many::<String>("set").unwrap_or_default() {
+        if let Some((address, _)) = parse_assignment(assignment) {
+            checker.mark_written(address);
+        }
+    }
+    Ok(checker)
+}
+
+/// the `tui` subcommand
+fn tui(matches: &ArgMatches) -> Result<(), EmulatorError> {
+    let (program, mut machine, pending) = load(matches)?;
+
+    let render = match matches.get_one::<String>("render").map(String::as_str) {
+        Some("half-block") => Render::HalfBlock,
+        _ => Render::Braille,
+    };
+    let options = TuiOptions {
+        render,
+        scale: matches
+            .get_one::<usize>("scale")
+            .copied()
+            .unwrap_or(render.default_scale()),
+        speed: *matches.get_one::<u64>("speed").expect("default"),
+        hold: *matches.get_one::<u64>("hold").expect("default"),
+    };
+    let session = tui::run(
+        &mut machine,
+        &program,
+        &options,
+        key_script(matches, pending)?,
+    )?;
+    if let Some(path) = matches.get_one::<String>("record") {
+        session.save(Path::new(path))?;
+    }
+    Ok(())
+}
+
+/// the `debug` subcommand
+fn debug(matches: &ArgMatches) -> Result<(), EmulatorError> {
+    let (program, machine, _) = load(matches)?;
+    let source_map = match matches.get_one::<String>("source-map") {
+        Some(path) => Some(SourceMap::load(Path::new(path))?),
+        None => None,
+    };
+    let history = *matches.get_one::<usize>("history").expect("default");
+
+    let mut debugger = Debugger::new(machine, program, source_map, history);
+    debugger
+        .repl(io::stdin().lock(), io::stdout())
+        .map_err(|err| EmulatorError::io("stdin", err))
+}
+
+/// the `profile` subcommand
+fn profile(matches: &ArgMatches) -> Result<(), EmulatorError> {
+    let (program, mut machine, _) = load(matches)?;
+    let cycles = *matches.get_one::<u64>("cycles").expect("default");
+    let top = *matches.get_one::<usize>("top").expect("default");
+
+    let profile = Profile::run(&mut machine, cycles);
+    println!("{}", profile.report(&program, top));
+    if let Some(path) = matches.get_one::<String>("lcov") {
+        let input = matches
+            .get_one::<String>("input")
+            .map_or("--restore", String::as_str);
+        let lcov = profile.lcov(&program, input)?;
+        fs::write(path, lcov).map_err(|err| EmulatorError::io(path, err))?;
+    }
+    Ok(())
+}
+
+/// the `trace` subcommand
+fn trace(matches: &ArgMatches) -> Result<(), EmulatorError> {
+    let (program, mut machine, pending) = load(matches)?;
+    let cycles = *matches.get_one::<u64>("cycles").expect("default");
+    let mut keys = key_script(matches, pending)?;
+    let mut filter = TraceFilter::default();
+    for spec in matches.get_many::<String>("pc").unwrap_or_default() {
+        filter.add("--pc", spec, &program)?;
+    }
+
+    let output = matches.get_one::<String>("output").expect("required");
+    let format = match matches.get_one::<String>("format").map(String::as_str) {
+        Some("binary") => TraceFormat::Binary,
+        Some(_) => TraceFormat::Jsonl,
+        None => TraceFormat::from_path(Path::new(output)),
+    };
+    let error = |err| EmulatorError::io(output, err);
+    let file = fs::File::create(output).map_err(error)?;
+    let mut writer = TraceWriter::new(io::BufWriter::new(file), format).map_err(error)?;
+    let written =
+        trace::trace(&mut machine, &mut keys, cycles, &filter, &mut writer).map_err(error)?;
+    writer.finish().map_err(error)?;
+    println!(
+        "traced {} of {} instructions to {}",
+        written,
+        machine.cycles(),
+        output
+    );
+    Ok(())
+}
+
+/// the `who-wrote` subcommand
+fn who_wrote(matches: &ArgMatches) -> Result<(), EmulatorError> {
+    let program = match matches.get_one::<String>("input") {
+        Some(input) => rom::load(Path::new(input))?,
+        None => Program::default(),
+    };
+    let name = matches.get_one::<String>("address").expect("required");
+    let address = name
+        .parse::<u16>()
+        .ok()
+        .or_else(|| program.variable(name))
+        .ok_or_else(|| {
+            let message = format!("`{}` is not an address or a variable", name);
+            EmulatorError::load("ADDRESS", 1, message)
+        })?;
+
+    let records = trace::read(Path::new(
+        matches.get_one::<String>("trace").expect("required"),
+    ))?;
+    match trace::last_write(&records, address) {
+        Some(record) => println!("{}", trace::describe(record, &program)),
+        None => println!("RAM[{}] was not written in the trace", address),
+    }
+    Ok(())
+}
+
+/// parses `ADDRESS=VALUE`, where the value may be negative
+fn parse_assignment(assignment: &str) -> Option<(u16, u16)> {
+    let (address, value) = assignment.split_once('=')?;
+    let address = address.trim().parse::<u16>().ok()?;
+    let value = value.trim().parse::<i32>().ok()?;
+    (-32768..=65535)
+        .contains(&value)
+        .then_some((address, value as u16))
+}
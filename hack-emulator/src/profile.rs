@@ -0,0 +1,329 @@
+use crate::error::EmulatorError;
+use crate::machine::{Machine, ROM_SIZE};
+use crate::rom::Program;
+use std::collections::HashMap;
+use std::fmt::Write;
+
+/// the instructions of a label region and the cycles spent in them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Region {
+    /// the labels at the start of the region, `(start)` for the code before the first label
+    pub name: String,
+    /// the first ROM address of the region
+    pub start: u16,
+    /// the ROM address after the region
+    pub end: u16,
+    /// number of instructions executed in the region
+    pub cycles: u64,
+}
+
+/// a backward jump to a constant address that no other jump goes to, the end of a loop;
+/// returns, computed jumps and calls to shared code are not loops
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    /// the ROM address of the loop head, the target of the jump
+    pub head: u16,
+    /// the ROM address of the jump
+    pub jump: u16,
+    /// number of times the jump was taken
+    pub iterations: u64,
+    /// number of instructions executed between the head and the jump, calls excluded
+    pub cycles: u64,
+}
+
+/// # Profile
+/// the number of times every ROM address was executed during a run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Profile {
+    /// executions per ROM address
+    counts: Vec<u64>,
+    /// times every jump was taken, by jump and target address
+    jumps: HashMap<(u16, u16), u64>,
+    /// number of instructions executed
+    cycles: u64,
+    /// whether the run ended in the final infinite loop of the program
+    halted: bool,
+}
+
+impl Profile {
+    /// `Profile::run()`: runs `machine` for up to `cycles` instructions, or until the program halts,
+    /// counting the executions of every ROM address
+    pub fn run(machine: &mut Machine, cycles: u64) -> Self {
+        let mut profile = Profile {
+            counts: vec![0; ROM_SIZE],
+            jumps: HashMap::new(),
+            cycles: 0,
+            halted: false,
+        };
+        for _ in 0..cycles {
+            let pc = machine.pc();
+            profile.counts[pc as usize] += 1;
+            profile.cycles += 1;
+            machine.step();
+            if machine.halted() {
+                // the jump of the final loop counts as executed once
+                profile.counts[machine.pc() as usize] += 1;
+                profile.halted = true;
+                break;
+            }
+            if machine.pc() != pc.wrapping_add(1) {
+                *profile.jumps.entry((pc, machine.pc())).or_default() += 1;
+            }
+        }
+        profile
+    }
+
+    /// `Profile.count()`: the number of times the instruction at `address` was executed
+    pub fn count(&self, address: u16) -> u64 {
+        self.counts[address as usize]
+    }
+
+    /// `Profile.cycles()`: the number of instructions executed
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// `Profile.regions()`: the cycles spent in every label region of the program,
+    /// the hottest first
+    pub fn regions(&self, program: &Program) -> Vec<Region> {
+        let mut starts: Vec<(u16, String)> = Vec::new();
+        for (label, address) in &program.labels {
+            match starts.last_mut() {
+                Some((start, name)) if start == address => *name += &format!(", {}", label),
+                _ => starts.push((*address, label.clone())),
+            }
+        }
+        if starts.first().is_none_or(|(start, _)| *start > 0) {
+            starts.insert(0, (0, "(start)".to_string()));
+        }
+
+        let len = program.words.len() as u16;
+        let mut regions: Vec<Region> = starts
+            .iter()
+            .enumerate()
+            .map(|(idx, (start, name))| {
+                let end = starts.get(idx + 1).map_or(len, |(end, _)| *end).max(*start);
+                Region {
+                    name: name.clone(),
+                    start: *start,
+                    end,
+                    cycles: self.counts[*start as usize..end as usize].iter().sum(),
+                }
+            })
+            .filter(|region| region.start < region.end)
+            .collect();
+        regions.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.start.cmp(&b.start)));
+        regions
+    }
+
+    /// `Profile.loops()`: the loops found by the backward jumps taken, the hottest first
+    /// program: the words of the program, to tell jumps to constant addresses
+    pub fn loops(&self, program: &Program) -> Vec<Loop> {
+        let is_loop = |jump: u16, head: u16| {
+            let constant = jump
+                .checked_sub(1)
+                .and_then(|address| program.words.get(address as usize))
+                .is_some_and(|&word| word == head);
+            let shared = self
+                .jumps
+                .keys()
+                .any(|&(from, to)| to == head && from != jump);
+            head <= jump && constant && !shared
+        };
+        let mut loops: Vec<Loop> = self
+            .jumps
+            .iter()
+            .filter(|&(&(jump, head), _)| is_loop(jump, head))
+            .map(|(&(jump, head), &iterations)| Loop {
+                head,
+                jump,
+                iterations,
+                cycles: self.counts[head as usize..=jump as usize].iter().sum(),
+            })
+            .collect();
+        loops.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.head.cmp(&b.head)));
+        loops
+    }
+
+    /// `Profile.report()`: the total cycles, the `top` hottest regions and loops, and the coverage
+    pub fn report(&self, program: &Program, top: usize) -> String {
+        let mut text = String::new();
+        let total = self.cycles.max(1) as f64;
+        let ending = if self.halted {
+            "the program halted"
+        } else {
+            "the program was stopped"
+        };
+        writeln!(text, "total cycles: {} ({})", self.cycles, ending).unwrap();
+
+        writeln!(
+            text,
+            "\nhottest regions:\n{:>12} {:>6}  region",
+            "cycles", "%"
+        )
+        .unwrap();
+        for region in self.regions(program).iter().take(top) {
+            writeln!(
+                text,
+                "{:>12} {:>5.1}%  {} ({}-{})",
+                region.cycles,
+                region.cycles as f64 * 100.0 / total,
+                region.name,
+                region.start,
+                region.end - 1
+            )
+            .unwrap();
+        }
+
+        writeln!(
+            text,
+            "\nhottest loops:\n{:>12} {:>6} {:>12}  loop",
+            "cycles", "%", "iterations"
+        )
+        .unwrap();
+        for found in self.loops(program).iter().take(top) {
+            writeln!(
+                text,
+                "{:>12} {:>5.1}% {:>12}  {} -> {}",
+                found.cycles,
+                found.cycles as f64 * 100.0 / total,
+                found.iterations,
+                program.describe(found.jump),
+                program.describe(found.head)
+            )
+            .unwrap();
+        }
+
+        let len = program.words.len();
+        let executed = self.counts[..len]
+            .iter()
+            .filter(|&&count| count > 0)
+            .count();
+        write!(
+            text,
+            "\ncoverage: {} of {} instructions executed",
+            executed, len
+        )
+        .unwrap();
+        text
+    }
+
+    /// `Profile.lcov()`: the line coverage of the .asm source of `program` in lcov format
+    /// source: the path of the .asm file, as named in the report
+    pub fn lcov(&self, program: &Program, source: &str) -> Result<String, EmulatorError> {
+        if program.lines.is_empty() {
+            return Err(EmulatorError::load(
+                source,
+                1,
+                "line coverage needs a program assembled from .asm".to_string(),
+            ));
+        }
+        let mut text = format!("TN:\nSF:{}\n", source);
+        let mut hit = 0;
+        for (address, line) in program.lines.iter().enumerate() {
+            let count = self.counts[address];
+            if count > 0 {
+                hit += 1;
+            }
+            writeln!(text, "DA:{},{}", line, count).unwrap();
+        }
+        writeln!(
+            text,
+            "LF:{}\nLH:{}\nend_of_record",
+            program.lines.len(),
+            hit
+        )
+        .unwrap();
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom;
+
+    /// counts down from 3 in a loop, then halts
+    const PROGRAM: &str = "\
+@3
+D=A
+@n
+M=D
+(LOOP)
+@n
+MD=M-1
+@LOOP
+D;JGT
+@END
+0;JMP
+(SKIPPED)
+@n
+(END)
+@END
+0;JMP
+";
+
+    fn profile() -> (Program, Profile) {
+        let program = rom::assemble(PROGRAM, "Loop.asm").unwrap();
+        let mut machine = Machine::new(&program.words);
+        let profile = Profile::run(&mut machine, 1000);
+        (program, profile)
+    }
+
+    #[test]
+    fn counts_executions_until_the_program_halts() {
+        let (_, profile) = profile();
+        assert_eq!(profile.count(0), 1);
+        assert_eq!(profile.count(4), 3);
+        assert_eq!(profile.count(8), 1);
+        assert_eq!(profile.count(10), 0);
+        assert_eq!(profile.count(12), 1);
+        assert_eq!(profile.cycles(), 4 + 3 * 4 + 2 + 1);
+    }
+
+    #[test]
+    fn aggregates_label_regions_and_loops() {
+        let (program, profile) = profile();
+        let regions = profile.regions(&program);
+        let cycles: Vec<(&str, u64)> = regions
+            .iter()
+            .map(|region| (region.name.as_str(), region.cycles))
+            .collect();
+        assert_eq!(
+            cycles,
+            [("LOOP", 14), ("(start)", 4), ("END", 2), ("SKIPPED", 0)]
+        );
+        assert_eq!(
+            profile.loops(&program),
+            [Loop {
+                head: 4,
+                jump: 7,
+                iterations: 2,
+                cycles: 12
+            }]
+        );
+        let report = profile.report(&program, 2);
+        assert!(report.starts_with("total cycles: 19 (the program halted)\n"));
+        assert!(report.contains("  LOOP (4-9)\n"), "{}", report);
+        assert!(!report.contains("SKIPPED"), "{}", report);
+        assert!(
+            report.contains("           2  LOOP+3 -> LOOP\n"),
+            "{}",
+            report
+        );
+        assert!(report.ends_with("coverage: 12 of 13 instructions executed"));
+    }
+
+    #[test]
+    fn writes_lcov_for_the_asm_lines() {
+        let (program, profile) = profile();
+        let lcov = profile.lcov(&program, "Loop.asm").unwrap();
+        assert!(lcov.starts_with("TN:\nSF:Loop.asm\nDA:1,1\nDA:2,1\n"));
+        assert!(lcov.contains("\nDA:6,3\n"));
+        assert!(lcov.contains("\nDA:13,0\n"));
+        assert!(lcov.ends_with("DA:16,1\nLF:13\nLH:12\nend_of_record\n"));
+
+        let hack = rom::parse_hack("0000000000000000\n", "t.hack").unwrap();
+        assert!(profile.lcov(&hack, "t.hack").is_err());
+    }
+}
@@ -0,0 +1,226 @@
+use crate::error::EmulatorError;
+use crate::machine::ROM_SIZE;
+use hack_assembler::{Assembler, PREDEFINED_SYMBOLS};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+/// a program ready to be loaded into ROM
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Program {
+    /// the instruction words, from ROM address 0
+    pub words: Vec<u16>,
+    /// the labels of the assembler's symbol table and their ROM addresses, in order of address,
+    /// empty for programs loaded from .hack files
+    pub labels: Vec<(String, u16)>,
+    /// the variables of the assembler's symbol table and the extra predefined symbols,
+    /// and their RAM addresses, empty for programs loaded from .hack files
+    pub variables: Vec<(String, u16)>,
+    /// the 1-based .asm line of the instruction at every ROM address,
+    /// empty for programs loaded from .hack files
+    pub lines: Vec<usize>,
+    /// the lines of the .asm source, empty for programs loaded from .hack files
+    pub asm: Vec<String>,
+}
+
+impl Program {
+    /// `Program.label_at()`: the last label at or before `address` and the offset from it,
+    /// e.g. `("LOOP", 3)` for the fourth instruction after `(LOOP)`
+    pub fn label_at(&self, address: u16) -> Option<(&str, u16)> {
+        let idx = self
+            .labels
+            .partition_point(|(_, label_address)| *label_address <= address);
+        let (label, label_address) = self.labels.get(idx.checked_sub(1)?)?;
+        Some((label, address - label_address))
+    }
+
+    /// `Program.label()`: the ROM address of a label
+    pub fn label(&self, name: &str) -> Option<u16> {
+        self.labels
+            .iter()
+            .find(|(label, _)| label == name)
+            .map(|(_, address)| *address)
+    }
+
+    /// `Program.region()`: the ROM addresses from label `name` to the next label at a higher
+    /// address, or to the end of the program
+    pub fn region(&self, name: &str) -> Option<Range<u16>> {
+        let start = self.label(name)?;
+        let end = self
+            .labels
+            .iter()
+            .map(|(_, address)| *address)
+            .find(|&address| address > start)
+            .unwrap_or(self.words.len() as u16);
+        Some(start..end.max(start))
+    }
+
+    /// `Program.function()`: the ROM addresses of the VM function `name`, from its label to the
+    /// label of the next function, so that the labels generated inside the function do not end it;
+    /// functions are the labels with a dot and no `$`, e.g. `Screen.drawPixel`
+    pub fn function(&self, name: &str) -> Option<Range<u16>> {
+        let is_function = |label: &str| label.contains('.') && !label.contains('$');
+        if !is_function(name) {
+            return None;
+        }
+        let start = self.label(name)?;
+        let end = self
+            .labels
+            .iter()
+            .find(|(label, address)| *address > start && is_function(label))
+            .map(|(_, address)| *address)
+            .unwrap_or(self.words.len() as u16);
+        Some(start..end)
+    }
+
+    /// `Program.variable()`: the RAM address of a variable or of a predefined symbol, e.g. `SP`
+    pub fn variable(&self, name: &str) -> Option<u16> {
+        let predefined = PREDEFINED_SYMBOLS
+            .iter()
+            .map(|&(symbol, address)| (symbol, address as u16));
+        self.variables
+            .iter()
+            .map(|(variable, address)| (variable.as_str(), *address))
+            .chain(predefined)
+            .find(|(variable, _)| *variable == name)
+            .map(|(_, address)| address)
+    }
+
+    /// `Program.asm_line()`: the 1-based .asm line of the instruction at `address` and its text
+    pub fn asm_line(&self, address: u16) -> Option<(usize, &str)> {
+        let line = *self.lines.get(address as usize)?;
+        let text = self.asm.get(line - 1)?;
+        Some((line, text.trim()))
+    }
+
+    /// `Program.describe()`: names `address` by its label, e.g. `LOOP+3`, or by itself without one
+    pub fn describe(&self, address: u16) -> String {
+        match self.label_at(address) {
+            Some((label, 0)) => label.to_string(),
+            Some((label, offset)) => format!("{}+{}", label, offset),
+            None => address.to_string(),
+        }
+    }
+}
+
+/// reads a program from a .hack file, or assembles it from a .asm file
+pub fn load(path: &Path) -> Result<Program, EmulatorError> {
+    let display = path.display().to_string();
+    let text = fs::read_to_string(path).map_err(|err| EmulatorError::io(&display, err))?;
+    if path.extension().is_some_and(|ext| ext == "asm") {
+        assemble(&text, &display)
+    } else {
+        parse_hack(&text, &display)
+    }
+}
+
+/// parses the text of a .hack file, one 16-digit binary word per line
+/// path: the file the text came from, used in error messages
+pub fn parse_hack(text: &str, path: &str) -> Result<Program, EmulatorError> {
+    let mut words = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message| EmulatorError::load(path, idx + 1, message);
+        if line.len() != 16 || !line.chars().all(|ch| ch == '0' || ch == '1') {
+            return Err(error(format!("`{}` is not a 16-digit binary word", line)));
+        }
+        if words.len() == ROM_SIZE {
+            return Err(error(format!(
+                "the program is longer than {} words",
+                ROM_SIZE
+            )));
+        }
+        words.push(u16::from_str_radix(line, 2).expect("checked above"));
+    }
+    Ok(Program {
+        words,
+        ..Program::default()
+    })
+}
+
+/// assembles the text of a .asm file with `hack_assembler`
+/// path: the file the text came from, used in error messages
+pub fn assemble(text: &str, path: &str) -> Result<Program, EmulatorError> {
+    assemble_with_symbols(text, path, &[])
+}
+
+/// assembles the text of a .asm file with extra predefined symbols, e.g. `Machine.symbols()` to
+/// name the addresses of the devices; they resolve like variables with `Program.variable()`
+/// path: the file the text came from, used in error messages
+pub fn assemble_with_symbols(
+    text: &str,
+    path: &str,
+    symbols: &[(String, u16)],
+) -> Result<Program, EmulatorError> {
+    let mut assembler = Assembler::new(text);
+    for (symbol, address) in symbols {
+        assembler.define_symbol(symbol, *address as usize);
+    }
+    let hack = assembler
+        .assemble()
+        .map_err(|err| EmulatorError::load(path, err.line, err.message))?;
+    let mut program = parse_hack(&hack, path)?;
+    let table = |table: &[(String, usize)]| {
+        table
+            .iter()
+            .map(|(symbol, address)| (symbol.clone(), *address as u16))
+            .collect()
+    };
+    program.labels = table(assembler.labels());
+    program.variables = table(assembler.variables());
+    program.variables.extend(symbols.iter().cloned());
+    program.lines = assembler.rom_lines().to_vec();
+    program.asm = text.lines().map(str::to_string).collect();
+    Ok(program)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_addresses_by_their_label() {
+        let program = assemble("@1\n(LOOP)\nD=A\n@LOOP\n0;JMP\n(END)\n@END\n", "t.asm").unwrap();
+        assert_eq!(program.words.len(), 5);
+        assert_eq!(program.describe(0), "0");
+        assert_eq!(program.describe(1), "LOOP");
+        assert_eq!(program.describe(3), "LOOP+2");
+        assert_eq!(program.describe(4), "END");
+        assert_eq!(program.label_at(9), Some(("END", 5)));
+        assert_eq!(program.label("LOOP"), Some(1));
+        assert_eq!(program.region("LOOP"), Some(1..4));
+        assert_eq!(program.region("END"), Some(4..5));
+        assert_eq!(program.asm_line(3), Some((5, "0;JMP")));
+    }
+
+    #[test]
+    fn finds_functions_across_generated_labels() {
+        let asm = "(Main.main)\n@1\n(RET_ADDRESS0)\n@2\n(Main.main$LOOP)\n@3\n(Sys.init)\n@4\n";
+        let program = assemble(asm, "t.asm").unwrap();
+        assert_eq!(program.function("Main.main"), Some(0..3));
+        assert_eq!(program.function("Sys.init"), Some(3..4));
+        assert_eq!(program.function("Main.main$LOOP"), None);
+        assert_eq!(program.region("Main.main"), Some(0..1));
+    }
+
+    #[test]
+    fn resolves_variables_and_predefined_symbols() {
+        let program = assemble("@sum\nM=0\n@i\n", "t.asm").unwrap();
+        assert_eq!(program.variable("i"), Some(17));
+        assert_eq!(program.variable("SP"), Some(0));
+        assert_eq!(program.variable("KBD"), Some(24576));
+        assert_eq!(program.variable("LOOP"), None);
+    }
+
+    #[test]
+    fn rejects_invalid_hack_files() {
+        let err = parse_hack("0000000000000001\n\n00000000000000012\n", "t.hack").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "t.hack:3: `00000000000000012` is not a 16-digit binary word"
+        );
+    }
+}
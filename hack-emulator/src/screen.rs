@@ -0,0 +1,158 @@
+use crate::error::EmulatorError;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// width of the screen in pixels
+pub const WIDTH: usize = 512;
+/// height of the screen in pixels
+pub const HEIGHT: usize = 256;
+/// number of words of the screen memory map, 32 words of 16 pixels per row
+pub const SCREEN_WORDS: usize = WIDTH * HEIGHT / 16;
+
+/// whether the pixel at column `x` and row `y` is black
+/// screen: the words of the screen memory map, from `SCREEN`
+/// the least significant bit of a word is its leftmost pixel
+pub fn pixel(screen: &[u16], x: usize, y: usize) -> bool {
+    screen[y * WIDTH / 16 + x / 16] & (1 << (x % 16)) != 0
+}
+
+/// the formats a screen can be saved in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// binary portable bitmap, `P4`
+    Pbm,
+    /// 1-bit grayscale PNG
+    Png,
+}
+
+impl ImageFormat {
+    /// `ImageFormat::from_path()`: the format named by the extension of `path`, `.pbm` or `.png`
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "pbm" => Some(ImageFormat::Pbm),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+/// packs the screen into rows of bytes, most significant bit first, 1 for a black pixel
+fn packed_rows(screen: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(WIDTH * HEIGHT / 8);
+    for &word in &screen[..SCREEN_WORDS] {
+        // the leftmost pixel is the least significant bit of the word
+        let word = word.reverse_bits();
+        bytes.extend_from_slice(&word.to_be_bytes());
+    }
+    bytes
+}
+
+/// encodes the screen as a binary PBM image
+/// screen: the words of the screen memory map, from `SCREEN`
+pub fn to_pbm(screen: &[u16]) -> Vec<u8> {
+    let mut image = format!("P4\n{} {}\n", WIDTH, HEIGHT).into_bytes();
+    image.extend(packed_rows(screen));
+    image
+}
+
+/// encodes the screen as a 1-bit grayscale PNG image, black pixels are 0
+/// screen: the words of the screen memory map, from `SCREEN`
+pub fn to_png(screen: &[u16]) -> Vec<u8> {
+    let mut image = Vec::new();
+    let mut encoder = png::Encoder::new(&mut image, WIDTH as u32, HEIGHT as u32);
+    encoder.set_color(png::ColorType::Grayscale);
+    encoder.set_depth(png::BitDepth::One);
+    // in grayscale 0 is black, the inverse of the screen memory map
+    let data: Vec<u8> = packed_rows(screen).iter().map(|byte| !byte).collect();
+    encoder
+        .write_header()
+        .and_then(|mut writer| writer.write_image_data(&data))
+        .expect("writing to memory cannot fail");
+    image
+}
+
+/// saves the screen to `path`, in the format named by its extension
+/// screen: the words of the screen memory map, from `SCREEN`
+pub fn save(screen: &[u16], path: &Path) -> Result<(), EmulatorError> {
+    let display = path.display().to_string();
+    let image = match ImageFormat::from_path(path) {
+        Some(ImageFormat::Pbm) => to_pbm(screen),
+        Some(ImageFormat::Png) => to_png(screen),
+        None => {
+            let err = io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "screenshots must be .pbm or .png files",
+            );
+            return Err(EmulatorError::io(&display, err));
+        }
+    };
+    fs::write(path, image).map_err(|err| EmulatorError::io(&display, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{rom, Machine};
+
+    fn golden(name: &str) -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("golden")
+            .join(name);
+        fs::read(path).unwrap()
+    }
+
+    fn run(program: &str, setup: &[(u16, u16)], cycles: u64) -> Machine {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("../projects")
+            .join(program);
+        let mut machine = Machine::new(&rom::load(&path).unwrap().words);
+        for &(address, value) in setup {
+            machine.poke(address, value);
+        }
+        machine.run(cycles);
+        machine
+    }
+
+    #[test]
+    fn packs_the_leftmost_pixel_first() {
+        let mut screen = vec![0; SCREEN_WORDS];
+        screen[0] = 0b1;
+        screen[33] = 0x8000;
+        assert!(pixel(&screen, 0, 0));
+        assert!(pixel(&screen, 31, 1));
+        assert!(!pixel(&screen, 15, 0));
+        let pbm = to_pbm(&screen);
+        let header = b"P4\n512 256\n".len();
+        assert_eq!(pbm[header], 0b1000_0000);
+        assert_eq!(pbm[header + WIDTH / 8 + 3], 0b0000_0001);
+    }
+
+    #[test]
+    fn matches_the_golden_rect() {
+        let machine = run("06/rect/Rect.hack", &[(0, 20)], 10_000);
+        assert_eq!(to_pbm(machine.screen()), golden("Rect.pbm"));
+    }
+
+    #[test]
+    fn matches_the_golden_pong() {
+        let machine = run("06/pong/Pong.hack", &[], 6_000_000);
+        assert_eq!(to_pbm(machine.screen()), golden("Pong.pbm"));
+    }
+
+    #[test]
+    fn png_decodes_to_the_screen() {
+        let machine = run("06/rect/Rect.hack", &[(0, 20)], 10_000);
+        let png = to_png(machine.screen());
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().unwrap();
+        let mut data = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut data).unwrap();
+        assert_eq!((info.width, info.height), (WIDTH as u32, HEIGHT as u32));
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                let white = data[y * WIDTH / 8 + x / 8] & (0x80 >> (x % 8)) != 0;
+                assert_eq!(white, !pixel(machine.screen(), x, y), "({}, {})", x, y);
+            }
+        }
+    }
+}
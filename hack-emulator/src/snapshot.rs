@@ -0,0 +1,224 @@
+use crate::error::EmulatorError;
+use crate::keys::{KeyEvent, KeyScript};
+use crate::machine::{Machine, Registers, RAM_SIZE, ROM_SIZE};
+use std::fs;
+use std::path::Path;
+
+/// the first bytes of a snapshot file
+const MAGIC: &[u8; 8] = b"HACKSNAP";
+/// the version of the snapshot format written, files of other versions are rejected
+pub const VERSION: u16 = 1;
+
+/// # Snapshot
+/// the full state of a run: the program, the data memory, the registers, the cycle count and the
+/// keys still to be pressed, so that the run can be resumed exactly
+///
+/// the file format is little-endian: `HACKSNAP`, the version (u16), A, D and PC (u16 each), the cycle
+/// count (u64), the number of ROM words (u32) and the words, the `RAM_SIZE` words of the data memory
+/// including the screen and the keyboard register, then the number of pending key events (u32)
+/// and each event's cycle (u64) and key code (u16)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// the loaded program
+    pub rom: Vec<u16>,
+    /// the data memory
+    pub ram: Vec<u16>,
+    /// the registers and the cycle count
+    pub registers: Registers,
+    /// the key events not applied yet
+    pub keys: Vec<KeyEvent>,
+}
+
+/// reads the little-endian fields of a snapshot, failing on a truncated file
+struct Reader<'a> {
+    bytes: &'a [u8],
+    path: &'a str,
+}
+
+impl Reader<'_> {
+    fn take(&mut self, len: usize) -> Result<&[u8], EmulatorError> {
+        if self.bytes.len() < len {
+            return Err(EmulatorError::format(
+                self.path,
+                "truncated snapshot".to_string(),
+            ));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u16(&mut self) -> Result<u16, EmulatorError> {
+        Ok(u16::from_le_bytes(
+            self.take(2)?.try_into().expect("2 bytes"),
+        ))
+    }
+
+    fn u32(&mut self) -> Result<u32, EmulatorError> {
+        Ok(u32::from_le_bytes(
+            self.take(4)?.try_into().expect("4 bytes"),
+        ))
+    }
+
+    fn u64(&mut self) -> Result<u64, EmulatorError> {
+        Ok(u64::from_le_bytes(
+            self.take(8)?.try_into().expect("8 bytes"),
+        ))
+    }
+
+    fn words(&mut self, len: usize) -> Result<Vec<u16>, EmulatorError> {
+        (0..len).map(|_| self.u16()).collect()
+    }
+}
+
+impl Snapshot {
+    /// `Snapshot::capture()`: the state of `machine`, with the events of `keys` not applied yet
+    pub fn capture(machine: &Machine, keys: &KeyScript) -> Self {
+        Snapshot {
+            rom: machine.rom()[..machine.program_len()].to_vec(),
+            ram: (0..RAM_SIZE)
+                .map(|address| machine.peek(address as u16))
+                .collect(),
+            registers: machine.registers(),
+            keys: keys.pending().to_vec(),
+        }
+    }
+
+    /// `Snapshot.restore()`: a machine in the captured state and the keys it has still to be pressed
+    pub fn restore(&self) -> (Machine, KeyScript) {
+        let mut machine = Machine::new(&self.rom);
+        for (address, &value) in self.ram.iter().enumerate() {
+            machine.poke(address as u16, value);
+        }
+        machine.set_registers(self.registers);
+        (machine, KeyScript::new(self.keys.clone()))
+    }
+
+    /// `Snapshot.to_bytes()`: the snapshot in the file format
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend(VERSION.to_le_bytes());
+        let registers = self.registers;
+        for word in [registers.a, registers.d, registers.pc] {
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes.extend(registers.cycle.to_le_bytes());
+        bytes.extend((self.rom.len() as u32).to_le_bytes());
+        for word in self.rom.iter().chain(&self.ram) {
+            bytes.extend(word.to_le_bytes());
+        }
+        bytes.extend((self.keys.len() as u32).to_le_bytes());
+        for event in &self.keys {
+            bytes.extend(event.cycle.to_le_bytes());
+            bytes.extend(event.code.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// `Snapshot::from_bytes()`: parses a snapshot file
+    /// path: the file the bytes came from, used in error messages
+    pub fn from_bytes(bytes: &[u8], path: &str) -> Result<Self, EmulatorError> {
+        let error = |message: &str| EmulatorError::format(path, message.to_string());
+        let mut reader = Reader { bytes, path };
+        if reader.take(MAGIC.len()).ok() != Some(MAGIC) {
+            return Err(error("not a Hack snapshot"));
+        }
+        let version = reader.u16()?;
+        if version != VERSION {
+            return Err(error(&format!(
+                "snapshot version {} is not supported, expected {}",
+                version, VERSION
+            )));
+        }
+        let (a, d, pc) = (reader.u16()?, reader.u16()?, reader.u16()?);
+        let registers = Registers {
+            a,
+            d,
+            pc,
+            cycle: reader.u64()?,
+        };
+        let rom_len = reader.u32()? as usize;
+        if rom_len > ROM_SIZE {
+            return Err(error("the program is larger than the ROM"));
+        }
+        let rom = reader.words(rom_len)?;
+        let ram = reader.words(RAM_SIZE)?;
+        let key_count = reader.u32()? as usize;
+        let mut keys = Vec::new();
+        for _ in 0..key_count {
+            let cycle = reader.u64()?;
+            keys.push(KeyEvent {
+                cycle,
+                code: reader.u16()?,
+            });
+        }
+        if !reader.bytes.is_empty() {
+            return Err(error("unexpected bytes after the snapshot"));
+        }
+        Ok(Snapshot {
+            rom,
+            ram,
+            registers,
+            keys,
+        })
+    }
+
+    /// `Snapshot::load()`: reads a snapshot file
+    pub fn load(path: &Path) -> Result<Self, EmulatorError> {
+        let display = path.display().to_string();
+        let bytes = fs::read(path).map_err(|err| EmulatorError::io(&display, err))?;
+        Snapshot::from_bytes(&bytes, &display)
+    }
+
+    /// `Snapshot.save()`: writes the snapshot to a file
+    pub fn save(&self, path: &Path) -> Result<(), EmulatorError> {
+        fs::write(path, self.to_bytes())
+            .map_err(|err| EmulatorError::io(&path.display().to_string(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom;
+    use crate::KBD;
+
+    #[test]
+    fn resumes_pong_exactly() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("../projects/06/pong/Pong.asm");
+        let program = rom::load(&path).unwrap();
+        let mut machine = Machine::new(&program.words);
+        let mut keys = KeyScript::parse("5200000 right\n5500000 release\n", "t.keys").unwrap();
+        keys.run(&mut machine, 5_300_000);
+
+        let bytes = Snapshot::capture(&machine, &keys).to_bytes();
+        let snapshot = Snapshot::from_bytes(&bytes, "t.hacksnap").unwrap();
+        assert_eq!(snapshot.keys.len(), 1);
+        let (mut resumed, mut resumed_keys) = snapshot.restore();
+        assert_eq!(resumed.registers(), machine.registers());
+        assert_eq!(resumed.peek(KBD), 132);
+
+        keys.run(&mut machine, 700_000);
+        resumed_keys.run(&mut resumed, 700_000);
+        assert_eq!(resumed.registers(), machine.registers());
+        assert_eq!(resumed.screen(), machine.screen());
+        assert_eq!(resumed.peek(0), machine.peek(0));
+    }
+
+    #[test]
+    fn rejects_other_files_and_versions() {
+        let machine = Machine::new(&[1, 2, 3]);
+        let mut bytes = Snapshot::capture(&machine, &KeyScript::default()).to_bytes();
+
+        let err = Snapshot::from_bytes(b"P4\n512 256\n", "t.pbm").unwrap_err();
+        assert_eq!(err.to_string(), "t.pbm: not a Hack snapshot");
+        let err = Snapshot::from_bytes(&bytes[..100], "t.hacksnap").unwrap_err();
+        assert_eq!(err.to_string(), "t.hacksnap: truncated snapshot");
+        bytes[8] = 2;
+        let err = Snapshot::from_bytes(&bytes, "t.hacksnap").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "t.hacksnap: snapshot version 2 is not supported, expected 1"
+        );
+    }
+}
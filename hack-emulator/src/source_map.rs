@@ -0,0 +1,94 @@
+use crate::error::EmulatorError;
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+/// what the instruction at a ROM address was generated from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    /// the 1-based line of the .asm program
+    pub asm_line: usize,
+    /// the VM source and command the line was translated from, e.g. `Main.vm:3 push constant 7`,
+    /// none for code the translator generated on its own
+    pub vm: Option<String>,
+}
+
+/// relates ROM addresses to the lines of the .asm program and to the VM commands they were
+/// translated from, read from the source map written by `vm-translator --source-map`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// the source of every ROM address the map names
+    lines: HashMap<u16, SourceLine>,
+}
+
+impl SourceMap {
+    /// `SourceMap::parse()`: parses the text of a source map, one tab separated line per .asm line:
+    /// the .asm line, the ROM address or `-`, and `file:line` and the VM command or `-`
+    /// path: the file the text came from, used in error messages
+    pub fn parse(text: &str, path: &str) -> Result<Self, EmulatorError> {
+        let mut lines = HashMap::new();
+        for (idx, line) in text.lines().enumerate() {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = || EmulatorError::load(path, idx + 1, format!("invalid line `{}`", line));
+            let mut fields = line.splitn(4, '\t');
+            let asm_line = fields.next().and_then(|field| field.parse::<usize>().ok());
+            let rom_address = fields.next().ok_or_else(error)?;
+            let (asm_line, vm) = match (asm_line, fields.next(), fields.next()) {
+                (Some(asm_line), Some("-"), None) => (asm_line, None),
+                (Some(asm_line), Some(source), Some(command)) => {
+                    (asm_line, Some(format!("{} {}", source, command)))
+                }
+                _ => return Err(error()),
+            };
+            if rom_address == "-" {
+                continue;
+            }
+            let rom_address = rom_address.parse::<u16>().map_err(|_| error())?;
+            lines.insert(rom_address, SourceLine { asm_line, vm });
+        }
+        Ok(SourceMap { lines })
+    }
+
+    /// `SourceMap::load()`: reads a source map file
+    pub fn load(path: &Path) -> Result<Self, EmulatorError> {
+        let display = path.display().to_string();
+        let text = fs::read_to_string(path).map_err(|err| EmulatorError::io(&display, err))?;
+        SourceMap::parse(&text, &display)
+    }
+
+    /// `SourceMap.get()`: what the instruction at `address` was generated from
+    pub fn get(&self, address: u16) -> Option<&SourceLine> {
+        self.lines.get(&address)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_the_translator_format() {
+        let text = "# asm_line\trom_address\tvm_source\tvm_command\n\
+                    1\t0\t-\n\
+                    2\t-\tMain.vm:3\tpush constant 7\n\
+                    3\t1\tMain.vm:3\tpush constant 7\n";
+        let map = SourceMap::parse(text, "Main.map").unwrap();
+        assert_eq!(
+            map.get(0),
+            Some(&SourceLine {
+                asm_line: 1,
+                vm: None
+            })
+        );
+        assert_eq!(
+            map.get(1).unwrap().vm.as_deref(),
+            Some("Main.vm:3 push constant 7")
+        );
+        assert_eq!(map.get(2), None);
+
+        let err = SourceMap::parse("1\t0\n", "Main.map").unwrap_err();
+        assert_eq!(err.to_string(), "Main.map:1: invalid line `1\t0`");
+    }
+}
@@ -0,0 +1,462 @@
+use crate::decode;
+use crate::error::EmulatorError;
+use crate::keys::KeyScript;
+use crate::machine::Machine;
+use crate::rom::Program;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// the first bytes of a binary trace
+const MAGIC: &[u8; 8] = b"HACKTRCE";
+/// the version of the binary trace format written, files of other versions are rejected
+pub const VERSION: u16 = 1;
+/// number of bytes of a record of a binary trace
+const RECORD_SIZE: usize = 26;
+/// the write address of a binary record of an instruction that wrote no RAM,
+/// addresses are 15 bits wide
+const NO_WRITE: u16 = 0xffff;
+
+/// how a trace is stored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// fixed-size little-endian records after a `HACKTRCE` header, without the disassembly
+    Binary,
+    /// one JSON object per line, with the disassembly
+    Jsonl,
+}
+
+impl TraceFormat {
+    /// `TraceFormat::from_path()`: JSONL for `.jsonl` files, binary for the others
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("jsonl") => TraceFormat::Jsonl,
+            _ => TraceFormat::Binary,
+        }
+    }
+}
+
+/// a write of an instruction to the data memory
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RamWrite {
+    /// the RAM address written
+    pub address: u16,
+    /// the word before the write
+    pub old: u16,
+    /// the word written
+    pub new: u16,
+}
+
+/// an executed instruction and its effects
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// number of instructions executed before this one
+    pub cycle: u64,
+    /// the ROM address of the instruction
+    pub pc: u16,
+    /// the instruction word
+    pub instruction: u16,
+    /// the A register before the instruction
+    pub a: u16,
+    /// the D register before the instruction
+    pub d: u16,
+    /// the A register after the instruction
+    pub a_after: u16,
+    /// the D register after the instruction
+    pub d_after: u16,
+    /// the RAM write of the instruction, if it wrote RAM
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write: Option<RamWrite>,
+}
+
+/// a line of a JSONL trace: the record and the disassembled instruction
+#[derive(Serialize, Deserialize)]
+struct JsonRecord {
+    #[serde(flatten)]
+    record: TraceRecord,
+    asm: String,
+}
+
+impl TraceRecord {
+    /// `TraceRecord.to_bytes()`: the record in the binary format
+    fn to_bytes(self) -> [u8; RECORD_SIZE] {
+        let write = self.write.unwrap_or(RamWrite {
+            address: NO_WRITE,
+            old: 0,
+            new: 0,
+        });
+        let mut bytes = [0; RECORD_SIZE];
+        bytes[..8].copy_from_slice(&self.cycle.to_le_bytes());
+        let words = [
+            self.pc,
+            self.instruction,
+            self.a,
+            self.d,
+            self.a_after,
+            self.d_after,
+            write.address,
+            write.old,
+            write.new,
+        ];
+        for (idx, word) in words.iter().enumerate() {
+            bytes[8 + 2 * idx..10 + 2 * idx].copy_from_slice(&word.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// `TraceRecord::from_bytes()`: a record of the binary format
+    fn from_bytes(bytes: &[u8; RECORD_SIZE]) -> Self {
+        let word = |idx: usize| u16::from_le_bytes([bytes[8 + 2 * idx], bytes[9 + 2 * idx]]);
+        TraceRecord {
+            cycle: u64::from_le_bytes(bytes[..8].try_into().expect("8 bytes")),
+            pc: word(0),
+            instruction: word(1),
+            a: word(2),
+            d: word(3),
+            a_after: word(4),
+            d_after: word(5),
+            write: (word(6) != NO_WRITE).then(|| RamWrite {
+                address: word(6),
+                old: word(7),
+                new: word(8),
+            }),
+        }
+    }
+}
+
+/// # TraceFilter
+/// the ROM addresses whose instructions are traced, all of them when no range was added
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TraceFilter {
+    /// the ranges of ROM addresses traced
+    ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl TraceFilter {
+    /// `TraceFilter.add()`: traces the ROM addresses of `spec`, `START-END`, a single address,
+    /// the region of a label, or `PREFIX*` for every label starting with `PREFIX`, with the whole
+    /// function for the labels of VM functions; addresses may be labels of `program`
+    /// option: the command line option of `spec`, used in error messages
+    pub fn add(
+        &mut self,
+        option: &str,
+        spec: &str,
+        program: &Program,
+    ) -> Result<(), EmulatorError> {
+        let error = || {
+            EmulatorError::load(
+                option,
+                1,
+                format!("`{}` is not an address, a range or a label", spec),
+            )
+        };
+        if let Some(prefix) = spec.strip_suffix('*') {
+            let regions: Vec<_> = program
+                .labels
+                .iter()
+                .filter(|(label, _)| label.starts_with(prefix))
+                .filter_map(|(label, _)| program.function(label).or_else(|| program.region(label)))
+                .filter(|region| !region.is_empty())
+                .map(|region| region.start..=region.end - 1)
+                .collect();
+            if regions.is_empty() {
+                return Err(error());
+            }
+            self.ranges.extend(regions);
+            return Ok(());
+        }
+        let address = |name: &str| {
+            let name = name.trim();
+            name.parse::<u16>().ok().or_else(|| program.label(name))
+        };
+        let range = match spec.split_once('-') {
+            Some((start, end)) => address(start).zip(address(end)).map(|(s, e)| s..=e),
+            None => match program.region(spec) {
+                Some(region) if !region.is_empty() => Some(region.start..=region.end - 1),
+                _ => address(spec).map(|address| address..=address),
+            },
+        };
+        self.ranges.push(range.ok_or_else(error)?);
+        Ok(())
+    }
+
+    /// `TraceFilter.is_empty()`: whether no range was added, so that every address is traced
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// `TraceFilter.matches()`: whether the instruction at `pc` is traced
+    pub fn matches(&self, pc: u16) -> bool {
+        self.ranges.is_empty() || self.ranges.iter().any(|range| range.contains(&pc))
+    }
+}
+
+/// writes trace records to a file in one of the formats
+pub struct TraceWriter<W: Write> {
+    out: W,
+    format: TraceFormat,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// `TraceWriter::new()`: constructor, writes the header of binary traces
+    pub fn new(mut out: W, format: TraceFormat) -> io::Result<Self> {
+        if format == TraceFormat::Binary {
+            out.write_all(MAGIC)?;
+            out.write_all(&VERSION.to_le_bytes())?;
+        }
+        Ok(TraceWriter { out, format })
+    }
+
+    /// `TraceWriter.write()`: writes a record
+    pub fn write(&mut self, record: &TraceRecord) -> io::Result<()> {
+        match self.format {
+            TraceFormat::Binary => self.out.write_all(&record.to_bytes()),
+            TraceFormat::Jsonl => {
+                let line = JsonRecord {
+                    record: *record,
+                    asm: decode::disassemble(record.instruction),
+                };
+                serde_json::to_writer(&mut self.out, &line)?;
+                self.out.write_all(b"\n")
+            }
+        }
+    }
+
+    /// `TraceWriter.finish()`: flushes the records written and returns the output
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// runs `machine` for up to `cycles` instructions, or until the program halts, writing a record
+/// of every instruction `filter` matches; returns the number of records written
+/// keys: the keys pressed during the run
+pub fn trace<W: Write>(
+    machine: &mut Machine,
+    keys: &mut KeyScript,
+    cycles: u64,
+    filter: &TraceFilter,
+    writer: &mut TraceWriter<W>,
+) -> io::Result<u64> {
+    let mut written = 0;
+    for _ in 0..cycles {
+        if machine.halted() {
+            break;
+        }
+        keys.apply(machine);
+        let before = machine.registers();
+        let write_address = machine.write_address();
+        let old = write_address.map(|address| machine.peek(address));
+        machine.step();
+        if !filter.matches(before.pc) {
+            continue;
+        }
+        let record = TraceRecord {
+            cycle: before.cycle,
+            pc: before.pc,
+            instruction: machine.rom()[before.pc as usize],
+            a: before.a,
+            d: before.d,
+            a_after: machine.a(),
+            d_after: machine.d(),
+            write: write_address.zip(old).map(|(address, old)| RamWrite {
+                address,
+                old,
+                new: machine.peek(address),
+            }),
+        };
+        writer.write(&record)?;
+        written += 1;
+    }
+    Ok(written)
+}
+
+/// reads the records of a trace file, of either format
+pub fn read(path: &Path) -> Result<Vec<TraceRecord>, EmulatorError> {
+    let display = path.display().to_string();
+    let io_error = |err| EmulatorError::io(&display, err);
+    let mut reader = BufReader::new(File::open(path).map_err(io_error)?);
+
+    let binary = reader.fill_buf().map_err(io_error)?.starts_with(MAGIC);
+    let mut records = Vec::new();
+    if binary {
+        let mut header = [0; MAGIC.len() + 2];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| EmulatorError::format(&display, "truncated trace".to_string()))?;
+        let version = u16::from_le_bytes([header[8], header[9]]);
+        if version != VERSION {
+            let message = format!(
+                "trace version {} is not supported, expected {}",
+                version, VERSION
+            );
+            return Err(EmulatorError::format(&display, message));
+        }
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes).map_err(io_error)?;
+        if bytes.len() % RECORD_SIZE != 0 {
+            return Err(EmulatorError::format(
+                &display,
+                "truncated trace".to_string(),
+            ));
+        }
+        for chunk in bytes.chunks_exact(RECORD_SIZE) {
+            records.push(TraceRecord::from_bytes(chunk.try_into().expect("a record")));
+        }
+    } else {
+        for (idx, line) in reader.lines().enumerate() {
+            let line = line.map_err(io_error)?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let json: JsonRecord = serde_json::from_str(&line)
+                .map_err(|err| EmulatorError::load(&display, idx + 1, err.to_string()))?;
+            records.push(json.record);
+        }
+    }
+    Ok(records)
+}
+
+/// the last record of `records` that wrote RAM `address`
+pub fn last_write(records: &[TraceRecord], address: u16) -> Option<&TraceRecord> {
+    records
+        .iter()
+        .rev()
+        .find(|record| record.write.is_some_and(|write| write.address == address))
+}
+
+/// describes a record on one line, with the label of its instruction if the program has them
+pub fn describe(record: &TraceRecord, program: &Program) -> String {
+    let mut text = format!(
+        "cycle {} {} {}: A={} D={} -> A={} D={}",
+        record.cycle,
+        program.describe(record.pc),
+        decode::disassemble(record.instruction),
+        record.a,
+        record.d as i16,
+        record.a_after,
+        record.d_after as i16
+    );
+    if let Some(write) = record.write {
+        text += &format!(
+            ", RAM[{}] {} -> {}",
+            write.address, write.old as i16, write.new as i16
+        );
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom;
+
+    /// adds 1 and 2 into `sum`, then halts
+    const PROGRAM: &str = "\
+@sum
+M=1
+(ADD)
+@2
+D=A
+@sum
+M=D+M
+(END)
+@END
+0;JMP
+";
+
+    fn record(format: TraceFormat, filter: &TraceFilter) -> (Program, Vec<u8>, u64) {
+        let program = rom::assemble(PROGRAM, "Sum.asm").unwrap();
+        let mut machine = Machine::new(&program.words);
+        let mut writer = TraceWriter::new(Vec::new(), format).unwrap();
+        let mut keys = KeyScript::default();
+        let count = trace(&mut machine, &mut keys, 100, filter, &mut writer).unwrap();
+        (program, writer.finish().unwrap(), count)
+    }
+
+    fn read_bytes(bytes: &[u8], name: &str) -> Vec<TraceRecord> {
+        let path = std::env::temp_dir().join(format!("hack-trace-{}-{}", std::process::id(), name));
+        std::fs::write(&path, bytes).unwrap();
+        let records = read(&path);
+        std::fs::remove_file(&path).unwrap();
+        records.unwrap()
+    }
+
+    #[test]
+    fn records_registers_and_writes() {
+        let (program, bytes, count) = record(TraceFormat::Jsonl, &TraceFilter::default());
+        // the run stops at the final loop, before its jump
+        assert_eq!(count, 7);
+        let text = String::from_utf8(bytes).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            lines[1],
+            r#"{"cycle":1,"pc":1,"instruction":61384,"a":16,"d":0,"a_after":16,"d_after":0,"write":{"address":16,"old":0,"new":1},"asm":"M=1"}"#
+        );
+        assert!(
+            lines[0].ends_with(r#""d_after":0,"asm":"@16"}"#),
+            "{}",
+            lines[0]
+        );
+
+        let records = read_bytes(text.as_bytes(), "t.jsonl");
+        assert_eq!(records.len(), 7);
+        let last = last_write(&records, 16).unwrap();
+        assert_eq!(
+            describe(last, &program),
+            "cycle 5 ADD+3 M=D+M: A=16 D=2 -> A=16 D=2, RAM[16] 1 -> 3"
+        );
+        assert_eq!(last_write(&records, 17), None);
+    }
+
+    #[test]
+    fn binary_traces_match_jsonl() {
+        let (_, jsonl, _) = record(TraceFormat::Jsonl, &TraceFilter::default());
+        let (_, binary, _) = record(TraceFormat::Binary, &TraceFilter::default());
+        assert_eq!(binary.len(), 10 + 7 * RECORD_SIZE);
+        assert_eq!(
+            read_bytes(&binary, "t.trace"),
+            read_bytes(&jsonl, "t.jsonl")
+        );
+
+        let mut truncated = binary.clone();
+        truncated.pop();
+        let path = std::env::temp_dir().join(format!("hack-trace-{}-bad", std::process::id()));
+        std::fs::write(&path, &truncated).unwrap();
+        let err = read(&path).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.to_string().ends_with(": truncated trace"), "{}", err);
+    }
+
+    #[test]
+    fn filters_by_address_range_and_label() {
+        let program = rom::assemble(PROGRAM, "Sum.asm").unwrap();
+        let mut filter = TraceFilter::default();
+        filter.add("--pc", "ADD", &program).unwrap();
+        let (_, bytes, count) = record(TraceFormat::Binary, &filter);
+        assert_eq!(count, 4);
+        let pcs: Vec<u16> = read_bytes(&bytes, "label.trace")
+            .iter()
+            .map(|record| record.pc)
+            .collect();
+        assert_eq!(pcs, [2, 3, 4, 5]);
+
+        let mut filter = TraceFilter::default();
+        filter.add("--pc", "0-1", &program).unwrap();
+        filter.add("--pc", "END", &program).unwrap();
+        assert!(filter.matches(1) && filter.matches(6) && !filter.matches(2));
+        let err = filter.add("--pc", "NOWHERE", &program).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "--pc:1: `NOWHERE` is not an address, a range or a label"
+        );
+
+        let mut filter = TraceFilter::default();
+        filter.add("--graphics", "A*", &program).unwrap();
+        assert!(filter.matches(2) && filter.matches(5) && !filter.matches(6));
+        assert!(filter.add("--graphics", "LOOP*", &program).is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use crate::decode;
+use crate::error::EmulatorError;
+use crate::keys::{KeyEvent, KeyScript};
+use crate::machine::{Machine, KBD};
+use crate::rom::Program;
+use crate::screen::{self, HEIGHT, WIDTH};
+use crate::snapshot::Snapshot;
+use std::io::{self, Write};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+use termion::cursor::HideCursor;
+use termion::event::Key;
+use termion::input::TermRead;
+use termion::raw::IntoRawMode;
+use termion::screen::IntoAlternateScreen;
+
+/// number of frames drawn per second
+const FPS: u64 = 30;
+
+/// how the screen is drawn with text characters
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Render {
+    /// braille characters, 2x4 dots per character
+    Braille,
+    /// half-block characters, 1x2 dots per character
+    HalfBlock,
+}
+
+impl Render {
+    /// `Render.default_scale()`: the scale that draws the screen in 128x32 characters
+    pub fn default_scale(self) -> usize {
+        match self {
+            Render::Braille => 2,
+            Render::HalfBlock => 4,
+        }
+    }
+}
+
+/// settings of the terminal front-end
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TuiOptions {
+    /// how the screen is drawn
+    pub render: Render,
+    /// the side of the square of pixels drawn as one dot, a dot is set if any of its pixels is black
+    pub scale: usize,
+    /// number of instructions executed per second
+    pub speed: u64,
+    /// how long a key stays pressed, in milliseconds, as terminals do not report key releases
+    pub hold: u64,
+}
+
+/// whether any pixel of the `scale` x `scale` square of dot (`dot_x`, `dot_y`) is black
+fn dot(screen: &[u16], scale: usize, dot_x: usize, dot_y: usize) -> bool {
+    let ys = (dot_y * scale).min(HEIGHT)..((dot_y + 1) * scale).min(HEIGHT);
+    ys.into_iter().any(|y| {
+        let xs = (dot_x * scale).min(WIDTH)..((dot_x + 1) * scale).min(WIDTH);
+        xs.into_iter().any(|x| screen::pixel(screen, x, y))
+    })
+}
+
+/// draws the screen as lines of text
+/// screen: the words of the screen memory map, from `SCREEN`
+/// scale: the side of the square of pixels drawn as one dot
+pub fn render_screen(screen: &[u16], render: Render, scale: usize) -> Vec<String> {
+    let scale = scale.max(1);
+    // the size of a character in dots
+    let (cell_width, cell_height) = match render {
+        Render::Braille => (2, 4),
+        Render::HalfBlock => (1, 2),
+    };
+    let dots_x = WIDTH.div_ceil(scale);
+    let dots_y = HEIGHT.div_ceil(scale);
+    let mut lines = Vec::new();
+    for row in 0..dots_y.div_ceil(cell_height) {
+        let mut line = String::new();
+        for col in 0..dots_x.div_ceil(cell_width) {
+            let set = |dx, dy| dot(screen, scale, col * cell_width + dx, row * cell_height + dy);
+            let ch = match render {
+                Render::Braille => {
+                    // the bits of the braille dots, column by column
+                    const DOTS: [(usize, usize, u32); 8] = [
+                        (0, 0, 0x01),
+                        (0, 1, 0x02),
+                        (0, 2, 0x04),
+                        (1, 0, 0x08),
+                        (1, 1, 0x10),
+                        (1, 2, 0x20),
+                        (0, 3, 0x40),
+                        (1, 3, 0x80),
+                    ];
+                    let bits = DOTS
+                        .iter()
+                        .filter(|&&(dx, dy, _)| set(dx, dy))
+                        .fold(0, |bits, &(_, _, bit)| bits | bit);
+                    char::from_u32(0x2800 + bits).expect("braille patterns are characters")
+                }
+                Render::HalfBlock => match (set(0, 0), set(0, 1)) {
+                    (true, true) => '█',
+                    (true, false) => '▀',
+                    (false, true) => '▄',
+                    (false, false) => ' ',
+                },
+            };
+            line.push(ch);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// the Hack keyboard code of a terminal key, none for keys the Hack keyboard does not have
+pub fn key_code(key: Key) -> Option<u16> {
+    let code = match key {
+        Key::Char('\n') => 128,
+        Key::Backspace => 129,
+        Key::Left => 130,
+        Key::Up => 131,
+        Key::Right => 132,
+        Key::Down => 133,
+        Key::Home => 134,
+        Key::End => 135,
+        Key::PageUp => 136,
+        Key::PageDown => 137,
+        Key::Insert => 138,
+        Key::Delete => 139,
+        Key::Esc => 140,
+        Key::F(n @ 1..=12) => 140 + n as u16,
+        Key::Char(ch @ ' '..='~') => ch as u16,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// describes the registers and the current instruction, with its label if the program has them
+pub fn status(machine: &Machine, program: &Program) -> Vec<String> {
+    let pc = machine.pc();
+    let word = machine.rom()[pc as usize];
+    vec![
+        format!(
+            "A={:<6} D={:<6} PC={:<6} KBD={:<4} cycles={}",
+            machine.a(),
+            machine.d() as i16,
+            pc,
+            machine.peek(KBD),
+            machine.cycles()
+        ),
+        format!(
+            "{:>5} {:<24} {}",
+            pc,
+            program.describe(pc),
+            decode::disassemble(word)
+        ),
+    ]
+}
+
+/// sets the keyboard register and records the change
+fn press(machine: &mut Machine, pressed: &mut KeyScript, code: u16) {
+    machine.set_key(code);
+    pressed.push(KeyEvent {
+        cycle: machine.cycles(),
+        code,
+    });
+}
+
+/// runs `machine` in the terminal until Ctrl-C is pressed, drawing the screen and the registers
+/// and passing the keys pressed to the keyboard register
+/// Ctrl-P pauses and resumes, Ctrl-S saves the screen to `screenshot-CYCLE.png` and Ctrl-W
+/// saves the state to `snapshot-CYCLE.hacksnap`
+/// script: keys to replay along with the keys pressed
+/// returns the keys of the session, replayed and pressed, to reproduce it with `KeyScript.run()`
+pub fn run(
+    machine: &mut Machine,
+    program: &Program,
+    options: &TuiOptions,
+    mut script: KeyScript,
+) -> Result<KeyScript, EmulatorError> {
+    let error = |err| EmulatorError::io("terminal", err);
+    let stdout = io::stdout().into_raw_mode().map_err(error)?;
+    let mut stdout = HideCursor::from(stdout.into_alternate_screen().map_err(error)?);
+    let mut keys = termion::async_stdin().keys();
+
+    let frame = Duration::from_secs(1) / FPS as u32;
+    let cycles_per_frame = (options.speed / FPS).max(1);
+    let hold_cycles = options.speed * options.hold / 1000;
+    // the cycle at which the key being pressed is released
+    let mut release_at: Option<u64> = None;
+    // the keys pressed and released in the terminal
+    let mut pressed = KeyScript::default();
+    let mut paused = false;
+    let mut message = String::new();
+
+    write!(stdout, "{}", termion::clear::All).map_err(error)?;
+    loop {
+        let start = Instant::now();
+        for key in keys.by_ref() {
+            // escape sequences of keys termion does not know are ignored
+            let Ok(key) = key else { continue };
+            match key {
+                Key::Ctrl('c') => {
+                    // a key pressed at the cycle of a replayed event was set first
+                    let mut events = pressed.events().to_vec();
+                    events.extend_from_slice(script.applied());
+                    return Ok(KeyScript::new(events));
+                }
+                Key::Ctrl('p') => paused = !paused,
+                Key::Ctrl('s') => {
+                    let path = format!("screenshot-{}.png", machine.cycles());
+                    message = match screen::save(machine.screen(), Path::new(&path)) {
+                        Ok(()) => format!("saved {}", path),
+                        Err(err) => err.to_string(),
+                    };
+                }
+                Key::Ctrl('w') => {
+                    // the release of the key being pressed is still to come
+                    let mut pending = script.pending().to_vec();
+                    pending.extend(release_at.map(|cycle| KeyEvent { cycle, code: 0 }));
+                    let snapshot = Snapshot::capture(machine, &KeyScript::new(pending));
+                    let path = format!("snapshot-{}.hacksnap", machine.cycles());
+                    message = match snapshot.save(Path::new(&path)) {
+                        Ok(()) => format!("saved {}", path),
+                        Err(err) => err.to_string(),
+                    };
+                }
+                key => {
+                    if let Some(code) = key_code(key) {
+                        press(machine, &mut pressed, code);
+                        release_at = Some(machine.cycles() + hold_cycles);
+                    }
+                }
+            }
+        }
+
+        if !paused {
+            let end = machine.cycles() + cycles_per_frame;
+            if let Some(release) = release_at.filter(|&release| release <= end) {
+                script.run(machine, release.saturating_sub(machine.cycles()));
+                press(machine, &mut pressed, 0);
+                release_at = None;
+            }
+            script.run(machine, end - machine.cycles());
+        }
+
+        let mut lines = render_screen(machine.screen(), options.render, options.scale);
+        lines.extend(status(machine, program));
+        lines.push(format!(
+            "{}  Ctrl-C quit  Ctrl-P pause  Ctrl-S screenshot  Ctrl-W snapshot  {}",
+            if paused { "PAUSED " } else { "running" },
+            message
+        ));
+        write!(stdout, "{}", termion::cursor::Goto(1, 1)).map_err(error)?;
+        for line in lines {
+            write!(stdout, "{}{}\r\n", line, termion::clear::UntilNewline).map_err(error)?;
+        }
+        stdout.flush().map_err(error)?;
+
+        thread::sleep(frame.saturating_sub(start.elapsed()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rom;
+    use crate::screen::SCREEN_WORDS;
+
+    #[test]
+    fn draws_braille_and_half_blocks() {
+        let mut screen = vec![0; SCREEN_WORDS];
+        // the top-left pixel and the pixel at (3, 7)
+        screen[0] = 0b1;
+        screen[7 * 32] = 0b1000;
+
+        let braille = render_screen(&screen, Render::Braille, 1);
+        assert_eq!(braille.len(), 64);
+        assert_eq!(braille[0].chars().count(), 256);
+        assert_eq!(braille[0].chars().next(), Some('⠁'));
+        assert_eq!(braille[1].chars().nth(1), Some('⢀'));
+
+        let half = render_screen(&screen, Render::HalfBlock, 4);
+        assert_eq!((half.len(), half[0].chars().count()), (32, 128));
+        assert_eq!(half[0].chars().next(), Some('█'));
+        assert!(half[1..].iter().all(|line| line.trim().is_empty()));
+    }
+
+    #[test]
+    fn maps_terminal_keys_to_hack_codes() {
+        assert_eq!(key_code(Key::Char('a')), Some(97));
+        assert_eq!(key_code(Key::Char('\n')), Some(128));
+        assert_eq!(key_code(Key::Backspace), Some(129));
+        assert_eq!(key_code(Key::Left), Some(130));
+        assert_eq!(key_code(Key::Down), Some(133));
+        assert_eq!(key_code(Key::Esc), Some(140));
+        assert_eq!(key_code(Key::F(12)), Some(152));
+        assert_eq!(key_code(Key::Char('\t')), None);
+        assert_eq!(key_code(Key::Ctrl('x')), None);
+    }
+
+    #[test]
+    fn shows_the_current_instruction_with_its_label() {
+        let program = rom::assemble("@3\nD=A\n(LOOP)\n@LOOP\n0;JMP\n", "t.asm").unwrap();
+        let mut machine = Machine::new(&program.words);
+        machine.run(3);
+        let lines = status(&machine, &program);
+        assert!(lines[0].starts_with("A=2 "), "{}", lines[0]);
+        assert!(lines[0].contains("D=3 "), "{}", lines[0]);
+        assert_eq!(
+            lines[1].split_whitespace().collect::<Vec<_>>(),
+            ["3", "LOOP+1", "0;JMP"]
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use crate::error::TranslateError;
+use crate::parser::{ParsedCommand, Segment, SourceFile, VmCommand};
+use std::collections::{HashMap, HashSet};
+
+/// largest value that `push constant` can load with a single A-instruction
+const MAX_CONSTANT: u16 = 32767;
+
+/// number of registers in the temp segment (RAM[5]..RAM[12])
+const TEMP_SIZE: u16 = 8;
+
+/// checks the whole translation set before any code is generated
+/// files: every parsed .vm file that is translated together
+/// mult_files: whether the program is made of several files, where all code must live in
+/// functions; `generate` sets it for more than one file, whether they were given one by one or
+/// as a directory, so a directory holding a single file may keep code outside of functions
+/// bootstrap: whether the bootstrap code is emitted, which calls `Sys.init`
+pub fn check(
+    files: &[SourceFile],
+    mult_files: bool,
+    bootstrap: bool,
+) -> Result<(), TranslateError> {
+    // every function defined anywhere in the translation set
+    let functions: HashSet<&str> = files
+        .iter()
+        .flat_map(|file| file.program.iter())
+        .filter_map(|parsed| match &parsed.command {
+            VmCommand::Function { name, .. } => Some(name.as_str()),
+            _ => None,
+        })
+        .collect();
+
+    // the file stem names the static variables of a file, so two files may not share it
+    let mut names: HashMap<&str, &str> = HashMap::new();
+    for file in files {
+        if let Some(other) = names.insert(&file.name, &file.path) {
+            return Err(TranslateError::program(format!(
+                "`{}` and `{}` have the same name `{}`, which names their static variables",
+                other, file.path, file.name
+            )));
+        }
+    }
+
+    if bootstrap && !functions.contains("Sys.init") {
+        return Err(TranslateError::program(
+            "the bootstrap code calls `Sys.init`, which is not defined in any input file"
+                .to_string(),
+        ));
+    }
+    for file in files {
+        check_file(file, &functions, mult_files)?;
+    }
+    Ok(())
+}
+
+/// checks the commands of a single file
+fn check_file(
+    file: &SourceFile,
+    functions: &HashSet<&str>,
+    mult_files: bool,
+) -> Result<(), TranslateError> {
+    let error = |line, message| TranslateError::source(&file.path, line, message);
+
+    // labels defined in and jumps made from the current function,
+    // code before the first function declaration forms its own scope
+    let mut labels: HashSet<&str> = HashSet::new();
+    let mut jumps: Vec<&ParsedCommand> = Vec::new();
+    let mut in_function = false;
+
+    for parsed in &file.program {
+        check_command(&parsed.command, functions).map_err(|message| error(parsed.line, message))?;
+
+        match &parsed.command {
+            VmCommand::Function { .. } => {
+                check_jumps(&labels, &jumps).map_err(|(line, message)| error(line, message))?;
+                labels.clear();
+                jumps.clear();
+                in_function = true;
+            }
+            _ if mult_files && !in_function => {
+                return Err(error(
+                    parsed.line,
+                    "command outside of any function".to_string(),
+                ));
+            }
+            VmCommand::Label(label) if labels.contains(label.as_str()) => {
+                return Err(error(
+                    parsed.line,
+                    format!(
+                        "label `{}` is defined more than once in this function",
+                        label
+                    ),
+                ));
+            }
+            VmCommand::Label(label) => {
+                labels.insert(label);
+            }
+            VmCommand::Goto(_) | VmCommand::IfGoto(_) => jumps.push(parsed),
+            _ => {}
+        }
+    }
+    check_jumps(&labels, &jumps).map_err(|(line, message)| error(line, message))
+}
+
+/// checks the operands of a single command
+fn check_command(command: &VmCommand, functions: &HashSet<&str>) -> Result<(), String> {
+    match command {
+        VmCommand::Push { segment, index } | VmCommand::Pop { segment, index } => {
+            let is_pop = matches!(command, VmCommand::Pop { .. });
+            match segment {
+                Segment::Constant if is_pop => {
+                    Err("cannot pop to the constant segment".to_string())
+                }
+                Segment::Constant if *index > MAX_CONSTANT => Err(format!(
+                    "constant {} is out of range 0-{}",
+                    index, MAX_CONSTANT
+                )),
+                Segment::Pointer if *index > 1 => Err(format!(
+                    "pointer index {} is out of range, expected 0 (THIS) or 1 (THAT)",
+                    index
+                )),
+                Segment::Temp if *index >= TEMP_SIZE => Err(format!(
+                    "temp index {} is out of range 0-{}",
+                    index,
+                    TEMP_SIZE - 1
+                )),
+                _ => Ok(()),
+            }
+        }
+        VmCommand::Call { name, .. } if !functions.contains(name.as_str()) => Err(format!(
+            "call to function `{}` which is not defined in any input file",
+            name
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// checks that every goto/if-goto of a function targets a label defined in that function
+/// returns the line and message of the first offending jump
+fn check_jumps(labels: &HashSet<&str>, jumps: &[&ParsedCommand]) -> Result<(), (usize, String)> {
+    for jump in jumps {
+        if let VmCommand::Goto(label) | VmCommand::IfGoto(label) = &jump.command {
+            if !labels.contains(label.as_str()) {
+                return Err((
+                    jump.line,
+                    format!(
+                        "jump to label `{}` which is not defined in this function",
+                        label
+                    ),
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser, tokenizer};
+
+    /// parses `(name, source)` files
+    fn parse_sources(files: &[(&str, &str)]) -> Vec<SourceFile> {
+        files
+            .iter()
+            .map(|(name, source)| {
+                let lines =
+                    tokenizer::remove_comments(source.lines().map(str::to_string).collect());
+                SourceFile {
+                    path: name.to_string(),
+                    name: name
+                        .rsplit('/')
+                        .next()
+                        .unwrap()
+                        .trim_end_matches(".vm")
+                        .to_string(),
+                    program: parser::parse(lines, name).unwrap(),
+                }
+            })
+            .collect()
+    }
+
+    /// checks `(name, source)` files translated together, without the bootstrap code
+    fn check_sources(files: &[(&str, &str)]) -> Result<(), String> {
+        let files = parse_sources(files);
+        check(&files, files.len() > 1, false).map_err(|err| err.to_string())
+    }
+
+    fn error(source: &str) -> String {
+        check_sources(&[("Main.vm", source)]).unwrap_err()
+    }
+
+    #[test]
+    fn rejects_operands_out_of_range() {
+        assert_eq!(
+            error("push constant 1\npop constant 0\n"),
+            "Main.vm:2: cannot pop to the constant segment"
+        );
+        assert_eq!(
+            error("push pointer 2\n"),
+            "Main.vm:1: pointer index 2 is out of range, expected 0 (THIS) or 1 (THAT)"
+        );
+        assert_eq!(
+            error("pop temp 8\n"),
+            "Main.vm:1: temp index 8 is out of range 0-7"
+        );
+        assert_eq!(
+            error("push constant 32768\n"),
+            "Main.vm:1: constant 32768 is out of range 0-32767"
+        );
+        // the largest operands are fine
+        check_sources(&[(
+            "Main.vm",
+            "push constant 32767\npop pointer 1\npush temp 7\npop temp 7\n",
+        )])
+        .unwrap();
+    }
+
+    #[test]
+    fn rejects_jumps_to_labels_of_other_functions() {
+        assert_eq!(
+            error("function Main.f 0\nlabel LOOP\nfunction Main.g 0\ngoto LOOP\n"),
+            "Main.vm:4: jump to label `LOOP` which is not defined in this function"
+        );
+        assert_eq!(
+            error("if-goto NOWHERE\n"),
+            "Main.vm:1: jump to label `NOWHERE` which is not defined in this function"
+        );
+        assert_eq!(
+            error("function Main.f 0\nlabel L\nlabel L\n"),
+            "Main.vm:3: label `L` is defined more than once in this function"
+        );
+        // labels may be jumped to before they are defined
+        check_sources(&[("Main.vm", "function Main.f 0\ngoto END\nlabel END\n")]).unwrap();
+    }
+
+    #[test]
+    fn rejects_calls_to_undefined_functions() {
+        assert_eq!(
+            error("function Main.main 0\ncall Math.multiply 2\n"),
+            "Main.vm:2: call to function `Math.multiply` which is not defined in any input file"
+        );
+        // functions of the other files are known
+        check_sources(&[
+            ("Main.vm", "function Main.main 0\ncall Math.multiply 2\n"),
+            ("Math.vm", "function Math.multiply 0\nreturn\n"),
+        ])
+        .unwrap();
+    }
+
+    #[test]
+    fn requires_functions_only_with_several_files() {
+        let main = "push constant 1\nfunction Main.main 0\nreturn\n";
+        check_sources(&[("Main.vm", main)]).unwrap();
+        assert_eq!(
+            check_sources(&[
+                ("Sys.vm", "function Sys.init 0\nreturn\n"),
+                ("Main.vm", main)
+            ])
+            .unwrap_err(),
+            "Main.vm:1: command outside of any function"
+        );
+    }
+
+    #[test]
+    fn requires_sys_init_for_the_bootstrap_code() {
+        let files = parse_sources(&[("Main.vm", "function Main.main 0\nreturn\n")]);
+        let err = check(&files, false, true).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the bootstrap code calls `Sys.init`, which is not defined in any input file"
+        );
+        let files = parse_sources(&[("Sys.vm", "function Sys.init 0\nreturn\n")]);
+        check(&files, false, true).unwrap();
+    }
+
+    #[test]
+    fn rejects_files_with_the_same_name() {
+        let files = parse_sources(&[
+            ("a/Main.vm", "function Main.f 0\nreturn\n"),
+            ("b/Main.vm", "function Main.g 0\nreturn\n"),
+        ]);
+        assert_eq!(
+            check(&files, true, false).unwrap_err().to_string(),
+            "`a/Main.vm` and `b/Main.vm` have the same name `Main`, which names their static variables"
+        );
+    }
+}
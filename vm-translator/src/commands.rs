@@ -1,20 +1,22 @@
+use crate::parser::{ArithmeticOp, Segment};
+
 /// map VM segments to their corresponding assembler symbols
-pub fn segment_mapping(segment: String) -> String {
-    match segment.as_str() {
-        "local" => "LCL",
-        "argument" => "ARG",
-        "this" => "THIS",
-        "that" => "THAT",
-        _ => "error: invalid segment",
-    }
-    .to_string()
+pub fn segment_mapping(segment: Segment) -> Result<String, String> {
+    let symbol = match segment {
+        Segment::Local => "LCL",
+        Segment::Argument => "ARG",
+        Segment::This => "THIS",
+        Segment::That => "THAT",
+        _ => return Err(format!("segment `{}` has no base address", segment)),
+    };
+    Ok(symbol.to_string())
 }
 
 /// implementations of the VM's arithmetic commands
 /// command: the arithmetic command to be translated
 /// cnt: counter
-pub fn arithmetic_command(command: String, cnt: u32) -> String {
-    match command.as_str() {
+pub fn arithmetic_command(command: ArithmeticOp, cnt: u32) -> String {
+    match command {
         // add the top two elements on the stack
         // impl desc:
         // select A = stack pointer
@@ -25,7 +27,7 @@ pub fn arithmetic_command(command: String, cnt: u32) -> String {
         // add the value of D to the value of M
         // thus effectively adding both numbers previously pushed
         // to the stack
-        "add" => "@SP\n\
+        ArithmeticOp::Add => "@SP\n\
                   M=M-1\n\
                   A=M\n\
                   D=M\n\
@@ -36,7 +38,7 @@ pub fn arithmetic_command(command: String, cnt: u32) -> String {
         // subtract the top two elements on the stack
         // impl desc:
         // same as with add but subtract isntead of add
-        "sub" => "@SP\n\
+        ArithmeticOp::Sub => "@SP\n\
                   M=M-1\n\
                   A=M\n\
                   D=M\n\
@@ -45,7 +47,7 @@ pub fn arithmetic_command(command: String, cnt: u32) -> String {
             .to_string(),
 
         // negate the top element on the stack
-        "neg" => "@SP\n\
+        ArithmeticOp::Neg => "@SP\n\
                  A=M-1\n\
                  M=-M\n"
             .to_string(),
@@ -53,41 +55,41 @@ pub fn arithmetic_command(command: String, cnt: u32) -> String {
         // compare the top two elements on the stack
         // if they are equal, set the top element to -1
         // if they are not equal, set the top element to 0
-        "eq" => format!(
+        ArithmeticOp::Eq => format!(
             "@SP\n\
                     M=M-1\n\
                     A=M\n\
                     D=M\n\
                     A=A-1\n\
                     D=M-D\n\
-                    @ISEQUAL{0}\n\
+                    @__VM_EQ_TRUE{0}\n\
                     D;JEQ\n\
-                    @NOTEQUAL{0}\n\
+                    @__VM_EQ_FALSE{0}\n\
                     D;JNE\n\
-                    (ISEQUAL{0})\n\
+                    (__VM_EQ_TRUE{0})\n\
                     @SP\n\
                     A=M-1\n\
                     M=-1\n\
-                    @EQEND{0}\n\
+                    @__VM_EQ_END{0}\n\
                     0;JMP\n\
-                    (NOTEQUAL{0})\n\
+                    (__VM_EQ_FALSE{0})\n\
                     @SP\n\
                     A=M-1\n\
                     M=0\n\
-                    @EQEND{0}\n\
+                    @__VM_EQ_END{0}\n\
                     0;JMP\n\
-                    (EQEND{0})\n",
+                    (__VM_EQ_END{0})\n",
             cnt
         ),
 
         // negate the top element on the stack
-        "not" => "@SP\n\
+        ArithmeticOp::Not => "@SP\n\
                  A=M-1\n\
                  M=!M\n"
             .to_string(),
 
         // bitwise and the top two elements on the stack
-        "and" => "@SP\n\
+        ArithmeticOp::And => "@SP\n\
                 M=M-1\n\
                 A=M\n\
                 D=M\n\
@@ -96,7 +98,7 @@ pub fn arithmetic_command(command: String, cnt: u32) -> String {
             .to_string(),
 
         // bitwise or the top two elements on the stack
-        "or" => "@SP\n\
+        ArithmeticOp::Or => "@SP\n\
                 M=M-1\n\
                 A=M\n\
                 D=M\n\
@@ -108,97 +110,110 @@ pub fn arithmetic_command(command: String, cnt: u32) -> String {
         // if the first element is greater than the second
         // set the top element to -1
         // otherwise set the top element to 0
-        "gt" => format!(
-            "@SP\n\
-                    M=M-1\n\
-                    A=M\n\
-                    D=M\n\
-                    A=A-1\n\
-                    D=M-D\n\
-                    @ISGREATER_GT{0}\n\
-                    D;JGT\n\
-                    @ISLESS_GT{0}\n\
-                    D;JLE\n\
-                    (ISGREATER_GT{0})\n\
-                    @SP\n\
-                    A=M-1\n\
-                    M=-1\n\
-                    @GTEND{0}\n\
-                    0;JMP\n\
-                    (ISLESS_GT{0})\n\
-                    @SP\n\
-                    A=M-1\n\
-                    M=0\n\
-                    @GTEND{0}\n\
-                    0;JMP\n\
-                    (GTEND{0})\n",
-            cnt
-        ),
+        ArithmeticOp::Gt => signed_comparison("GT", "JGT", cnt),
 
         // compare the top two elements on the stack
         // if the first element is less than the second
         // set the top element to -1
         // otherwise set the top element to 0
-        "lt" => format!(
-            "@SP\n\
-                    M=M-1\n\
-                    A=M\n\
-                    D=M\n\
-                    A=A-1\n\
-                    D=M-D\n\
-                    @ISGREATER_LT{0}\n\
-                    D;JGE\n\
-                    @ISLESS_LT{0}\n\
-                    D;JLT\n\
-                    (ISGREATER_LT{0})\n\
-                    @SP\n\
-                    A=M-1\n\
-                    M=0\n\
-                    @LTEND{0}\n\
-                    0;JMP\n\
-                    (ISLESS_LT{0})\n\
-                    @SP\n\
-                    A=M-1\n\
-                    M=-1\n\
-                    @LTEND{0}\n\
-                    0;JMP\n\
-                    (LTEND{0})\n",
-            cnt
-        ),
+        ArithmeticOp::Lt => signed_comparison("LT", "JLT", cnt),
+    }
+}
 
-        _ => format!("error: invalid arithmetic command"),
+/// overflow-safe signed comparison of the top two elements on the stack,
+/// x (the first element) against y (the top element)
+/// x - y overflows when the operands have different signs, so the signs are checked first:
+/// if they differ the result follows from the signs alone,
+/// otherwise the subtraction is safe and its sign is tested with `jump`
+/// name: GT or LT, used in the labels
+/// jump: JGT or JLT, the jump taken on x - y when the comparison is true
+/// cnt: counter
+fn signed_comparison(name: &str, jump: &str, cnt: u32) -> String {
+    // where to go when x >= 0 and y < 0, and when x < 0 and y >= 0
+    let (x_pos_y_neg, x_neg_y_pos) = if name == "GT" {
+        ("TRUE", "FALSE")
+    } else {
+        ("FALSE", "TRUE")
+    };
+    format!(
+        "@SP\n\
+         M=M-1\n\
+         A=M\n\
+         D=M\n\
+         @__VM_{0}_YNEG{2}\n\
+         D;JLT\n\
+         @SP\n\
+         A=M-1\n\
+         D=M\n\
+         @__VM_{0}_{4}{2}\n\
+         D;JLT\n\
+         @__VM_{0}_SAMESIGN{2}\n\
+         0;JMP\n\
+         (__VM_{0}_YNEG{2})\n\
+         @SP\n\
+         A=M-1\n\
+         D=M\n\
+         @__VM_{0}_{3}{2}\n\
+         D;JGE\n\
+         (__VM_{0}_SAMESIGN{2})\n\
+         @SP\n\
+         A=M\n\
+         D=M\n\
+         A=A-1\n\
+         D=M-D\n\
+         @__VM_{0}_TRUE{2}\n\
+         D;{1}\n\
+         (__VM_{0}_FALSE{2})\n\
+         @SP\n\
+         A=M-1\n\
+         M=0\n\
+         @__VM_{0}_END{2}\n\
+         0;JMP\n\
+         (__VM_{0}_TRUE{2})\n\
+         @SP\n\
+         A=M-1\n\
+         M=-1\n\
+         (__VM_{0}_END{2})\n",
+        name, jump, cnt, x_pos_y_neg, x_neg_y_pos
+    )
+}
+
+/// the register a `pointer` index selects: 0 for THIS and 1 for THAT
+fn pointer_register(index: u16) -> Result<&'static str, String> {
+    match index {
+        0 => Ok("THIS"),
+        1 => Ok("THAT"),
+        _ => Err(format!("pointer index {} is out of range 0-1", index)),
     }
 }
 
 /// implementations of the VM's push command
 /// segment: the segment to be pushed to
-/// address: the address to be pushed
+/// index: the index in the segment
 /// filename: the name of the file
-pub fn push_command(segment: String, address: String, filename: String) -> String {
-    match segment.as_str() {
+pub fn push_command(segment: Segment, index: u16, filename: &str) -> Result<String, String> {
+    let asm = match segment {
         // match the four segments with an identical implementation
         // just allocate using the assembler's A command
         // to LCL, ARG, THIS, or THAT
-        "local" | "argument" | "this" | "that" => format!(
+        Segment::Local | Segment::Argument | Segment::This | Segment::That => format!(
             "@{0}\n\
                 D=A\n\
                 @{1}\n\
                 D=D+M\n\
-                @addr\n\
-                M=D\n\
-                A=M\n\
+                A=D\n\
                 D=M\n\
                 @SP\n\
                 A=M\n\
                 M=D\n\
                 @SP\n\
                 M=M+1\n",
-            address,
-            segment_mapping(segment.clone())
+            index,
+            segment_mapping(segment)?
         ),
 
         // push a constant to the stack
-        "constant" => format!(
+        Segment::Constant => format!(
             "@{0}\n\
                 D=A\n\
                 @SP\n\
@@ -206,11 +221,11 @@ pub fn push_command(segment: String, address: String, filename: String) -> Strin
                 M=D\n\
                 @SP\n\
                 M=M+1\n",
-            address
+            index
         ),
 
         // push a value to the temp segment
-        "temp" => format!(
+        Segment::Temp => format!(
             "@{0}\n\
                 D=A\n\
                 @5\n\
@@ -222,13 +237,13 @@ pub fn push_command(segment: String, address: String, filename: String) -> Strin
                 M=D\n\
                 @SP\n\
                 M=M+1\n",
-            address
+            index
         ),
 
         // push a value to the static segment
         // using the following syntax:
         // @{filename}.{address}
-        "static" => format!(
+        Segment::Static => format!(
             "@{1}.{0}\n\
                 D=M\n\
                 @SP\n\
@@ -236,122 +251,119 @@ pub fn push_command(segment: String, address: String, filename: String) -> Strin
                 M=D\n\
                 @SP\n\
                 M=M+1\n",
-            address,
-            filename.clone()
+            index, filename
         ),
 
         // push a value to the specified pointer segment
         // only 2 values are allowed: 0 (THIS) and 1 (THAT)
-        "pointer" => {
-            let memory_idx = if address == "0" { "THIS" } else { "THAT" };
-
-            format!(
-                "@{0}\n\
+        Segment::Pointer => format!(
+            "@{0}\n\
                 D=M\n\
                 @SP\n\
                 A=M\n\
                 M=D\n\
                 @SP\n\
                 M=M+1\n",
-                memory_idx
-            )
-        }
-        _ => format!("todo push segment!"),
-    }
+            pointer_register(index)?
+        ),
+    };
+    Ok(asm)
 }
 
 /// implementations of the VM's pop command
-pub fn pop_command(segment: String, address: String, filename: String) -> String {
-    match segment.as_str() {
+/// segment: the segment to be popped to
+/// index: the index in the segment
+/// filename: the name of the file
+pub fn pop_command(segment: Segment, index: u16, filename: &str) -> Result<String, String> {
+    let asm = match segment {
         // same as push but instead of pushing
         // we pop from the correct segment
-        "local" | "argument" | "this" | "that" => format!(
+        // the target address is kept in the R13 scratch register
+        // while the top of the stack is loaded into D
+        Segment::Local | Segment::Argument | Segment::This | Segment::That => format!(
             "@{0}\n\
                 D=A\n\
                 @{1}\n\
                 D=D+M\n\
-                @addr\n\
+                @R13\n\
                 M=D\n\
                 @SP\n\
                 M=M-1\n\
                 A=M\n\
                 D=M\n\
-                @addr\n\
+                @R13\n\
                 A=M\n\
                 M=D\n",
-            address,
-            segment_mapping(segment.clone())
+            index,
+            segment_mapping(segment)?
         ),
 
-        "temp" => format!(
+        Segment::Temp => format!(
             "@{0}\n\
                 D=A\n\
                 @5\n\
                 D=D+A\n\
-                @addr\n\
+                @R13\n\
                 M=D\n\
                 @SP\n\
                 M=M-1\n\
                 A=M\n\
                 D=M\n\
-                @addr\n\
+                @R13\n\
                 A=M\n\
                 M=D\n",
-            address
+            index
         ),
 
-        "static" => format!(
+        Segment::Static => format!(
             "@SP\n\
                 M=M-1\n\
                 A=M\n\
                 D=M\n\
                 @{1}.{0}\n\
                 M=D\n",
-            address,
-            filename.clone()
+            index, filename
         ),
 
-        "pointer" => {
-            let memory_idx = if address == "0" { "THIS" } else { "THAT" };
-
-            format!(
-                "@SP\n\
+        Segment::Pointer => format!(
+            "@SP\n\
                 M=M-1\n\
                 A=M\n\
                 D=M\n\
                 @{0}\n\
                 M=D\n",
-                memory_idx
-            )
-        }
-        _ => format!("todo push segment!"),
-    }
+            pointer_register(index)?
+        ),
+        Segment::Constant => return Err("cannot pop to the constant segment".to_string()),
+    };
+    Ok(asm)
 }
 
-/// implementations of the VM's branching commands
-/// arg1: the first argument (label, goto or if-goto)
-/// arg2: the second argument (label)
-pub fn branching_command(arg1: String, arg2: String) -> String {
-    match arg1.as_str() {
-        // (LABEL) command
-        "label" => format!("({})\n", arg2),
-        // goto command
-        "goto" => format!("@{}\n0;JMP\n", arg2),
-        // if-goto command
-        // jump if value on top of stack is not zero
-        "if-goto" => format!("@SP\nM=M-1\nA=M\nD=M\n@{}\nD;JNE\n", arg2),
-        _ => format!("error: invalid branch"),
-    }
+/// implementations of the VM's label command
+/// label: the label, already scoped to its function
+pub fn label_command(label: &str) -> String {
+    format!("({})\n", label)
+}
+
+/// implementations of the VM's goto command
+pub fn goto_command(label: &str) -> String {
+    format!("@{}\n0;JMP\n", label)
+}
+
+/// implementations of the VM's if-goto command
+/// jump if value on top of stack is not zero
+pub fn if_goto_command(label: &str) -> String {
+    format!("@SP\nM=M-1\nA=M\nD=M\n@{}\nD;JNE\n", label)
 }
 
 /// implementations of the VM's function call command
 /// fun: the function to be called
-/// args: the number of arguments
-/// cnt: counter
-pub fn funcall(fun: String, args: String, cnt: u32) -> String {
+/// n_args: the number of arguments
+/// ret_label: the label the callee returns to, unique in the whole program
+pub fn funcall(fun: &str, n_args: u16, ret_label: &str) -> String {
     format!(
         "// push return address\n\
-        @{0}_RETURN_{2}\n\
+        @{2}\n\
         D=A\n\
         @SP\n\
         A=M\n\
@@ -408,16 +420,17 @@ pub fn funcall(fun: String, args: String, cnt: u32) -> String {
         @{0}\n\
         0;JMP\n\
         // label\n\
-        ({0}_RETURN_{2})\n",
-        fun, args, cnt
+        ({2})\n",
+        fun, n_args, ret_label
     )
 }
 
 /// implementations of the VM's function declaration command
-pub fn fundecl(fun: String, args: String) -> String {
+/// n_locals: the number of local variables, initialized to 0
+pub fn fundecl(fun: &str, n_locals: u16) -> String {
     let local_init: &str = "@SP\nA=M\nM=0\n@SP\nM=M+1\n";
     let mut block: String = String::new();
-    for _i in 0..args.parse::<i32>().unwrap() {
+    for _i in 0..n_locals {
         block.push_str(local_init);
     }
     format!("// function declaration\n({0})\n{1}", fun, block)
@@ -425,20 +438,19 @@ pub fn fundecl(fun: String, args: String) -> String {
 
 /// implementations of the VM's function return command
 pub fn funret() -> String {
-    format!(
-        "// endframe = LCL\n\
+    "// endframe (R13) = LCL\n\
          @LCL\n\
          D=M\n\
-         @endframe\n\
+         @R13\n\
          M=D\n\
-         // retaddr = *(endframe - 5)\n\
-         @endframe\n\
+         // retaddr (R14) = *(endframe - 5)\n\
+         @R13\n\
          D=M\n\
          @5\n\
          D=D-A\n\
          A=D\n\
          D=M\n\
-         @retaddr\n\
+         @R14\n\
          M=D\n\
          // *ARG = pop()\n\
          @SP\n\
@@ -455,7 +467,7 @@ pub fn funret() -> String {
          @SP\n\
          M=D\n\
          // THAT = *(endframe - 1)\n\
-         @endframe\n\
+         @R13\n\
          D=M\n\
          @1\n\
          D=D-A\n\
@@ -464,7 +476,7 @@ pub fn funret() -> String {
          @THAT\n\
          M=D\n\
          // THIS = *(endframe - 2)\n\
-         @endframe\n\
+         @R13\n\
          D=M\n\
          @2\n\
          D=D-A\n\
@@ -473,7 +485,7 @@ pub fn funret() -> String {
          @THIS\n\
          M=D\n\
          // ARG = *(endframe - 3)\n\
-         @endframe\n\
+         @R13\n\
          D=M\n\
          @3\n\
          D=D-A\n\
@@ -482,7 +494,7 @@ pub fn funret() -> String {
          @ARG\n\
          M=D\n\
          // LCL = *(endframe - 4)\n\
-         @endframe\n\
+         @R13\n\
          D=M\n\
          @4\n\
          D=D-A\n\
@@ -491,10 +503,10 @@ pub fn funret() -> String {
          @LCL\n\
          M=D\n\
          // goto retaddr\n\
-         @retaddr\n\
+         @R14\n\
          A=M\n\
          0;JMP\n"
-    )
+        .to_string()
 }
 
 /// implementations of the VM's bootstrap code
@@ -503,8 +515,311 @@ pub fn bootstrap() -> String {
         "@256\n\
          D=A\n\
          @SP\n\
-         M=D\n
+         M=D\n\
          {}",
-        funcall("Sys.init".to_string(), "0".to_string(), 0)
+        funcall("Sys.init", 0, BOOTSTRAP_RETURN)
     )
 }
+
+/// the prefix of every label generated by the translator, which VM names may not start with,
+/// so that the labels of the program never collide with them
+pub const RESERVED_PREFIX: &str = "__VM_";
+
+/// label of the infinite loop that ends the program
+pub const END_LOOP: &str = "__VM_END";
+
+/// prefix of the return address labels, followed by the calling function and a counter,
+/// e.g. `__VM_RET$Main.main.0`
+pub const RETURN_LABEL_PREFIX: &str = "__VM_RET$";
+
+/// return address label of the bootstrap's call to `Sys.init`
+pub const BOOTSTRAP_RETURN: &str = "__VM_BOOTSTRAP_RET";
+
+/// label of the shared call routine used by the compact code generation
+pub const CALL_ROUTINE: &str = "__VM_CALL";
+
+/// label of the shared return routine used by the compact code generation
+pub const RETURN_ROUTINE: &str = "__VM_RETURN";
+
+/// label of the shared routine for a comparison command (eq, gt or lt)
+pub fn compare_routine_label(command: ArithmeticOp) -> String {
+    format!("{}{}", RESERVED_PREFIX, command.name().to_uppercase())
+}
+
+/// compact comparison: jump into the shared routine with the return address in D
+/// command: eq, gt or lt
+/// ret_label: the label the routine returns to
+pub fn compact_compare(command: ArithmeticOp, ret_label: &str) -> String {
+    format!(
+        "@{1}\n\
+         D=A\n\
+         @{0}\n\
+         0;JMP\n\
+         ({1})\n",
+        compare_routine_label(command),
+        ret_label
+    )
+}
+
+/// compact function call: jump into the shared call routine with
+/// the return address in D, the number of arguments in R13 and the callee in R14
+pub fn compact_funcall(fun: &str, n_args: u16, ret_label: &str) -> String {
+    format!(
+        "// call {0} {1}\n\
+        @{1}\n\
+        D=A\n\
+        @R13\n\
+        M=D\n\
+        @{0}\n\
+        D=A\n\
+        @R14\n\
+        M=D\n\
+        @{2}\n\
+        D=A\n\
+        @{3}\n\
+        0;JMP\n\
+        ({2})\n",
+        fun, n_args, ret_label, CALL_ROUTINE
+    )
+}
+
+/// compact function return: jump into the shared return routine
+pub fn compact_funret() -> String {
+    format!("// return\n@{}\n0;JMP\n", RETURN_ROUTINE)
+}
+
+/// shared comparison routine: compares the top two elements on the stack
+/// and returns to the address passed in D, which is kept in R15
+/// cnt: counter for the labels of the comparison itself
+pub fn compare_routine(command: ArithmeticOp, cnt: u32) -> String {
+    format!(
+        "({0})\n\
+         @R15\n\
+         M=D\n\
+         {1}\
+         @R15\n\
+         A=M\n\
+         0;JMP\n",
+        compare_routine_label(command),
+        arithmetic_command(command, cnt)
+    )
+}
+
+/// shared call routine: builds the caller's frame like `funcall`,
+/// taking the return address from D, the number of arguments from R13
+/// and the address of the callee from R14
+pub fn call_routine() -> String {
+    format!(
+        "// shared call routine\n\
+        ({0})\n\
+        // push return address\n\
+        @SP\n\
+        A=M\n\
+        M=D\n\
+        @SP\n\
+        M=M+1\n\
+        // push LCL\n\
+        @LCL\n\
+        D=M\n\
+        @SP\n\
+        A=M\n\
+        M=D\n\
+        @SP\n\
+        M=M+1\n\
+        // push ARG\n\
+        @ARG\n\
+        D=M\n\
+        @SP\n\
+        A=M\n\
+        M=D\n\
+        @SP\n\
+        M=M+1\n\
+        // push THIS\n\
+        @THIS\n\
+        D=M\n\
+        @SP\n\
+        A=M\n\
+        M=D\n\
+        @SP\n\
+        M=M+1\n\
+        // push THAT\n\
+        @THAT\n\
+        D=M\n\
+        @SP\n\
+        A=M\n\
+        M=D\n\
+        @SP\n\
+        M=M+1\n\
+        // ARG = SP - 5 - nArgs\n\
+        @SP\n\
+        D=M\n\
+        @5\n\
+        D=D-A\n\
+        @R13\n\
+        D=D-M\n\
+        @ARG\n\
+        M=D\n\
+        // LCL = SP\n\
+        @SP\n\
+        D=M\n\
+        @LCL\n\
+        M=D\n\
+        // goto callee\n\
+        @R14\n\
+        A=M\n\
+        0;JMP\n",
+        CALL_ROUTINE
+    )
+}
+
+/// shared return routine: the body of `funret` behind a label
+pub fn return_routine() -> String {
+    format!(
+        "// shared return routine\n({})\n{}",
+        RETURN_ROUTINE,
+        funret()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hack_emulator::{rom, Machine};
+
+    /// runs `asm` on the emulator for `cycles` instructions, after setting the RAM addresses of `ram`
+    fn execute(asm: &str, ram: &[(u16, i16)], cycles: u64) -> Machine {
+        let program = rom::assemble(asm, "test.asm").unwrap();
+        let mut machine = Machine::new(&program.words);
+        for &(address, value) in ram {
+            machine.poke(address, value as u16);
+        }
+        machine.run(cycles);
+        machine
+    }
+
+    /// runs an arithmetic command on the stack [x, y] and returns the result it leaves
+    fn run_comparison(command: ArithmeticOp, x: i16, y: i16) -> i16 {
+        let asm = arithmetic_command(command, 0);
+        // the code only jumps forward, so it runs at most one cycle per line
+        let machine = execute(
+            &asm,
+            &[(0, 258), (256, x), (257, y)],
+            asm.lines().count() as u64,
+        );
+        assert_eq!(machine.peek(0), 257, "{} should pop one element", command);
+        machine.peek(256) as i16
+    }
+
+    /// values around zero and at both ends of the 16-bit range
+    const BOUNDARY_VALUES: [i16; 9] = [
+        i16::MIN,
+        i16::MIN + 1,
+        -2,
+        -1,
+        0,
+        1,
+        2,
+        i16::MAX - 1,
+        i16::MAX,
+    ];
+
+    #[test]
+    fn comparisons_are_correct_at_boundary_values() {
+        for x in BOUNDARY_VALUES {
+            for y in BOUNDARY_VALUES {
+                let expected = |result: bool| if result { -1 } else { 0 };
+                assert_eq!(
+                    run_comparison(ArithmeticOp::Gt, x, y),
+                    expected(x > y),
+                    "{} gt {}",
+                    x,
+                    y
+                );
+                assert_eq!(
+                    run_comparison(ArithmeticOp::Lt, x, y),
+                    expected(x < y),
+                    "{} lt {}",
+                    x,
+                    y
+                );
+                assert_eq!(
+                    run_comparison(ArithmeticOp::Eq, x, y),
+                    expected(x == y),
+                    "{} eq {}",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn gt_does_not_overflow_on_opposite_signs() {
+        // push constant 32767; neg; push constant 2; gt
+        assert_eq!(run_comparison(ArithmeticOp::Gt, -32767, 2), 0);
+        assert_eq!(run_comparison(ArithmeticOp::Lt, -32767, 2), -1);
+        assert_eq!(run_comparison(ArithmeticOp::Gt, 32767, -2), -1);
+        assert_eq!(run_comparison(ArithmeticOp::Lt, 32767, -2), 0);
+    }
+
+    #[test]
+    fn pointer_selects_this_or_that() {
+        assert!(push_command(Segment::Pointer, 0, "Main")
+            .unwrap()
+            .starts_with("@THIS\n"));
+        assert!(pop_command(Segment::Pointer, 1, "Main")
+            .unwrap()
+            .ends_with("@THAT\nM=D\n"));
+        assert_eq!(
+            push_command(Segment::Pointer, 2, "Main").unwrap_err(),
+            "pointer index 2 is out of range 0-1"
+        );
+    }
+
+    /// runs `f(5)` and `f(9)` from `Main.main`, where `f(x)` returns `x < 7`,
+    /// with the inline or the compact code for call, return and lt
+    fn run_calls(compact: bool) -> Vec<i16> {
+        let push = |value: u16| push_command(Segment::Constant, value, "Main");
+        let call = |ret_label: &str| {
+            if compact {
+                compact_funcall("Main.f", 1, ret_label)
+            } else {
+                funcall("Main.f", 1, ret_label)
+            }
+        };
+        let mut asm = String::new();
+        for (value, ret_label) in [(5, "__VM_RET$Main.main.0"), (9, "__VM_RET$Main.main.1")] {
+            asm += &push(value).unwrap();
+            asm += &call(ret_label);
+        }
+        asm += "@DONE\n0;JMP\n";
+        asm += &fundecl("Main.f", 0);
+        asm += &push_command(Segment::Argument, 0, "Main").unwrap();
+        asm += &push(7).unwrap();
+        if compact {
+            asm += &compact_compare(ArithmeticOp::Lt, "__VM_LT_RET0");
+            asm += &compact_funret();
+            asm += &call_routine();
+            asm += &return_routine();
+            asm += &compare_routine(ArithmeticOp::Lt, 0);
+        } else {
+            asm += &arithmetic_command(ArithmeticOp::Lt, 0);
+            asm += &funret();
+        }
+        asm += "(DONE)\n";
+
+        // SP, LCL, ARG, THIS and THAT of the caller
+        let ram = [(0, 256), (1, 300), (2, 400), (3, 3000), (4, 4000)];
+        let machine = execute(&asm, &ram, 1000);
+        [0, 1, 2, 3, 4, 256, 257]
+            .map(|address| machine.peek(address) as i16)
+            .to_vec()
+    }
+
+    #[test]
+    fn compact_calls_returns_and_comparisons_match_the_inline_code() {
+        let expected = [258, 300, 400, 3000, 4000, -1, 0];
+        assert_eq!(run_calls(false), expected);
+        assert_eq!(run_calls(true), expected);
+    }
+}
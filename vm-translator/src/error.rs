@@ -0,0 +1,87 @@
+use std::fmt;
+use std::io;
+
+/// an error that stops the translation
+#[derive(Debug)]
+pub enum TranslateError {
+    /// the input program is invalid at the given line
+    Source {
+        /// the file the error was found in
+        file: String,
+        /// 1-based line number of the offending command
+        line: usize,
+        /// human readable description
+        message: String,
+    },
+    /// the program as a whole is invalid, e.g. a function it needs is defined nowhere
+    Program {
+        /// human readable description
+        message: String,
+    },
+    /// the generated code that no VM command was translated from failed to assemble
+    Assembly {
+        /// 1-based line number in the generated assembly
+        line: usize,
+        /// the assembler's description
+        message: String,
+    },
+    /// an input could not be read or the output could not be written
+    Io {
+        /// the path that was being accessed
+        path: String,
+        /// the underlying error
+        source: io::Error,
+    },
+}
+
+impl TranslateError {
+    /// `TranslateError::source()`: constructor for an error in the input program
+    pub fn source(file: &str, line: usize, message: String) -> Self {
+        TranslateError::Source {
+            file: file.to_string(),
+            line,
+            message,
+        }
+    }
+
+    /// `TranslateError::program()`: constructor for an error in the program as a whole
+    pub fn program(message: String) -> Self {
+        TranslateError::Program { message }
+    }
+
+    /// `TranslateError::io()`: constructor for an error while accessing `path`
+    pub fn io(path: &str, source: io::Error) -> Self {
+        TranslateError::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+}
+
+impl fmt::Display for TranslateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TranslateError::Source {
+                file,
+                line,
+                message,
+            } => write!(f, "{}:{}: {}", file, line, message),
+            TranslateError::Program { message } => write!(f, "{}", message),
+            TranslateError::Assembly { line, message } => {
+                write!(f, "generated assembly line {}: {}", line, message)
+            }
+            TranslateError::Io { path, source } => write!(f, "{}: {}", path, source),
+        }
+    }
+}
+
+impl std::error::Error for TranslateError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TranslateError::Source { .. }
+            | TranslateError::Program { .. }
+            | TranslateError::Assembly { .. } => None,
+            TranslateError::Io { source, .. } => Some(source),
+        }
+    }
+}
@@ -1,115 +1,311 @@
 use crate::commands;
-use std::fs::File;
-use std::io::prelude::*;
+use crate::error::TranslateError;
+use crate::parser::{ArithmeticOp, ParsedCommand, SourceFile, VmCommand};
+use clap::ValueEnum;
+use std::collections::HashMap;
+use std::path::Path;
 
-/// Partition a string into a vector of strings represting the instruction
-pub fn partition_instr(instruction: String) -> Vec<String> {
-    instruction.split(' ').map(str::to_string).collect()
+/// when to emit the bootstrap code that sets SP and calls `Sys.init`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BootstrapPolicy {
+    /// only if one of the input files defines `Sys.init`
+    #[default]
+    Auto,
+    /// always, the checker then requires `Sys.init` to be defined
+    Always,
+    /// never, for programs that are run from a test script setting SP themselves
+    Never,
 }
 
-/// Return the index of the boolean command
-pub fn boolean_index(command: String) -> usize {
-    match command.as_str() {
-        "eq" => 0,
-        "gt" => 1,
-        "lt" => 2,
-        _ => {
-            println!("error: invalid command");
-            3
+impl BootstrapPolicy {
+    /// `BootstrapPolicy.emits_bootstrap()`: whether `files` get the bootstrap code
+    pub fn emits_bootstrap(self, files: &[SourceFile]) -> bool {
+        match self {
+            BootstrapPolicy::Auto => files.iter().any(|file| {
+                file.program.iter().any(|parsed| {
+                    matches!(&parsed.command, VmCommand::Function { name, .. } if name == "Sys.init")
+                })
+            }),
+            BootstrapPolicy::Always => true,
+            BootstrapPolicy::Never => false,
         }
     }
 }
 
-/// parses the contents of the .vm file and generates the assembly code
-pub fn asm(contents: Vec<String>, filename: String, mult_files: bool) -> Vec<String> {
-    let mut asm_code: Vec<String> = Vec::new();
-    let mut boolean_cnt: [u32; 3] = [0; 3];
-    let mut call_cnt: u32 = 1;
+/// options that change the code generated for a whole program
+/// `optimize`, `peephole` and `annotate` are applied by the library functions, around `program`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    /// when to emit the bootstrap code
+    pub bootstrap: BootstrapPolicy,
+    /// share a single call, return and comparison routine between all sites
+    /// instead of expanding them inline, to save ROM
+    pub compact: bool,
+    /// optimize the VM commands before generating code
+    pub optimize: bool,
+    /// remove redundant instructions from the generated code
+    pub peephole: bool,
+    /// prefix the code of every VM command with a comment naming it
+    pub annotate: bool,
+}
+
+/// the VM command a block of generated code was translated from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// the path of the .vm file
+    pub file: String,
+    /// 1-based line number of the command
+    pub line: usize,
+    /// the command as written in the .vm file, e.g. `push local 2`
+    pub command: String,
+}
+
+/// a block of generated code, ending with a newline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Block {
+    /// the command it was translated from, none for the bootstrap code,
+    /// the final infinite loop and the shared routines
+    pub origin: Option<Origin>,
+    /// the assembly code
+    pub code: String,
+}
+
+impl Block {
+    /// `Block::generated()`: constructor for code that no VM command was translated from
+    pub fn generated(code: String) -> Self {
+        Block { origin: None, code }
+    }
+}
+
+/// translation state shared by every file of a program,
+/// so that generated labels are unique across all of them
+#[derive(Debug, Default)]
+pub struct Context {
+    /// number of eq, gt and lt commands translated so far
+    boolean_cnt: [u32; 3],
+    /// number of calls translated so far, per calling function
+    call_cnt: HashMap<String, u32>,
+    /// whether calls, returns and comparisons jump into shared routines
+    compact: bool,
+    /// whether the shared call routine has been jumped to
+    used_call: bool,
+    /// whether the shared return routine has been jumped to
+    used_return: bool,
+    /// whether the shared eq, gt and lt routines have been jumped to
+    used_compare: [bool; 3],
+}
+
+impl Context {
+    /// `Context.new()`: constructor
+    pub fn new(compact: bool) -> Self {
+        Context {
+            compact,
+            ..Self::default()
+        }
+    }
 
-    if mult_files == true {
-        asm_code.push(commands::bootstrap());
+    /// `Context.next_boolean()`: returns the next free counter for the comparison command
+    /// at `idx` in `ArithmeticOp::COMPARISONS`
+    fn next_boolean(&mut self, idx: usize) -> u32 {
+        let cnt = self.boolean_cnt[idx];
+        self.boolean_cnt[idx] += 1;
+        cnt
     }
 
-    for line in contents {
-        let instruction: Vec<String> = partition_instr(line);
-        match instruction.len() {
-            1 => {
+    /// `Context.return_label()`: returns the next return address label for a call made
+    /// from `caller`, e.g. `__VM_RET$Main.main.0`, under the reserved prefix so that
+    /// the labels of the program, such as `Main.main$ret.0`, never collide with it
+    fn return_label(&mut self, caller: &str) -> String {
+        let cnt = self.call_cnt.entry(caller.to_string()).or_insert(0);
+        let label = format!("{}{}.{}", commands::RETURN_LABEL_PREFIX, caller, cnt);
+        *cnt += 1;
+        label
+    }
+
+    /// `Context.runtime()`: returns the shared routines used by the compact code generation
+    fn runtime(&mut self) -> Vec<String> {
+        let mut routines = Vec::new();
+        if self.used_call {
+            routines.push(commands::call_routine());
+        }
+        if self.used_return {
+            routines.push(commands::return_routine());
+        }
+        for (idx, command) in ArithmeticOp::COMPARISONS.into_iter().enumerate() {
+            if self.used_compare[idx] {
+                let cnt = self.next_boolean(idx);
+                routines.push(commands::compare_routine(command, cnt));
+            }
+        }
+        routines
+    }
+}
+
+/// scope a label to the function it appears in, as `FunctionName$label`
+/// labels outside of any function are left as they are
+pub fn scoped_label(function: &Option<String>, label: String) -> String {
+    match function {
+        Some(function) => format!("{}${}", function, label),
+        None => label,
+    }
+}
+
+/// generates the assembly code for the parsed commands of a .vm file, one block per command
+/// path: the path of the .vm file, used in error messages and as the origin of the blocks
+/// ctx: state shared with the other files of the program
+pub fn asm(
+    program: Vec<ParsedCommand>,
+    filename: String,
+    path: &str,
+    ctx: &mut Context,
+) -> Result<Vec<Block>, TranslateError> {
+    let mut asm_code: Vec<Block> = Vec::new();
+    // the function being translated, used to scope its labels
+    let mut cur_function: Option<String> = None;
+
+    for parsed in program {
+        let line = parsed.line;
+        let error = |message| TranslateError::source(path, line, message);
+        let command = parsed.command.to_string();
+        let code = match parsed.command {
+            VmCommand::Arithmetic(command) => {
                 // if it is a comparison command
-                // push the correct command to the asm_code vector
-                if instruction[0] == "eq" || instruction[0] == "gt" || instruction[0] == "lt" {
-                    asm_code.push(commands::arithmetic_command(
-                        instruction[0].clone(),
-                        boolean_cnt[boolean_index(instruction[0].clone())],
-                    ));
-                    boolean_cnt[boolean_index(instruction[0].clone())] += 1;
-                // if it is a return command
-                } else if instruction[0] == "return" {
-                    asm_code.push(commands::funret());
-                // if it is an arithmetic command
+                // translate it with the next free label counter
+                if let Some(idx) = command.comparison_index() {
+                    let cnt = ctx.next_boolean(idx);
+                    if ctx.compact {
+                        ctx.used_compare[idx] = true;
+                        let name = command.name().to_uppercase();
+                        let ret_label = format!("{}{}_RET{}", commands::RESERVED_PREFIX, name, cnt);
+                        commands::compact_compare(command, &ret_label)
+                    } else {
+                        commands::arithmetic_command(command, cnt)
+                    }
+                // if it is any other arithmetic command
                 } else {
-                    asm_code.push(commands::arithmetic_command(instruction[0].clone(), 0));
+                    commands::arithmetic_command(command, 0)
                 }
             }
 
-            // if it s a branching command
-            2 => {
-                asm_code.push(commands::branching_command(
-                    instruction[0].clone(),
-                    instruction[1].clone(),
-                ));
+            VmCommand::Push { segment, index } => {
+                commands::push_command(segment, index, &filename).map_err(error)?
+            }
+
+            VmCommand::Pop { segment, index } => {
+                commands::pop_command(segment, index, &filename).map_err(error)?
+            }
+
+            // branching commands
+            VmCommand::Label(label) => commands::label_command(&scoped_label(&cur_function, label)),
+            VmCommand::Goto(label) => commands::goto_command(&scoped_label(&cur_function, label)),
+            VmCommand::IfGoto(label) => {
+                commands::if_goto_command(&scoped_label(&cur_function, label))
+            }
+
+            VmCommand::Function { name, n_locals } => {
+                let code = commands::fundecl(&name, n_locals);
+                cur_function = Some(name);
+                code
+            }
+
+            VmCommand::Call { name, n_args } => {
+                // code outside of any function is named after its file
+                let caller = cur_function.as_ref().unwrap_or(&filename);
+                let ret_label = ctx.return_label(caller);
+                if ctx.compact {
+                    ctx.used_call = true;
+                    commands::compact_funcall(&name, n_args, &ret_label)
+                } else {
+                    commands::funcall(&name, n_args, &ret_label)
+                }
             }
 
-            // if it is a push, pop, call, or function command
-            3 => {
-                if instruction[0] == "push" {
-                    asm_code.push(commands::push_command(
-                        instruction[1].clone(),
-                        instruction[2].clone(),
-                        filename.clone(),
-                    ));
-                } else if instruction[0] == "pop" {
-                    asm_code.push(commands::pop_command(
-                        instruction[1].clone(),
-                        instruction[2].clone(),
-                        filename.clone(),
-                    ));
-                } else if instruction[0] == "call" {
-                    asm_code.push(commands::funcall(
-                        instruction[1].clone(),
-                        instruction[2].clone(),
-                        call_cnt,
-                    ));
-                    call_cnt += 1;
+            VmCommand::Return => {
+                if ctx.compact {
+                    ctx.used_return = true;
+                    commands::compact_funret()
                 } else {
-                    asm_code.push(commands::fundecl(
-                        instruction[1].clone(),
-                        instruction[2].clone(),
-                    ));
+                    commands::funret()
                 }
             }
+        };
+        asm_code.push(Block {
+            origin: Some(Origin {
+                file: path.to_string(),
+                line,
+                command,
+            }),
+            code,
+        });
+    }
+    Ok(asm_code)
+}
+
+/// generates the assembly code for a whole program: the bootstrap code if requested,
+/// every file in order and a single infinite loop at the end,
+/// followed by the shared routines in compact mode
+pub fn program(files: Vec<SourceFile>, options: &Options) -> Result<Vec<Block>, TranslateError> {
+    let mut asm_code: Vec<Block> = Vec::new();
+    let mut ctx = Context::new(options.compact);
+
+    if options.bootstrap.emits_bootstrap(&files) {
+        asm_code.push(Block::generated(commands::bootstrap()));
+    }
+
+    for file in files {
+        asm_code.append(&mut asm(file.program, file.name, &file.path, &mut ctx)?);
+    }
 
-            _ => println!(" "),
+    // add the infinite loop to the end of the program
+    asm_code.push(Block::generated(format!(
+        "({0})\n@{0}\n0;JMP\n",
+        commands::END_LOOP
+    )));
+
+    // the shared routines are only reached by jumps, so they go after the infinite loop
+    asm_code.extend(ctx.runtime().into_iter().map(Block::generated));
+    Ok(asm_code)
+}
+
+/// prefixes every block translated from a VM command with a `// File.vm:LINE: command` comment
+pub fn annotate(asm: &mut [Block]) {
+    for block in asm {
+        if let Some(origin) = &block.origin {
+            let file = Path::new(&origin.file)
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let comment = format!("// {}:{}: {}\n", file, origin.line, origin.command);
+            block.code.insert_str(0, &comment);
         }
     }
-    // add the infinite loop to the end of the file
-    asm_code.push(format!("(END)\n@END\n0;JMP\n"));
-    asm_code
 }
 
-/// write the output asm code to a file
-pub fn write_output(asm: Vec<String>, mut filename: String) -> std::io::Result<()> {
-    let outpath: String;
-    if filename.ends_with(".vm") {
-        outpath = filename.replace(".vm", ".asm");
-    } else {
-        filename.push_str(".asm");
-        outpath = filename;
-    };
-    let mut output = File::create(outpath)?;
-    for line in asm {
-        write!(output, "{}", line)
-            .map_err(|err| println!("{:?}", err))
-            .ok();
+/// count the instructions in the generated code, i.e. the ROM words it occupies
+/// labels, comments and empty lines take no space
+pub fn rom_words(asm: &[Block]) -> usize {
+    asm.iter()
+        .flat_map(|block| block.code.lines())
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with("//") && !line.starts_with('('))
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn return_labels_are_reserved_and_numbered_per_caller() {
+        let mut ctx = Context::default();
+        assert_eq!(ctx.return_label("Main.main"), "__VM_RET$Main.main.0");
+        assert_eq!(ctx.return_label("Main.main"), "__VM_RET$Main.main.1");
+        // a function named like the bootstrap gets its own labels
+        assert_eq!(ctx.return_label("Bootstrap"), "__VM_RET$Bootstrap.0");
+        assert!(commands::bootstrap().contains(&format!("({})\n", commands::BOOTSTRAP_RETURN)));
+        // `ret.0` is the spec's name for the first return address, a label of the program
+        // with that name is scoped to its function instead
+        let label = scoped_label(&Some("Main.main".to_string()), "ret.0".to_string());
+        assert_eq!(label, "Main.main$ret.0");
     }
-    Ok(())
 }
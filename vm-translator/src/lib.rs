@@ -0,0 +1,445 @@
+use std::io::{self, prelude::*};
+use std::path::Path;
+mod checker;
+mod commands;
+mod error;
+mod generate_asm;
+mod optimizer;
+mod parser;
+mod peephole;
+mod source_map;
+mod tokenizer;
+pub use error::TranslateError;
+use generate_asm::Block;
+pub use generate_asm::{BootstrapPolicy, Options, Origin};
+use hack_assembler::Assembler;
+pub use optimizer::Stats;
+use parser::SourceFile;
+pub use source_map::{MapLine, SourceMap};
+
+/// what the optional passes of a translation did
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Report {
+    /// what the VM-level optimizer did, if it ran
+    pub optimizer: Option<Stats>,
+    /// ROM words used by the compact code and by the inline expansion, in compact mode
+    pub compact: Option<(usize, usize)>,
+    /// ROM words removed by the peephole optimizer, if it ran
+    pub peephole: Option<usize>,
+}
+
+/// translates in-memory .vm sources into a single .asm program with the default options
+/// files: `(name, source)` pairs in translation order, where the name is the path
+/// used in error messages and its file stem names the static variables, e.g. `Main.vm`;
+/// with more than one file, all code must be inside functions
+pub fn translate<N: AsRef<str>, S: AsRef<str>>(files: &[(N, S)]) -> Result<String, TranslateError> {
+    let (asm_code, _) = generate(files, &Options::default())?;
+    Ok(asm_code.into_iter().map(|block| block.code).collect())
+}
+
+/// translates in-memory .vm sources and writes the .asm program to `output` block by block,
+/// nothing is written unless every file translated successfully
+/// files: `(name, source)` pairs, as for `translate`
+/// output_name: the name of the output used in error messages, e.g. its path
+pub fn translate_to<N: AsRef<str>, S: AsRef<str>, W: Write>(
+    files: &[(N, S)],
+    options: &Options,
+    mut output: W,
+    output_name: &str,
+) -> Result<Report, TranslateError> {
+    let (asm_code, report) = generate(files, options)?;
+    let error = |err| TranslateError::io(output_name, err);
+    for block in asm_code {
+        output.write_all(block.code.as_bytes()).map_err(error)?;
+    }
+    output.flush().map_err(error)?;
+    Ok(report)
+}
+
+/// a translated program, for callers that want more than its text
+#[derive(Debug, Clone)]
+pub struct Translation {
+    /// the .asm program
+    pub asm: String,
+    /// relates the lines of `asm` to the VM commands they were translated from
+    pub source_map: SourceMap,
+    /// what the optional passes did
+    pub report: Report,
+}
+
+impl Translation {
+    /// `Translation.assemble()`: assembles the program with `hack_assembler` into .hack machine code
+    /// errors of the assembler are reported at the VM command the offending code was translated from
+    pub fn assemble(&self) -> Result<String, TranslateError> {
+        Assembler::new(&self.asm).assemble().map_err(|err| {
+            match self
+                .source_map
+                .line(err.line)
+                .and_then(|line| line.origin.as_ref())
+            {
+                Some(origin) => TranslateError::source(&origin.file, origin.line, err.message),
+                None => TranslateError::Assembly {
+                    line: err.line,
+                    message: err.message,
+                },
+            }
+        })
+    }
+}
+
+/// translates in-memory .vm sources into a `Translation` with its source map
+/// files: `(name, source)` pairs, as for `translate`
+pub fn translate_program<N: AsRef<str>, S: AsRef<str>>(
+    files: &[(N, S)],
+    options: &Options,
+) -> Result<Translation, TranslateError> {
+    let (asm_code, report) = generate(files, options)?;
+    Ok(Translation {
+        asm: asm_code.iter().map(|block| block.code.as_str()).collect(),
+        source_map: SourceMap::new(&asm_code),
+        report,
+    })
+}
+
+/// translates in-memory .vm sources and assembles them with `hack_assembler`,
+/// returning both the .asm and the .hack program
+/// files: `(name, source)` pairs, as for `translate`
+pub fn translate_hack<N: AsRef<str>, S: AsRef<str>>(
+    files: &[(N, S)],
+    options: &Options,
+) -> Result<(String, String, Report), TranslateError> {
+    let translation = translate_program(files, options)?;
+    let hack = translation.assemble()?;
+    Ok((translation.asm, hack, translation.report))
+}
+
+/// parses, checks, optimizes and translates the sources into blocks of assembly code
+/// the sources must have distinct file stems, and when there is more than one of them
+/// all of their code must be inside functions
+fn generate<N: AsRef<str>, S: AsRef<str>>(
+    files: &[(N, S)],
+    options: &Options,
+) -> Result<(Vec<Block>, Report), TranslateError> {
+    let mut report = Report::default();
+    let mut files = files
+        .iter()
+        .map(|(name, source)| parse_source(name.as_ref(), source.as_ref()))
+        .collect::<Result<Vec<SourceFile>, TranslateError>>()?;
+
+    // validate the whole program before generating any code,
+    // a program made of more than one file must keep all of its code in functions,
+    // whether the files come from a directory or are given one by one
+    let bootstrap = options.bootstrap.emits_bootstrap(&files);
+    checker::check(&files, files.len() > 1, bootstrap)?;
+
+    // optimize the commands of every file
+    if options.optimize {
+        let mut stats = Stats::default();
+        for file in &mut files {
+            file.program = optimizer::optimize(std::mem::take(&mut file.program), &mut stats);
+        }
+        report.optimizer = Some(stats);
+    }
+
+    // generate the assembly code for the whole program
+    let mut asm_code = generate_asm::program(files.clone(), options)?;
+
+    // in compact mode, compare with the ROM words of the inline expansion
+    if options.compact {
+        let inline = Options {
+            compact: false,
+            ..*options
+        };
+        let inline_words = generate_asm::rom_words(&generate_asm::program(files, &inline)?);
+        report.compact = Some((generate_asm::rom_words(&asm_code), inline_words));
+    }
+
+    // remove redundant instructions from the generated code
+    if options.peephole {
+        let removed;
+        (asm_code, removed) = peephole::optimize(asm_code);
+        report.peephole = Some(removed);
+    }
+
+    // name the VM command of every block, after the peephole optimizer
+    // so that the comments do not get in the way of its patterns
+    if options.annotate {
+        generate_asm::annotate(&mut asm_code);
+    }
+    Ok((asm_code, report))
+}
+
+/// parses the source of a single .vm file
+/// its static variables are named after the file stem, e.g. `Main.3` for `Main.vm`
+fn parse_source(path: &str, source: &str) -> Result<SourceFile, TranslateError> {
+    let name = Path::new(path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    // remove comments
+    let tokens = tokenizer::remove_comments(source.lines().map(str::to_string).collect());
+    // parse the instructions into commands
+    let program = parser::parse(tokens, path)?;
+    Ok(SourceFile {
+        path: path.to_string(),
+        name,
+        program,
+    })
+}
+
+/// reads a .vm file from disk into a `(name, source)` pair for `translate`
+pub fn read_source(path: &Path) -> Result<(String, String), TranslateError> {
+    let name = path.display().to_string();
+    let source = std::fs::read_to_string(path).map_err(|err| TranslateError::io(&name, err))?;
+    Ok((name, source))
+}
+
+/// reads every .vm file of a directory, in sorted order so that
+/// the output does not depend on the order of the directory listing
+pub fn read_dir_sources(dir: &Path) -> Result<Vec<(String, String)>, TranslateError> {
+    let display = dir.display().to_string();
+    let error = |err| TranslateError::io(&display, err);
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir).map_err(error)? {
+        let path = entry.map_err(error)?.path();
+        if path.is_file() && path.extension().is_some_and(|ext| ext == "vm") {
+            paths.push(path);
+        }
+    }
+    if paths.is_empty() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "no .vm files in directory");
+        return Err(error(err));
+    }
+    paths.sort();
+    paths.iter().map(|path| read_source(path)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hack_emulator::{rom, Machine};
+
+    #[test]
+    fn translates_in_memory_sources() {
+        let asm = translate(&[("Main.vm", "push constant 7\npop static 0\n")]).unwrap();
+        assert!(asm.contains("@Main.0\n"));
+        assert!(asm.ends_with("(__VM_END)\n@__VM_END\n0;JMP\n"));
+    }
+
+    #[test]
+    fn bootstraps_only_programs_that_define_sys_init() {
+        let always = Options {
+            bootstrap: BootstrapPolicy::Always,
+            ..Options::default()
+        };
+        let main = ("Main.vm", "function Main.main 0\npush constant 0\nreturn\n");
+        let err = translate_program(&[main], &always).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the bootstrap code calls `Sys.init`, which is not defined in any input file"
+        );
+        let sys = (
+            "Sys.vm",
+            "function Sys.init 0\ncall Main.main 0\nlabel END\ngoto END\n",
+        );
+        let translation = translate_program(&[sys, main], &always).unwrap();
+        assert!(translation.asm.starts_with("@256\n"));
+        translation.assemble().unwrap();
+    }
+
+    #[test]
+    fn labels_outside_functions_do_not_collide_with_generated_labels() {
+        // the names the comparisons used to generate
+        let source = "push constant 1\npush constant 2\neq\npush constant 3\npush constant 4\ngt\n\
+                      label ISEQUAL0\nlabel EQEND0\nlabel ISTRUE_GT0\nlabel GTEND0\ngoto ISEQUAL0\n";
+        let (asm, _, _) = translate_hack(&[("Main.vm", source)], &Options::default()).unwrap();
+        assert!(asm.contains("(__VM_EQ_TRUE0)\n") && asm.contains("(__VM_GT_END0)\n"));
+    }
+
+    #[test]
+    fn the_bootstrap_return_label_does_not_collide_with_a_bootstrap_function() {
+        let sys = (
+            "Sys.vm",
+            "function Sys.init 0\ncall Bootstrap 0\nlabel END\ngoto END\n",
+        );
+        let bootstrap = (
+            "Bootstrap.vm",
+            "function Bootstrap 0\ncall Bootstrap.f 0\nreturn\n\
+             function Bootstrap.f 0\npush constant 7\nreturn\n",
+        );
+        let (asm, _, _) = translate_hack(&[sys, bootstrap], &Options::default()).unwrap();
+        assert!(asm.contains("(__VM_BOOTSTRAP_RET)\n") && asm.contains("(__VM_RET$Bootstrap.0)\n"));
+    }
+
+    #[test]
+    fn return_labels_do_not_collide_with_the_labels_of_the_program() {
+        // `ret.0` is scoped to `Main.main$ret.0`, the spec's name for the first return address
+        let source = "function Main.main 0\ncall Main.f 0\nlabel ret.0\nreturn\n\
+                      function Main.f 0\npush constant 7\nreturn\n";
+        let (asm, _, _) = translate_hack(&[("Main.vm", source)], &Options::default()).unwrap();
+        assert!(asm.contains("(__VM_RET$Main.main.0)\n") && asm.contains("(Main.main$ret.0)\n"));
+    }
+
+    #[test]
+    fn reports_errors_by_source_name() {
+        let err = translate(&[("Main.vm", "push constant 1\n\npop constant 0\n")]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Main.vm:3: cannot pop to the constant segment"
+        );
+    }
+
+    #[test]
+    fn rejects_sources_with_the_same_file_stem() {
+        let files = [
+            ("a/Main.vm", "function Main.f 0\npush constant 0\nreturn\n"),
+            ("b/Main.vm", "function Main.g 0\npush static 0\nreturn\n"),
+        ];
+        let err = translate(&files).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`a/Main.vm` and `b/Main.vm` have the same name `Main`, which names their static variables"
+        );
+    }
+
+    #[test]
+    fn writes_nothing_on_error() {
+        let mut output = Vec::new();
+        let files = [
+            ("Sys.vm", "function Sys.init 0\ncall Main.main 0\n"),
+            ("Main.vm", "function Main.main 0\ngoto NOWHERE\n"),
+        ];
+        assert!(translate_to(&files, &Options::default(), &mut output, "out.asm").is_err());
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn assembles_to_hack() {
+        let files = [("Main.vm", "push constant 7\npush constant 8\nadd\n")];
+        let (asm, hack, _) = translate_hack(&files, &Options::default()).unwrap();
+        let words = asm
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('(') && !line.starts_with("//"))
+            .count();
+        assert_eq!(hack.lines().count(), words);
+        assert!(hack.lines().all(|word| word.len() == 16));
+    }
+
+    #[test]
+    fn maps_assembler_errors_to_vm_lines() {
+        // a function defined twice is a label the assembler rejects
+        let files = [(
+            "Main.vm",
+            "function Main.f 0\npush constant 0\nreturn\nfunction Main.f 0\nreturn\n",
+        )];
+        let err = translate_hack(&files, &Options::default()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Main.vm:4: symbol `Main.f` is defined more than once"
+        );
+    }
+
+    #[test]
+    fn annotates_blocks_with_their_vm_command() {
+        let options = Options {
+            annotate: true,
+            ..Options::default()
+        };
+        let files = [("dir/Main.vm", "// comment\npush   local 2\n")];
+        let translation = translate_program(&files, &options).unwrap();
+        assert!(translation.asm.starts_with("// Main.vm:2: push local 2\n"));
+        let origin = translation.source_map.rom_origin(0).unwrap();
+        assert_eq!((origin.file.as_str(), origin.line), ("dir/Main.vm", 2));
+    }
+
+    /// runs the test script `dir/name.tst` on the translation of the .vm files of `dir`:
+    /// sets the RAM of its `set` commands, runs its `repeat` cycles and returns the RAM
+    /// addresses of its output lists with the values expected by `dir/name.cmp`
+    fn run_project(dir: &Path, name: &str, options: &Options) -> Vec<(u16, i16, i16)> {
+        let file = dir.join(format!("{}.vm", name));
+        let files = if file.exists() {
+            vec![read_source(&file).unwrap()]
+        } else {
+            read_dir_sources(dir).unwrap()
+        };
+        let asm = translate_program(&files, options).unwrap().asm;
+        let program = rom::assemble(&asm, &format!("{}.asm", name)).unwrap();
+        let mut machine = Machine::new(&program.words);
+
+        let script = std::fs::read_to_string(dir.join(format!("{}.tst", name))).unwrap();
+        let (mut cycles, mut outputs) = (0, Vec::new());
+        for line in script.lines().map(|line| line.split("//").next().unwrap()) {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["set", address, value, ..] => {
+                    let address = address.trim_start_matches("RAM[").trim_end_matches(']');
+                    let value: i16 = value.trim_end_matches([',', ';']).parse().unwrap();
+                    machine.poke(address.parse().unwrap(), value as u16);
+                }
+                ["repeat", count, ..] => cycles = count.parse().unwrap(),
+                _ => {}
+            }
+            // the columns of the output lists, e.g. `RAM[256]%D1.6.1`
+            for column in line.split_whitespace().filter(|word| word.contains('%')) {
+                let address = column.split(['[', ']']).nth(1).unwrap();
+                outputs.push(address.parse::<u16>().unwrap());
+            }
+        }
+        machine.run(cycles);
+
+        // every other row of the comparison file holds the values of an output list
+        let expected = std::fs::read_to_string(dir.join(format!("{}.cmp", name))).unwrap();
+        let values = expected
+            .lines()
+            .skip(1)
+            .step_by(2)
+            .flat_map(|row| {
+                row.split('|')
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+            })
+            .map(|value| value.parse().unwrap());
+        outputs
+            .iter()
+            .zip(values)
+            .map(|(&address, value)| (address, machine.peek(address) as i16, value))
+            .collect()
+    }
+
+    #[test]
+    fn runs_the_project_programs_with_and_without_peephole() {
+        let projects = Path::new(env!("CARGO_MANIFEST_DIR")).join("../projects");
+        let programs = [
+            "07/StackArithmetic/SimpleAdd",
+            "07/StackArithmetic/StackTest",
+            "07/MemoryAccess/BasicTest",
+            "07/MemoryAccess/PointerTest",
+            "07/MemoryAccess/StaticTest",
+            "08/ProgramFlow/BasicLoop",
+            "08/ProgramFlow/FibonacciSeries",
+            "08/FunctionCalls/SimpleFunction",
+            "08/FunctionCalls/FibonacciElement",
+            "08/FunctionCalls/StaticsTest",
+            "08/FunctionCalls/NestedCall",
+        ];
+        for peephole in [false, true] {
+            let options = Options {
+                peephole,
+                ..Options::default()
+            };
+            for program in programs {
+                let dir = projects.join(program);
+                let name = program.rsplit('/').next().unwrap();
+                let results = run_project(&dir, name, &options);
+                assert!(!results.is_empty(), "{} has no outputs", program);
+                for (address, actual, expected) in results {
+                    assert_eq!(
+                        actual, expected,
+                        "{} RAM[{}] with peephole {}",
+                        program, address, peephole
+                    );
+                }
+            }
+        }
+    }
+}
@@ -1,9 +1,23 @@
+use clap::{value_parser, Arg, ArgAction, Command, ValueEnum};
 use std::fs;
-use std::path::PathBuf;
-mod commands;
-mod generate_asm;
-mod tokenizer;
-use clap::{Arg, Command};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use vm_translator::{BootstrapPolicy, Options, Report, TranslateError};
+
+/// the input and output name standing for stdin and stdout
+const STDIO: &str = "-";
+
+/// what the translator writes
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Emit {
+    /// the .asm program
+    #[default]
+    Asm,
+    /// the .hack machine code, assembled with hack_assembler
+    Hack,
+    /// the .asm program and, next to it, the .hack machine code
+    Both,
+}
 
 fn main() {
     // use clap to parse command line arguments
@@ -16,72 +30,232 @@ fn main() {
         .short('i')
         .long("input")
         .value_name("FILE")
-        .help("The input .vm file to assemble.")
+        .help("The input .vm files or directories of .vm files to translate, - for stdin.")
+        .num_args(1..)
+        .action(ArgAction::Append)
         .required(true)
     )
+    .arg(
+        Arg::new("output")
+        .short('o')
+        .long("output")
+        .value_name("FILE")
+        .help("The output .asm or .hack file, - for stdout. Defaults to Xxx.asm for Xxx.vm and to Xxx/Xxx.asm for a directory Xxx.")
+    )
+    .arg(
+        Arg::new("emit")
+        .long("emit")
+        .value_name("KIND")
+        .help("Whether to write the .asm program, the assembled .hack program or both, the .hack next to the .asm.")
+        .value_parser(value_parser!(Emit))
+        .default_value("asm")
+    )
+    .arg(
+        Arg::new("bootstrap")
+        .long("bootstrap")
+        .value_name("POLICY")
+        .help("When to emit the bootstrap code that calls Sys.init (auto: when Sys.init is defined).")
+        .value_parser(value_parser!(BootstrapPolicy))
+        .default_value("auto")
+    )
+    .arg(
+        Arg::new("compact")
+        .long("compact")
+        .help("Share a single call, return and comparison routine between all sites to save ROM, and report the words saved.")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(
+        Arg::new("optimize")
+        .short('O')
+        .long("optimize")
+        .help("Optimize the VM commands before generating code, and report what was done.")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(
+        Arg::new("peephole")
+        .long("peephole")
+        .help("Remove redundant instructions from the generated assembly, and report the words saved.")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(
+        Arg::new("annotate")
+        .long("annotate")
+        .help("Prefix the code of every VM command with a // File.vm:LINE: command comment.")
+        .action(ArgAction::SetTrue)
+    )
+    .arg(
+        Arg::new("source-map")
+        .long("source-map")
+        .value_name("FILE")
+        .help("Write a map from every line of the assembly and every ROM address to the VM file and line it came from.")
+    )
     .get_matches();
 
-    // match the input file
-    let vm_file = cmd_matches
-        .get_one::<String>("input-vm")
+    // match the input files, what to emit and the output file
+    let inputs: Vec<String> = cmd_matches
+        .get_many::<String>("input-vm")
         .expect("required")
-        .to_string();
+        .cloned()
+        .collect();
+    let emit = *cmd_matches.get_one::<Emit>("emit").expect("default");
+    let extension = if emit == Emit::Hack { "hack" } else { "asm" };
+    let output = match cmd_matches.get_one::<String>("output") {
+        Some(output) => output.clone(),
+        None => match default_output(&inputs, extension) {
+            Some(output) => output,
+            None => {
+                eprintln!("error: -o is required when translating several inputs");
+                std::process::exit(2);
+            }
+        },
+    };
+    if emit == Emit::Both && output == STDIO {
+        eprintln!("error: --emit both writes two files and cannot write to stdout");
+        std::process::exit(2);
+    }
+    // the .hack goes next to the .asm, with the same name, and would overwrite it
+    if emit == Emit::Both
+        && Path::new(&output)
+            .extension()
+            .is_some_and(|ext| ext == "hack")
+    {
+        eprintln!("error: --emit both writes the .hack next to the .asm output, which cannot be a .hack file");
+        std::process::exit(2);
+    }
 
-    let mut contents: Vec<String>;
-    let mut tokens: Vec<String>;
-    let mut asm_code: Vec<String> = Vec::new();
-    let mut mult_files = false;
-    let mut cur_filename = String::new();
+    // match the code generation options
+    let options = Options {
+        bootstrap: *cmd_matches
+            .get_one::<BootstrapPolicy>("bootstrap")
+            .expect("default"),
+        compact: cmd_matches.get_flag("compact"),
+        optimize: cmd_matches.get_flag("optimize"),
+        peephole: cmd_matches.get_flag("peephole"),
+        annotate: cmd_matches.get_flag("annotate"),
+    };
+    let source_map = cmd_matches.get_one::<String>("source-map");
 
-    if vm_file.ends_with(".vm") {
-        // if the input file is a single .vm file
-        // then we feed the output of the tokenizer into the contents String vector
-        contents = match tokenizer::file_contents(vm_file.clone()) {
-            Ok(description) => description,
-            Err(err) => {
-                panic!("There was a problem opening the file: {:?}", err)
-            }
-        };
-        // remove comments
-        tokens = tokenizer::remove_comments(contents);
-        // generate the assembly code
-        // and append to the output string vector
-        asm_code.append(&mut generate_asm::asm(
-            tokens,
-            cur_filename.clone(),
-            mult_files,
-        ));
-        // write the output to a file
-        generate_asm::write_output(asm_code, vm_file.clone())
-            .map_err(|err| println!("{:?}", err))
-            .ok();
+    // report the first error and exit with a non-zero status
+    if let Err(err) = run(&inputs, &output, emit, source_map, &options) {
+        eprintln!("error: {}", err);
+        std::process::exit(1);
+    }
+}
+
+/// derive the output path from a single input: Xxx.asm next to Xxx.vm,
+/// Xxx/Xxx.asm inside a directory Xxx and stdout for stdin
+/// extension: the extension of the output file, asm or hack
+fn default_output(inputs: &[String], extension: &str) -> Option<String> {
+    let [input] = inputs else {
+        return None;
+    };
+    if input == STDIO {
+        return Some(STDIO.to_string());
+    }
+    let path = Path::new(input);
+    let output = if path.is_dir() {
+        let dir = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+        let name = dir.file_name().unwrap_or_default();
+        path.join(name).with_extension(extension)
     } else {
-        mult_files = true;
-        let paths = fs::read_dir(vm_file.clone()).unwrap();
-        for path in paths {
-            cur_filename = path.unwrap().path().display().to_string();
-            let path_split = PathBuf::from(cur_filename.clone());
-            let crop_filename = path_split
-                .file_name()
-                .unwrap()
-                .to_os_string()
-                .into_string()
-                .unwrap();
-            contents = match tokenizer::file_contents(cur_filename.clone()) {
-                Ok(description) => description,
-                Err(err) => {
-                    panic!("There was a problem opening the file: {:?}", err)
-                }
+        path.with_extension(extension)
+    };
+    Some(output.display().to_string())
+}
+
+/// translate the input files and directories into a single program
+/// nothing is written unless every input translated successfully
+/// the reports of the optional passes go to stderr, so that stdout can carry the program
+/// source_map: where to write the source map, if anywhere
+fn run(
+    inputs: &[String],
+    output: &str,
+    emit: Emit,
+    source_map: Option<&String>,
+    options: &Options,
+) -> Result<(), TranslateError> {
+    let mut files: Vec<(String, String)> = Vec::new();
+    for input in inputs {
+        if input == STDIO {
+            let mut source = String::new();
+            io::stdin()
+                .read_to_string(&mut source)
+                .map_err(|err| TranslateError::io("stdin", err))?;
+            files.push(("stdin".to_string(), source));
+        } else if Path::new(input).is_dir() {
+            files.extend(vm_translator::read_dir_sources(Path::new(input))?);
+        } else {
+            files.push(vm_translator::read_source(&PathBuf::from(input))?);
+        }
+    }
+
+    let report = match (emit, source_map) {
+        (Emit::Asm, None) if output == STDIO => {
+            vm_translator::translate_to(&files, options, io::stdout().lock(), "stdout")?
+        }
+        (Emit::Asm, None) => {
+            // translate into memory first so that no file is created on error
+            let mut asm_code = Vec::new();
+            let report = vm_translator::translate_to(&files, options, &mut asm_code, output)?;
+            write_output(output, &asm_code)?;
+            report
+        }
+        _ => {
+            let translation = vm_translator::translate_program(&files, options)?;
+            // assemble before writing anything, so that no file is created on error
+            let hack = match emit {
+                Emit::Asm => None,
+                Emit::Hack | Emit::Both => Some(translation.assemble()?),
             };
-            tokens = tokenizer::remove_comments(contents);
-            asm_code.append(&mut generate_asm::asm(
-                tokens,
-                crop_filename.clone(),
-                mult_files,
-            ));
+            if emit != Emit::Hack {
+                write_output(output, translation.asm.as_bytes())?;
+            }
+            if let Some(hack) = hack {
+                let hack_output = match emit {
+                    Emit::Both => Path::new(output)
+                        .with_extension("hack")
+                        .display()
+                        .to_string(),
+                    _ => output.to_string(),
+                };
+                write_output(&hack_output, hack.as_bytes())?;
+            }
+            if let Some(source_map) = source_map {
+                write_output(source_map, translation.source_map.to_string().as_bytes())?;
+            }
+            translation.report
         }
-        generate_asm::write_output(asm_code, vm_file.clone())
-            .map_err(|err| println!("{:?}", err))
-            .ok();
+    };
+    print_report(&report);
+    Ok(())
+}
+
+/// write the output to a file, or to stdout for -
+fn write_output(output: &str, contents: &[u8]) -> Result<(), TranslateError> {
+    if output == STDIO {
+        io::stdout()
+            .lock()
+            .write_all(contents)
+            .map_err(|err| TranslateError::io("stdout", err))
+    } else {
+        fs::write(output, contents).map_err(|err| TranslateError::io(output, err))
+    }
+}
+
+/// print what the optional passes did
+fn print_report(report: &Report) {
+    if let Some(stats) = report.optimizer {
+        eprintln!("optimized: {}", stats);
+    }
+    if let Some((compact_words, inline_words)) = report.compact {
+        eprintln!(
+            "compact: {} ROM words instead of {} ({} saved)",
+            compact_words,
+            inline_words,
+            inline_words as i64 - compact_words as i64
+        );
+    }
+    if let Some(removed) = report.peephole {
+        eprintln!("peephole: {} ROM words removed", removed);
     }
 }
@@ -0,0 +1,442 @@
+use crate::parser::{ArithmeticOp, ParsedCommand, Segment, VmCommand};
+use std::fmt;
+
+/// how often each optimization was applied
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// number of commands before optimizing
+    pub before: usize,
+    /// number of commands after optimizing
+    pub after: usize,
+    /// arithmetic commands evaluated at translation time or removed as identities
+    pub folded: usize,
+    /// `push X; pop X` pairs removed
+    pub push_pop: usize,
+    /// `if-goto`s on constants and `goto`s to the next command simplified
+    pub branches: usize,
+    /// unreachable commands removed after `goto` and `return`
+    pub dead: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} VM commands to {} ({} folded, {} push/pop pairs, {} branches, {} unreachable)",
+            self.before, self.after, self.folded, self.push_pop, self.branches, self.dead
+        )
+    }
+}
+
+/// optimizes the commands of a file, repeating every pass until the program stops shrinking
+/// each pass only ever removes commands, so this terminates
+pub fn optimize(mut program: Vec<ParsedCommand>, stats: &mut Stats) -> Vec<ParsedCommand> {
+    stats.before += program.len();
+    loop {
+        let len = program.len();
+        program = fold_constants(program, stats);
+        program = remove_push_pop(program, stats);
+        program = simplify_branches(program, stats);
+        program = remove_dead_code(program, stats);
+        if program.len() == len {
+            break;
+        }
+    }
+    stats.after += program.len();
+    program
+}
+
+/// returns the value of the constant expression at the end of `program`
+/// and the number of commands it spans: `push constant n`, optionally followed by `neg` or `not`
+fn constant_at_end(program: &[ParsedCommand]) -> Option<(i16, usize)> {
+    let constant = |parsed: &ParsedCommand| match &parsed.command {
+        VmCommand::Push {
+            segment: Segment::Constant,
+            index,
+        } => Some(*index as i16),
+        _ => None,
+    };
+    let (last, rest) = program.split_last()?;
+    if let Some(value) = constant(last) {
+        return Some((value, 1));
+    }
+    let value = rest.last().and_then(constant)?;
+    match &last.command {
+        VmCommand::Arithmetic(ArithmeticOp::Neg) => Some((value.wrapping_neg(), 2)),
+        VmCommand::Arithmetic(ArithmeticOp::Not) => Some((!value, 2)),
+        _ => None,
+    }
+}
+
+/// the shortest commands that push `value`, since `push constant` only takes 0..=32767
+fn push_value(value: i16, line: usize) -> Vec<ParsedCommand> {
+    let push = |index: i16| ParsedCommand {
+        line,
+        command: VmCommand::Push {
+            segment: Segment::Constant,
+            index: index as u16,
+        },
+    };
+    let arithmetic = |op: ArithmeticOp| ParsedCommand {
+        line,
+        command: VmCommand::Arithmetic(op),
+    };
+    if value >= 0 {
+        vec![push(value)]
+    } else if value == i16::MIN {
+        vec![push(!value), arithmetic(ArithmeticOp::Not)]
+    } else {
+        vec![push(-value), arithmetic(ArithmeticOp::Neg)]
+    }
+}
+
+/// evaluates an arithmetic command on constant operands, `x` is unused by unary commands
+fn evaluate(op: ArithmeticOp, x: i16, y: i16) -> i16 {
+    let boolean = |result: bool| if result { -1 } else { 0 };
+    match op {
+        ArithmeticOp::Add => x.wrapping_add(y),
+        ArithmeticOp::Sub => x.wrapping_sub(y),
+        ArithmeticOp::And => x & y,
+        ArithmeticOp::Or => x | y,
+        ArithmeticOp::Eq => boolean(x == y),
+        ArithmeticOp::Gt => boolean(x > y),
+        ArithmeticOp::Lt => boolean(x < y),
+        ArithmeticOp::Neg => y.wrapping_neg(),
+        ArithmeticOp::Not => !y,
+    }
+}
+
+/// evaluates arithmetic on constants and removes arithmetic identities
+/// such as `push constant 0; add` and `not; not`
+fn fold_constants(program: Vec<ParsedCommand>, stats: &mut Stats) -> Vec<ParsedCommand> {
+    let mut out: Vec<ParsedCommand> = Vec::new();
+    for parsed in program {
+        let op = match parsed.command {
+            VmCommand::Arithmetic(op) => op,
+            _ => {
+                out.push(parsed);
+                continue;
+            }
+        };
+        let unary = op.is_unary();
+
+        // both operands are constants: replace them and the command by the result
+        if let Some((y, y_len)) = constant_at_end(&out) {
+            let operands = if unary {
+                Some((0, 0))
+            } else {
+                constant_at_end(&out[..out.len() - y_len])
+            };
+            if let Some((x, x_len)) = operands {
+                let start = out.len() - y_len - x_len;
+                let result = push_value(evaluate(op, x, y), out[start].line);
+                if result.len() < x_len + y_len + 1 {
+                    out.truncate(start);
+                    out.extend(result);
+                    stats.folded += 1;
+                    continue;
+                }
+            }
+        }
+
+        // identities: x + 0, x - 0, x | 0, not not x and neg neg x
+        let identity = match out.last().map(|last| &last.command) {
+            Some(VmCommand::Push {
+                segment: Segment::Constant,
+                index: 0,
+            }) => matches!(op, ArithmeticOp::Add | ArithmeticOp::Sub | ArithmeticOp::Or),
+            Some(VmCommand::Arithmetic(prev)) => unary && *prev == op,
+            _ => false,
+        };
+        if identity {
+            out.pop();
+            stats.folded += 1;
+            continue;
+        }
+        out.push(parsed);
+    }
+    out
+}
+
+/// removes `push X; pop X` pairs, which leave the stack and memory as they were
+fn remove_push_pop(program: Vec<ParsedCommand>, stats: &mut Stats) -> Vec<ParsedCommand> {
+    let mut out: Vec<ParsedCommand> = Vec::new();
+    for parsed in program {
+        if let (
+            Some(VmCommand::Push { segment, index }),
+            VmCommand::Pop {
+                segment: pop_segment,
+                index: pop_index,
+            },
+        ) = (out.last().map(|last| &last.command), &parsed.command)
+        {
+            if segment == pop_segment && index == pop_index {
+                out.pop();
+                stats.push_pop += 1;
+                continue;
+            }
+        }
+        out.push(parsed);
+    }
+    out
+}
+
+/// turns `if-goto` on a constant into a `goto` or nothing,
+/// and removes a `goto` to the label that immediately follows it
+fn simplify_branches(program: Vec<ParsedCommand>, stats: &mut Stats) -> Vec<ParsedCommand> {
+    let mut out: Vec<ParsedCommand> = Vec::new();
+    for parsed in program {
+        match &parsed.command {
+            VmCommand::IfGoto(label) => {
+                if let Some((value, len)) = constant_at_end(&out) {
+                    out.truncate(out.len() - len);
+                    if value != 0 {
+                        out.push(ParsedCommand {
+                            line: parsed.line,
+                            command: VmCommand::Goto(label.clone()),
+                        });
+                    }
+                    stats.branches += 1;
+                    continue;
+                }
+            }
+            VmCommand::Label(label) => {
+                let jumps_here =
+                    |last: &ParsedCommand| last.command == VmCommand::Goto(label.clone());
+                if out.last().is_some_and(jumps_here) {
+                    out.pop();
+                    stats.branches += 1;
+                }
+            }
+            _ => {}
+        }
+        out.push(parsed);
+    }
+    out
+}
+
+/// removes the commands after a `goto` or `return` that no jump can reach,
+/// up to the next label or function declaration
+fn remove_dead_code(program: Vec<ParsedCommand>, stats: &mut Stats) -> Vec<ParsedCommand> {
+    let mut out: Vec<ParsedCommand> = Vec::new();
+    let mut reachable = true;
+    for parsed in program {
+        // labels can be jumped to and functions called, so they are always reachable
+        let is_target = matches!(
+            parsed.command,
+            VmCommand::Label(_) | VmCommand::Function { .. }
+        );
+        if !reachable && !is_target {
+            stats.dead += 1;
+            continue;
+        }
+        reachable = !matches!(parsed.command, VmCommand::Goto(_) | VmCommand::Return);
+        out.push(parsed);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{parser, tokenizer};
+
+    /// optimizes a .vm source and prints the commands left, one per line
+    fn optimized(source: &str) -> (String, Stats) {
+        let lines = tokenizer::remove_comments(source.lines().map(str::to_string).collect());
+        let program = parser::parse(lines, "Main.vm").unwrap();
+        let mut stats = Stats::default();
+        let text = optimize(program, &mut stats)
+            .iter()
+            .map(|parsed| format!("{}\n", parsed.command))
+            .collect();
+        (text, stats)
+    }
+
+    fn commands(source: &str) -> String {
+        optimized(source).0
+    }
+
+    #[test]
+    fn folds_constant_arithmetic() {
+        assert_eq!(
+            commands("push constant 2\npush constant 3\nadd\n"),
+            "push constant 5\n"
+        );
+        assert_eq!(
+            commands("push constant 1\npush constant 3\nsub\n"),
+            "push constant 2\nneg\n"
+        );
+        assert_eq!(
+            commands("push constant 12\npush constant 10\nand\npush constant 1\nor\nnot\n"),
+            "push constant 9\nnot\n"
+        );
+        assert_eq!(
+            commands("push constant 1\npush constant 2\nlt\n"),
+            "push constant 1\nneg\n"
+        );
+        assert_eq!(
+            commands("push constant 2\npush constant 2\ngt\n"),
+            "push constant 0\n"
+        );
+        // a folded result that would take more commands is left alone
+        assert_eq!(
+            commands("push local 0\npush constant 2\nadd\n"),
+            "push local 0\npush constant 2\nadd\n"
+        );
+    }
+
+    #[test]
+    fn folds_to_i16_min_with_not_and_wraps_additions() {
+        // 32767 + 1 wraps to -32768, which `neg` cannot reach from a constant
+        assert_eq!(
+            commands("push constant 32767\npush constant 1\nadd\n"),
+            "push constant 32767\nnot\n"
+        );
+        assert_eq!(
+            commands("push constant 32767\nneg\npush constant 1\nsub\n"),
+            "push constant 32767\nnot\n"
+        );
+        // and folding it again gives back the same commands
+        assert_eq!(
+            commands("push constant 32767\nnot\npush constant 0\nadd\n"),
+            "push constant 32767\nnot\n"
+        );
+        assert_eq!(
+            commands("push constant 32767\nnot\nneg\n"),
+            "push constant 32767\nnot\n"
+        );
+    }
+
+    #[test]
+    fn removes_arithmetic_identities() {
+        for op in ["add", "sub", "or"] {
+            assert_eq!(
+                commands(&format!("push local 0\npush constant 0\n{}\n", op)),
+                "push local 0\n"
+            );
+        }
+        assert_eq!(
+            commands("push local 0\npush constant 0\nand\n"),
+            "push local 0\npush constant 0\nand\n"
+        );
+        assert_eq!(commands("push local 0\nnot\nnot\n"), "push local 0\n");
+        assert_eq!(commands("push local 0\nneg\nneg\n"), "push local 0\n");
+        assert_eq!(
+            commands("push local 0\nneg\nnot\n"),
+            "push local 0\nneg\nnot\n"
+        );
+    }
+
+    #[test]
+    fn removes_push_then_pop_of_the_same_place() {
+        let (text, stats) = optimized("push local 1\npop local 1\npush static 2\npop static 2\n");
+        assert_eq!(text, "");
+        assert_eq!(stats.push_pop, 2);
+        assert_eq!(
+            commands("push local 1\npop local 2\npush that 0\npop this 0\n"),
+            "push local 1\npop local 2\npush that 0\npop this 0\n"
+        );
+    }
+
+    #[test]
+    fn simplifies_if_goto_on_constants() {
+        let body = "push local 0\nlabel L\nreturn\n";
+        let (text, stats) = optimized(&format!(
+            "function Main.f 0\npush constant 0\nif-goto L\n{}",
+            body
+        ));
+        assert_eq!(text, format!("function Main.f 0\n{}", body));
+        assert_eq!(stats.branches, 1);
+
+        // a taken branch skips the code up to its label, then jumps to the next command
+        let (text, stats) = optimized(&format!(
+            "function Main.f 0\npush constant 1\nneg\nif-goto L\n{}",
+            body
+        ));
+        assert_eq!(text, "function Main.f 0\nlabel L\nreturn\n");
+        assert_eq!((stats.branches, stats.dead), (2, 1));
+
+        // non-constant conditions are kept
+        let source = format!("function Main.f 0\npush argument 0\nif-goto L\n{}", body);
+        assert_eq!(commands(&source), source);
+    }
+
+    #[test]
+    fn removes_dead_code_up_to_the_next_label_or_function() {
+        let (text, stats) = optimized(
+            "\
+function Main.f 0
+return
+push constant 1
+pop local 0
+label L
+push constant 2
+goto L
+push local 0
+function Main.g 0
+push constant 3
+return
+",
+        );
+        assert_eq!(
+            text,
+            "\
+function Main.f 0
+return
+label L
+push constant 2
+goto L
+function Main.g 0
+push constant 3
+return
+"
+        );
+        assert_eq!(stats.dead, 3);
+    }
+
+    #[test]
+    fn counts_every_optimization() {
+        let source = "\
+function Main.f 0
+push constant 2
+push constant 3
+add
+push local 0
+not
+not
+push local 1
+pop local 1
+push constant 0
+if-goto L
+label L
+return
+push constant 4
+";
+        let (text, mut stats) = optimized(source);
+        assert_eq!(
+            text,
+            "function Main.f 0\npush constant 5\npush local 0\nlabel L\nreturn\n"
+        );
+        assert_eq!(
+            stats,
+            Stats {
+                before: 14,
+                after: 5,
+                folded: 2,
+                push_pop: 1,
+                branches: 1,
+                dead: 1,
+            }
+        );
+        assert_eq!(
+            stats.to_string(),
+            "14 VM commands to 5 (2 folded, 1 push/pop pairs, 1 branches, 1 unreachable)"
+        );
+
+        // the counters add up over the files of a program
+        let lines = tokenizer::remove_comments(source.lines().map(str::to_string).collect());
+        optimize(parser::parse(lines, "Main.vm").unwrap(), &mut stats);
+        assert_eq!((stats.before, stats.after, stats.folded), (28, 10, 4));
+    }
+}
@@ -0,0 +1,489 @@
+use crate::commands::RESERVED_PREFIX;
+use crate::error::TranslateError;
+use std::fmt;
+
+/// an arithmetic or logical command of the VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticOp {
+    /// x + y
+    Add,
+    /// x - y
+    Sub,
+    /// -y
+    Neg,
+    /// x == y, true is -1 and false is 0
+    Eq,
+    /// x > y
+    Gt,
+    /// x < y
+    Lt,
+    /// x & y, bitwise
+    And,
+    /// x | y, bitwise
+    Or,
+    /// !y, bitwise
+    Not,
+}
+
+impl ArithmeticOp {
+    /// every arithmetic command, in the order of the spec
+    pub const ALL: [ArithmeticOp; 9] = [
+        ArithmeticOp::Add,
+        ArithmeticOp::Sub,
+        ArithmeticOp::Neg,
+        ArithmeticOp::Eq,
+        ArithmeticOp::Gt,
+        ArithmeticOp::Lt,
+        ArithmeticOp::And,
+        ArithmeticOp::Or,
+        ArithmeticOp::Not,
+    ];
+
+    /// the comparisons, in the order of their label counters
+    pub const COMPARISONS: [ArithmeticOp; 3] =
+        [ArithmeticOp::Eq, ArithmeticOp::Gt, ArithmeticOp::Lt];
+
+    /// `ArithmeticOp.name()`: the command as it is written in a .vm file
+    pub fn name(self) -> &'static str {
+        match self {
+            ArithmeticOp::Add => "add",
+            ArithmeticOp::Sub => "sub",
+            ArithmeticOp::Neg => "neg",
+            ArithmeticOp::Eq => "eq",
+            ArithmeticOp::Gt => "gt",
+            ArithmeticOp::Lt => "lt",
+            ArithmeticOp::And => "and",
+            ArithmeticOp::Or => "or",
+            ArithmeticOp::Not => "not",
+        }
+    }
+
+    /// `ArithmeticOp::from_name()`: the command written `name` in a .vm file, if any
+    pub fn from_name(name: &str) -> Option<Self> {
+        ArithmeticOp::ALL.into_iter().find(|op| op.name() == name)
+    }
+
+    /// `ArithmeticOp.is_unary()`: whether the command takes a single operand, neg and not
+    pub fn is_unary(self) -> bool {
+        matches!(self, ArithmeticOp::Neg | ArithmeticOp::Not)
+    }
+
+    /// `ArithmeticOp.comparison_index()`: the index of eq, gt and lt in `COMPARISONS`,
+    /// none for the other commands
+    pub fn comparison_index(self) -> Option<usize> {
+        ArithmeticOp::COMPARISONS.iter().position(|&op| op == self)
+    }
+}
+
+impl fmt::Display for ArithmeticOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// a memory segment of the VM
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Segment {
+    /// the locals of the function, at `LCL`
+    Local,
+    /// the arguments of the function, at `ARG`
+    Argument,
+    /// the object, at `THIS`
+    This,
+    /// the array, at `THAT`
+    That,
+    /// the constants 0..=32767, push only
+    Constant,
+    /// the static variables of the file
+    Static,
+    /// RAM[5]..RAM[12]
+    Temp,
+    /// `THIS` and `THAT` themselves
+    Pointer,
+}
+
+impl Segment {
+    /// every segment, in the order of the spec
+    pub const ALL: [Segment; 8] = [
+        Segment::Local,
+        Segment::Argument,
+        Segment::This,
+        Segment::That,
+        Segment::Constant,
+        Segment::Static,
+        Segment::Temp,
+        Segment::Pointer,
+    ];
+
+    /// `Segment.name()`: the segment as it is written in a .vm file
+    pub fn name(self) -> &'static str {
+        match self {
+            Segment::Local => "local",
+            Segment::Argument => "argument",
+            Segment::This => "this",
+            Segment::That => "that",
+            Segment::Constant => "constant",
+            Segment::Static => "static",
+            Segment::Temp => "temp",
+            Segment::Pointer => "pointer",
+        }
+    }
+
+    /// `Segment::from_name()`: the segment written `name` in a .vm file, if any
+    pub fn from_name(name: &str) -> Option<Self> {
+        Segment::ALL
+            .into_iter()
+            .find(|segment| segment.name() == name)
+    }
+}
+
+impl fmt::Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// a single VM command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VmCommand {
+    /// add, sub, neg, eq, gt, lt, and, or, not
+    Arithmetic(ArithmeticOp),
+    /// push segment index
+    Push { segment: Segment, index: u16 },
+    /// pop segment index
+    Pop { segment: Segment, index: u16 },
+    /// label name
+    Label(String),
+    /// goto name
+    Goto(String),
+    /// if-goto name
+    IfGoto(String),
+    /// function name nLocals
+    Function { name: String, n_locals: u16 },
+    /// call name nArgs
+    Call { name: String, n_args: u16 },
+    /// return
+    Return,
+}
+
+/// prints the command as it is written in a .vm file
+impl fmt::Display for VmCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VmCommand::Arithmetic(command) => write!(f, "{}", command),
+            VmCommand::Push { segment, index } => write!(f, "push {} {}", segment, index),
+            VmCommand::Pop { segment, index } => write!(f, "pop {} {}", segment, index),
+            VmCommand::Label(label) => write!(f, "label {}", label),
+            VmCommand::Goto(label) => write!(f, "goto {}", label),
+            VmCommand::IfGoto(label) => write!(f, "if-goto {}", label),
+            VmCommand::Function { name, n_locals } => write!(f, "function {} {}", name, n_locals),
+            VmCommand::Call { name, n_args } => write!(f, "call {} {}", name, n_args),
+            VmCommand::Return => write!(f, "return"),
+        }
+    }
+}
+
+/// a parsed command together with the line of the .vm file it came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedCommand {
+    /// 1-based line number in the source file
+    pub line: usize,
+    /// the command itself
+    pub command: VmCommand,
+}
+
+/// the parsed commands of a single .vm file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    /// the path the file was read from
+    pub path: String,
+    /// the name used for the file's static variables
+    pub name: String,
+    /// the commands of the file in order
+    pub program: Vec<ParsedCommand>,
+}
+
+/// parses the comment-free lines of a .vm file into commands
+/// lines: (line number, instruction) pairs as returned by `tokenizer::remove_comments`
+/// filename: the file the lines came from, used in error messages
+pub fn parse(
+    lines: Vec<(usize, String)>,
+    filename: &str,
+) -> Result<Vec<ParsedCommand>, TranslateError> {
+    let mut commands = Vec::new();
+    for (line, instruction) in lines {
+        let command = parse_command(&instruction)
+            .map_err(|message| TranslateError::source(filename, line, message))?;
+        commands.push(ParsedCommand { line, command });
+    }
+    Ok(commands)
+}
+
+/// parses a single instruction, returning a description of the problem on failure
+pub fn parse_command(instruction: &str) -> Result<VmCommand, String> {
+    let words: Vec<&str> = instruction.split_whitespace().collect();
+    let (&name, args) = words
+        .split_first()
+        .ok_or_else(|| "empty command".to_string())?;
+
+    if let Some(op) = ArithmeticOp::from_name(name) {
+        expect_arity(name, args, 0)?;
+        return Ok(VmCommand::Arithmetic(op));
+    }
+    let command = match name {
+        "return" => {
+            expect_arity(name, args, 0)?;
+            VmCommand::Return
+        }
+        "label" | "goto" | "if-goto" => {
+            expect_arity(name, args, 1)?;
+            let label = parse_name("label", args[0])?;
+            match name {
+                "label" => VmCommand::Label(label),
+                "goto" => VmCommand::Goto(label),
+                _ => VmCommand::IfGoto(label),
+            }
+        }
+        "push" | "pop" => {
+            expect_arity(name, args, 2)?;
+            let segment = Segment::from_name(args[0])
+                .ok_or_else(|| format!("unknown segment `{}`", args[0]))?;
+            let index = parse_number(name, args[1])?;
+            if name == "push" {
+                VmCommand::Push { segment, index }
+            } else {
+                VmCommand::Pop { segment, index }
+            }
+        }
+        "function" => {
+            expect_arity(name, args, 2)?;
+            VmCommand::Function {
+                name: parse_name("function", args[0])?,
+                n_locals: parse_number(name, args[1])?,
+            }
+        }
+        "call" => {
+            expect_arity(name, args, 2)?;
+            VmCommand::Call {
+                name: parse_name("function", args[0])?,
+                n_args: parse_number(name, args[1])?,
+            }
+        }
+        _ => return Err(format!("unknown command `{}`", name)),
+    };
+    Ok(command)
+}
+
+/// checks that a command got exactly `expected` arguments
+fn expect_arity(name: &str, args: &[&str], expected: usize) -> Result<(), String> {
+    if args.len() == expected {
+        Ok(())
+    } else {
+        Err(format!(
+            "`{}` takes {} argument(s) but {} were given",
+            name,
+            expected,
+            args.len()
+        ))
+    }
+}
+
+/// parses a non-negative numeric operand
+fn parse_number(name: &str, operand: &str) -> Result<u16, String> {
+    operand.parse::<u16>().map_err(|_| {
+        format!(
+            "`{}` expects a non-negative number, found `{}`",
+            name, operand
+        )
+    })
+}
+
+/// parses a label or function name, which the assembler must accept as a symbol:
+/// letters, digits, `_`, `.`, `$` and `:`, not starting with a digit,
+/// nor with the `RESERVED_PREFIX` of the generated labels
+/// kind: label or function, used in error messages
+fn parse_name(kind: &str, name: &str) -> Result<String, String> {
+    if name.starts_with(RESERVED_PREFIX) {
+        return Err(format!(
+            "invalid {} name `{}`, names starting with `{}` are reserved for the translator",
+            kind, name, RESERVED_PREFIX
+        ));
+    }
+    let is_symbol_char = |ch: char| ch.is_ascii_alphanumeric() || "_.$:".contains(ch);
+    let starts_with_digit = name.starts_with(|ch: char| ch.is_ascii_digit());
+    if starts_with_digit || !name.chars().all(is_symbol_char) {
+        return Err(format!(
+            "invalid {} name `{}`, expected letters, digits, `_`, `.`, `$` or `:` not starting with a digit",
+            kind, name
+        ));
+    }
+    Ok(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tokenizer;
+
+    /// parses a .vm source the way the translator does, comments removed
+    fn parse_source(source: &str) -> Result<Vec<ParsedCommand>, TranslateError> {
+        let lines = tokenizer::remove_comments(source.lines().map(str::to_string).collect());
+        parse(lines, "Main.vm")
+    }
+
+    fn error(source: &str) -> String {
+        parse_source(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_every_kind_of_command() {
+        let source = "\
+// comment
+push constant 7
+pop  local 2 // trailing comment
+add
+label Main.loop$1:x
+if-goto _LOOP
+function Main.main 3
+call Math.multiply 2
+return
+";
+        let commands: Vec<(usize, VmCommand)> = parse_source(source)
+            .unwrap()
+            .into_iter()
+            .map(|parsed| (parsed.line, parsed.command))
+            .collect();
+        assert_eq!(
+            commands,
+            [
+                (
+                    2,
+                    VmCommand::Push {
+                        segment: Segment::Constant,
+                        index: 7
+                    }
+                ),
+                (
+                    3,
+                    VmCommand::Pop {
+                        segment: Segment::Local,
+                        index: 2
+                    }
+                ),
+                (4, VmCommand::Arithmetic(ArithmeticOp::Add)),
+                (5, VmCommand::Label("Main.loop$1:x".to_string())),
+                (6, VmCommand::IfGoto("_LOOP".to_string())),
+                (
+                    7,
+                    VmCommand::Function {
+                        name: "Main.main".to_string(),
+                        n_locals: 3
+                    }
+                ),
+                (
+                    8,
+                    VmCommand::Call {
+                        name: "Math.multiply".to_string(),
+                        n_args: 2
+                    }
+                ),
+                (9, VmCommand::Return),
+            ]
+        );
+        // every command prints back as it was written
+        for op in ArithmeticOp::ALL {
+            assert_eq!(parse_command(op.name()), Ok(VmCommand::Arithmetic(op)));
+        }
+        assert_eq!(
+            parse_command("pop  pointer 1").unwrap().to_string(),
+            "pop pointer 1"
+        );
+    }
+
+    #[test]
+    fn rejects_wrong_arities() {
+        assert_eq!(
+            error("push constant\n"),
+            "Main.vm:1: `push` takes 2 argument(s) but 1 were given"
+        );
+        assert_eq!(
+            error("add\nadd 1\n"),
+            "Main.vm:2: `add` takes 0 argument(s) but 1 were given"
+        );
+        assert_eq!(
+            error("return 0\n"),
+            "Main.vm:1: `return` takes 0 argument(s) but 1 were given"
+        );
+        assert_eq!(
+            error("label\n"),
+            "Main.vm:1: `label` takes 1 argument(s) but 0 were given"
+        );
+        assert_eq!(
+            error("call Main.main 0 1\n"),
+            "Main.vm:1: `call` takes 2 argument(s) but 3 were given"
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_commands_and_segments() {
+        assert_eq!(error("mul\n"), "Main.vm:1: unknown command `mul`");
+        assert_eq!(
+            error("Push constant 1\n"),
+            "Main.vm:1: unknown command `Push`"
+        );
+        assert_eq!(
+            error("push constant 1\npush heap 1\n"),
+            "Main.vm:2: unknown segment `heap`"
+        );
+    }
+
+    #[test]
+    fn rejects_negative_and_non_numeric_operands() {
+        assert_eq!(
+            error("push constant -1\n"),
+            "Main.vm:1: `push` expects a non-negative number, found `-1`"
+        );
+        assert_eq!(
+            error("pop local x\n"),
+            "Main.vm:1: `pop` expects a non-negative number, found `x`"
+        );
+        assert_eq!(
+            error("function Main.main -2\n"),
+            "Main.vm:1: `function` expects a non-negative number, found `-2`"
+        );
+        assert_eq!(
+            error("call Main.main two\n"),
+            "Main.vm:1: `call` expects a non-negative number, found `two`"
+        );
+        assert_eq!(
+            error("push constant 65536\n"),
+            "Main.vm:1: `push` expects a non-negative number, found `65536`"
+        );
+    }
+
+    #[test]
+    fn rejects_names_the_assembler_cannot_take() {
+        assert_eq!(
+            error("function Main-f 0\n"),
+            "Main.vm:1: invalid function name `Main-f`, \
+             expected letters, digits, `_`, `.`, `$` or `:` not starting with a digit"
+        );
+        assert!(error("call 2fast 0\n").starts_with("Main.vm:1: invalid function name `2fast`"));
+        assert!(error("label LOOP\ngoto a+b\n").starts_with("Main.vm:2: invalid label name `a+b`"));
+        assert!(error("if-goto 1\n").starts_with("Main.vm:1: invalid label name `1`"));
+        assert_eq!(
+            error("label __VM_EQ_TRUE0\n"),
+            "Main.vm:1: invalid label name `__VM_EQ_TRUE0`, \
+             names starting with `__VM_` are reserved for the translator"
+        );
+    }
+
+    #[test]
+    fn reports_the_line_of_the_error_in_the_file() {
+        let source = "// header\n\npush constant 1\n  // indented comment\n\nadd\nnot 3\n";
+        assert_eq!(
+            error(source),
+            "Main.vm:7: `not` takes 0 argument(s) but 1 were given"
+        );
+    }
+}
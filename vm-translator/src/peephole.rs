@@ -0,0 +1,207 @@
+use crate::generate_asm::Block;
+
+/// a line of generated code and the index of the block it came from
+type Line = (usize, String);
+
+/// a value known to be held by the A register
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    /// the address or constant `@symbol`
+    Symbol(String),
+    /// the contents of RAM[symbol], e.g. the stack pointer after `@SP` `A=M`
+    Load(String),
+}
+
+/// what is known about the registers at a point of the instruction stream
+/// a write through a pointer, such as `@SP` `A=M` `M=D`, is assumed not to change the pointer
+/// itself, so that A still holds RAM[SP] after it; this holds unless a pointer holds its own
+/// address: the stack starts at 256, and `pop temp` and `pop pointer` write RAM[3..12]
+/// through R13
+#[derive(Debug, Default)]
+struct State {
+    /// the value of A, if known
+    a: Option<Value>,
+    /// the address whose contents D is known to equal, if any
+    d_equals: Option<Value>,
+}
+
+/// optimizes the generated assembly and returns it with the number of ROM words removed,
+/// each remaining line stays in the block it came from
+/// asm: the generated code blocks as returned by `generate_asm::program`
+pub fn optimize(asm: Vec<Block>) -> (Vec<Block>, usize) {
+    let lines: Vec<Line> = asm
+        .iter()
+        .enumerate()
+        .flat_map(|(idx, block)| block.code.lines().map(move |line| (idx, line)))
+        .map(|(idx, line)| (idx, line.trim().to_string()))
+        .filter(|(_, line)| !line.is_empty())
+        .collect();
+    let before = instructions(&lines);
+
+    let mut lines = remove_sp_round_trips(lines);
+    loop {
+        let len = lines.len();
+        lines = remove_redundant_loads(lines);
+        if lines.len() == len {
+            break;
+        }
+    }
+
+    let removed = before - instructions(&lines);
+
+    // regroup the remaining lines into their blocks, dropping the blocks left empty
+    let mut blocks: Vec<Block> = Vec::new();
+    let mut last = None;
+    for (idx, line) in lines {
+        if last != Some(idx) {
+            blocks.push(Block {
+                origin: asm[idx].origin.clone(),
+                code: String::new(),
+            });
+            last = Some(idx);
+        }
+        let block = blocks.last_mut().expect("pushed above");
+        block.code.push_str(&line);
+        block.code.push('\n');
+    }
+    (blocks, removed)
+}
+
+/// counts the lines that are instructions rather than labels or comments
+fn instructions(lines: &[Line]) -> usize {
+    lines
+        .iter()
+        .filter(|(_, line)| !line.starts_with("//") && !line.starts_with('('))
+        .count()
+}
+
+/// replaces `@SP` `M=M+1` `@SP` `M=M-1` (a push followed by a pop) and the reverse by `@SP`:
+/// the stack pointer ends up unchanged and A holds SP either way
+fn remove_sp_round_trips(lines: Vec<Line>) -> Vec<Line> {
+    let mut out: Vec<Line> = Vec::new();
+    for line in lines {
+        out.push(line);
+        let len = out.len();
+        let at = |back: usize| out[len - back].1.as_str();
+        if len >= 4
+            && at(4) == "@SP"
+            && at(2) == "@SP"
+            && ((at(3) == "M=M+1" && at(1) == "M=M-1") || (at(3) == "M=M-1" && at(1) == "M=M+1"))
+        {
+            out.truncate(len - 3);
+        }
+    }
+    out
+}
+
+/// tracks what A and D hold and removes instructions that would load a value they already hold:
+/// `@X` `A=M` when A already holds RAM[X], and `D=M` when D already holds RAM[A],
+/// which keeps the top of the stack in D after it has been pushed
+fn remove_redundant_loads(lines: Vec<Line>) -> Vec<Line> {
+    let mut out: Vec<Line> = Vec::new();
+    let mut state = State::default();
+    let mut iter = lines.into_iter().peekable();
+
+    while let Some((idx, line)) = iter.next() {
+        // labels can be jumped to from anywhere, so nothing is known after them
+        if line.starts_with('(') {
+            state = State::default();
+            out.push((idx, line));
+            continue;
+        }
+        if line.starts_with("//") {
+            out.push((idx, line));
+            continue;
+        }
+
+        if let Some(symbol) = line.strip_prefix('@') {
+            let reloads_a = iter.peek().is_some_and(|(_, next)| next == "A=M")
+                && state.a == Some(Value::Load(symbol.to_string()));
+            if reloads_a {
+                iter.next();
+                continue;
+            }
+            state.a = Some(Value::Symbol(symbol.to_string()));
+            out.push((idx, line));
+            continue;
+        }
+
+        let (dest, rest) = line.split_once('=').unwrap_or(("", &line));
+        let comp = rest.split(';').next().unwrap_or(rest);
+
+        if dest == "D" && comp == "M" && state.a.is_some() && state.d_equals == state.a {
+            // D already holds RAM[A], only the jump is left to do
+            if let Some((_, jump)) = rest.split_once(';') {
+                out.push((idx, format!("D;{}", jump)));
+            }
+            continue;
+        }
+
+        // the effect of the instruction on what is known
+        let a = state.a.take();
+        // any write to memory may change what D was loaded from,
+        // except storing D itself
+        if dest.contains('M') {
+            state.d_equals = if comp == "D" && !dest.contains('D') {
+                a.clone()
+            } else {
+                None
+            };
+        }
+        if dest.contains('D') {
+            state.d_equals = if comp == "M" && !dest.contains('M') && !dest.contains('A') {
+                a.clone()
+            } else {
+                None
+            };
+        }
+        state.a = if dest.contains('A') {
+            match (&a, dest, comp) {
+                (Some(Value::Symbol(symbol)), "A", "M") => Some(Value::Load(symbol.clone())),
+                _ => None,
+            }
+        } else {
+            a
+        };
+        out.push((idx, line));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimized(asm: &str) -> String {
+        let (blocks, _) = optimize(vec![Block::generated(asm.to_string())]);
+        blocks.into_iter().map(|block| block.code).collect()
+    }
+
+    #[test]
+    fn push_then_add_keeps_top_of_stack_in_d() {
+        let push = "@7\nD=A\n@SP\nA=M\nM=D\n@SP\nM=M+1\n";
+        let add = "@SP\nM=M-1\nA=M\nD=M\nA=A-1\nM=M+D\n";
+        assert_eq!(
+            optimized(&format!("{}{}", push, add)),
+            "@7\nD=A\n@SP\nA=M\nM=D\nA=A-1\nM=M+D\n"
+        );
+    }
+
+    #[test]
+    fn labels_forget_register_contents() {
+        let asm = "@SP\nA=M\nM=D\n(LOOP)\n@SP\nA=M\nD=M\n";
+        assert_eq!(optimized(asm), asm);
+    }
+
+    #[test]
+    fn redundant_loads_keep_their_jump() {
+        let asm = "@SP\nA=M\nD=M\n@SP\nA=M\nD=M;JNE\n";
+        assert_eq!(optimized(asm), "@SP\nA=M\nD=M\nD;JNE\n");
+    }
+
+    #[test]
+    fn stores_to_the_stack_pointer_forget_loads() {
+        let asm = "@SP\nA=M\nD=M\n@SP\nM=D\nA=M\nD=M\n";
+        assert_eq!(optimized(asm), asm);
+    }
+}
@@ -0,0 +1,112 @@
+use crate::generate_asm::{Block, Origin};
+use std::fmt;
+
+/// what a single line of the generated assembly corresponds to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MapLine {
+    /// the ROM address of the instruction on this line, none for labels, comments and empty lines
+    pub rom_address: Option<usize>,
+    /// the VM command the line was translated from, none for the bootstrap code,
+    /// the final infinite loop and the shared routines
+    pub origin: Option<Origin>,
+}
+
+/// relates every line of the generated assembly, and every ROM address of the assembled
+/// program, to the VM file and line it was translated from
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    /// one entry per line of the generated assembly, in order
+    lines: Vec<MapLine>,
+}
+
+impl SourceMap {
+    /// `SourceMap::new()`: constructor, from the blocks the assembly is the concatenation of
+    /// ROM addresses are counted the way `hack_assembler` assigns them
+    pub fn new(asm: &[Block]) -> Self {
+        let mut lines = Vec::new();
+        let mut rom_address = 0;
+        for block in asm {
+            for line in block.code.lines() {
+                let line = line.trim();
+                let is_instruction =
+                    !line.is_empty() && !line.starts_with("//") && !line.starts_with('(');
+                lines.push(MapLine {
+                    rom_address: is_instruction.then_some(rom_address),
+                    origin: block.origin.clone(),
+                });
+                if is_instruction {
+                    rom_address += 1;
+                }
+            }
+        }
+        SourceMap { lines }
+    }
+
+    /// `SourceMap.line()`: returns what the 1-based line `asm_line` of the assembly corresponds to
+    pub fn line(&self, asm_line: usize) -> Option<&MapLine> {
+        self.lines.get(asm_line.checked_sub(1)?)
+    }
+
+    /// `SourceMap.rom_origin()`: returns the VM command the instruction at `rom_address`
+    /// was translated from
+    pub fn rom_origin(&self, rom_address: usize) -> Option<&Origin> {
+        self.lines
+            .iter()
+            .find(|line| line.rom_address == Some(rom_address))?
+            .origin
+            .as_ref()
+    }
+}
+
+/// writes one tab separated line per line of the assembly:
+/// the assembly line, the ROM address or `-`, and `file:line` and the VM command or `-`
+impl fmt::Display for SourceMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "# asm_line\trom_address\tvm_source\tvm_command")?;
+        for (idx, line) in self.lines.iter().enumerate() {
+            write!(f, "{}\t", idx + 1)?;
+            match line.rom_address {
+                Some(address) => write!(f, "{}\t", address)?,
+                None => write!(f, "-\t")?,
+            }
+            match &line.origin {
+                Some(origin) => writeln!(f, "{}:{}\t{}", origin.file, origin.line, origin.command)?,
+                None => writeln!(f, "-")?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_lines_and_rom_addresses() {
+        let origin = Origin {
+            file: "Main.vm".to_string(),
+            line: 3,
+            command: "push constant 7".to_string(),
+        };
+        let asm = [
+            Block::generated("@256\nD=A\n".to_string()),
+            Block {
+                origin: Some(origin.clone()),
+                code: "// Main.vm:3: push constant 7\n(LABEL)\n@7\nD=A\n".to_string(),
+            },
+        ];
+        let map = SourceMap::new(&asm);
+
+        assert_eq!(map.line(1).unwrap().origin, None);
+        assert_eq!(map.line(3).unwrap().rom_address, None);
+        assert_eq!(map.line(5).unwrap().rom_address, Some(2));
+        assert_eq!(map.line(7), None);
+        assert_eq!(map.rom_origin(3), Some(&origin));
+        assert_eq!(map.rom_origin(1), None);
+        assert_eq!(
+            map.to_string().lines().nth(5),
+            Some("5\t2\tMain.vm:3\tpush constant 7")
+        );
+    }
+}